@@ -0,0 +1,96 @@
+//! Throughput and connection setup benchmarks against a local echo server
+//!
+//! Run with `cargo bench`. Both benchmarks go through a real merino listener on
+//! loopback, so they include the kernel's TCP overhead but no network variance.
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use merino::*;
+
+use std::io::{copy, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+
+/// Bytes sent through the tunnel per bulk transfer iteration
+const BULK_SIZE: usize = 1024 * 1024;
+
+/// Start an echo server on an ephemeral port, with a thread per connection
+fn echo_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            thread::spawn(move || {
+                let mut reader = stream.try_clone().unwrap();
+                copy(&mut reader, &mut stream).unwrap_or(0);
+            });
+        }
+    });
+    addr
+}
+
+/// Start merino without authentication on an ephemeral port
+fn proxy() -> SocketAddr {
+    let mut merino = Merino::new(0, "127.0.0.1".to_string(), vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap();
+    let addr = merino.local_addr().unwrap();
+    thread::spawn(move || merino.serve().is_ok());
+    addr
+}
+
+/// Open a tunnel to the IPv4 `target` through `proxy`
+fn socks_connect(proxy: SocketAddr, target: SocketAddr) -> TcpStream {
+    let ip = match target {
+        SocketAddr::V4(target) => target.ip().octets(),
+        SocketAddr::V6(_) => panic!("expected a V4 address"),
+    };
+
+    let mut stream = TcpStream::connect(proxy).unwrap();
+    stream.set_nodelay(true).unwrap();
+    stream.write_all(&[5, 1, AuthMethods::NoAuth as u8]).unwrap();
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).unwrap();
+
+    let mut request = vec![5, 1, 0, 1];
+    request.extend_from_slice(&ip);
+    request.extend_from_slice(&target.port().to_be_bytes());
+    stream.write_all(&request).unwrap();
+
+    let mut reply = [0u8; 10];
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(reply[1], ResponseCode::Success as u8);
+    stream
+}
+
+fn connection_setup(c: &mut Criterion) {
+    let target = echo_server();
+    let proxy = proxy();
+
+    c.bench_function("connect_setup", |b| {
+        b.iter(|| socks_connect(proxy, target))
+    });
+}
+
+fn bulk_transfer(c: &mut Criterion) {
+    let target = echo_server();
+    let proxy = proxy();
+    let mut stream = socks_connect(proxy, target);
+    let data = vec![0x5A; BULK_SIZE];
+    let mut echoed = vec![0; BULK_SIZE];
+
+    let mut group = c.benchmark_group("bulk_transfer");
+    // Every byte crosses the proxy twice, once each way
+    group.throughput(Throughput::Bytes(2 * BULK_SIZE as u64));
+    group.bench_function("echo_1mib", |b| {
+        b.iter(|| {
+            // Write from another thread so neither side's buffers fill up
+            let mut writer = stream.try_clone().unwrap();
+            thread::scope(|scope| {
+                scope.spawn(|| writer.write_all(&data).unwrap());
+                stream.read_exact(&mut echoed).unwrap();
+            });
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, connection_setup, bulk_transfer);
+criterion_main!(benches);
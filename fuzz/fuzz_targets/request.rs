@@ -0,0 +1,24 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use merino::protocol::{encode_request, parse_request, read_credentials, read_greeting, read_request};
+
+// Every input must parse or fail cleanly, never panic
+fuzz_target!(|data: &[u8]| {
+    let _ = read_greeting(&mut &data[..]);
+    let _ = read_credentials(&mut &data[..]);
+
+    if let Ok(request) = read_request(&mut &data[..]) {
+        // Formatting handles non UTF-8 domains
+        let _ = request.address.to_string();
+
+        // Whatever was read encodes back to a request that parses the same
+        let bytes = encode_request(request.command, &request.address).unwrap();
+        let (reparsed, consumed) = parse_request(&bytes).unwrap();
+        assert_eq!((reparsed.command, reparsed.address, consumed), (request.command, request.address, bytes.len()));
+    }
+
+    // Whatever a complete request took up is within the input
+    if let Ok((_, consumed)) = parse_request(data) {
+        assert!(consumed <= data.len());
+    }
+});
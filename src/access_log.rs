@@ -0,0 +1,185 @@
+//! Access log written independently of the `log` crate
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::UNIX_EPOCH;
+
+use crate::{SessionHook, SessionSummary};
+
+/// Writes one line per completed request to a writer of its own
+///
+/// Install it with `Options::session_hooks` through `AccessLog::hook`. Each
+/// line has space separated fields, with `-` for missing values. Whitespace
+/// and control characters in the client supplied fields are escaped as
+/// `\xNN`, so fields never contain spaces:
+///
+/// ```text
+/// TIMESTAMP CONN_ID CLIENT USER COMMAND DESTINATION REPLY BYTES_UP BYTES_DOWN DURATION
+/// 1700000000123 7 192.0.2.1:50000 alice Connect example.com:443 0 512 4096 1250
+/// ```
+///
+/// - `TIMESTAMP`: start of the connection, in milliseconds since the Unix epoch
+/// - `CLIENT`: client address and port
+/// - `USER`: username from USERPASS authentication
+/// - `COMMAND`: `Connect`, `Bind` or `UdpAssosiate`
+/// - `DESTINATION`: address and port from the request
+/// - `REPLY`: last SOCKS5 reply code sent, in decimal
+/// - `BYTES_UP`/`BYTES_DOWN`: bytes relayed from and to the client
+/// - `DURATION`: length of the connection in milliseconds
+///
+/// Clones write to the same writer, so one can be kept to `reopen` the log
+/// while another is installed.
+#[derive(Clone)]
+pub struct AccessLog {
+    writer: Arc<Mutex<Box<dyn Write + Send>>>,
+    /// File the log was opened from, for `reopen`
+    path: Option<PathBuf>,
+}
+
+impl AccessLog {
+    /// Log to `writer`, which is flushed after every line
+    pub fn new(writer: Box<dyn Write + Send>) -> Self {
+        AccessLog {
+            writer: Arc::new(Mutex::new(writer)),
+            path: None,
+        }
+    }
+
+    /// Append to the file at `path`, creating it if needed
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        Ok(AccessLog {
+            writer: Arc::new(Mutex::new(Box::new(append(&path)?))),
+            path: Some(path),
+        })
+    }
+
+    /// Open the log's file again, e.g. once logrotate has moved it away
+    ///
+    /// Lines being written finish in the old file first. Fails with
+    /// `InvalidInput` for logs made with `new`, which have no file to reopen.
+    pub fn reopen(&self) -> io::Result<()> {
+        let path = self.path.as_ref()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "access log wasn't opened from a file"))?;
+        let file = append(path)?;
+        *self.writer.lock().unwrap_or_else(|e| e.into_inner()) = Box::new(file);
+        debug!("Reopened access log {}", path.display());
+        Ok(())
+    }
+
+    /// Write the line for `session`
+    pub fn record(&self, session: &SessionSummary) -> io::Result<()> {
+        let line = format_line(session);
+        let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        writer.write_all(line.as_bytes())?;
+        writer.flush()
+    }
+
+    /// A hook recording every session, for `Options::session_hooks`
+    pub fn hook(self) -> SessionHook {
+        Arc::new(move |session| {
+            if let Err(e) = self.record(session) {
+                warn!("Failed to write access log: {}", e);
+            }
+        })
+    }
+}
+
+fn append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn format_line(session: &SessionSummary) -> String {
+    format!("{} {} {} {} {:?} {} {} {} {} {}\n",
+        session.started.duration_since(UNIX_EPOCH).map(|t| t.as_millis()).unwrap_or(0),
+        session.ctx.conn_id,
+        session.ctx.peer_addr,
+        session.ctx.username.as_deref().map(escape).unwrap_or_else(|| "-".to_string()),
+        session.command,
+        escape(&session.address.to_string()),
+        session.reply.map(|code| (code as u8).to_string()).unwrap_or_else(|| "-".to_string()),
+        session.bytes_up,
+        session.bytes_down,
+        session.duration.as_millis(),
+    )
+}
+
+/// Escape whitespace and control characters, which would break up a field
+pub(crate) fn escape(field: &str) -> String {
+    let mut escaped = String::with_capacity(field.len());
+    for c in field.chars() {
+        if c.is_whitespace() || c.is_control() {
+            for byte in c.to_string().bytes() {
+                escaped.push_str(&format!("\\x{:02x}", byte));
+            }
+        }
+        else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Address, ConnContext, ResponseCode, SockCommand};
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    #[test]
+    fn reopen() {
+        let dir = std::env::temp_dir().join(format!("merino-access-log-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (path, rotated) = (dir.join("access.log"), dir.join("access.log.1"));
+
+        let log = AccessLog::open(&path).unwrap();
+        let hook = log.clone().hook();
+        let session = SessionSummary {
+            ctx: ConnContext::new(7, "192.0.2.1:50000".parse().unwrap()),
+            command: SockCommand::Connect,
+            address: Address::Ipv4(Ipv4Addr::new(192, 0, 2, 2), 80),
+            reply: Some(ResponseCode::Success),
+            bytes_up: 0,
+            bytes_down: 0,
+            started: UNIX_EPOCH,
+            duration: Duration::from_millis(0),
+        };
+
+        hook(&session);
+        std::fs::rename(&path, &rotated).unwrap();
+        // Still written to the moved file until reopened
+        hook(&session);
+        log.reopen().unwrap();
+        hook(&session);
+
+        assert_eq!(std::fs::read_to_string(&rotated).unwrap().lines().count(), 2);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), format_line(&session));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(AccessLog::new(Box::new(io::sink())).reopen().is_err());
+    }
+
+    #[test]
+    fn line_format() {
+        let mut ctx = ConnContext::new(7, "192.0.2.1:50000".parse().unwrap());
+        let mut session = SessionSummary {
+            ctx: ctx.clone(),
+            command: SockCommand::Connect,
+            address: Address::Domain(b"example.com".to_vec(), 443),
+            reply: Some(ResponseCode::Success),
+            bytes_up: 512,
+            bytes_down: 4096,
+            started: UNIX_EPOCH + Duration::from_millis(1_700_000_000_123),
+            duration: Duration::from_millis(1250),
+        };
+        assert_eq!(format_line(&session), "1700000000123 7 192.0.2.1:50000 - Connect example.com:443 0 512 4096 1250\n");
+
+        ctx.username = Some("alice smith".to_string());
+        session.ctx = ctx;
+        session.address = Address::Ipv4(Ipv4Addr::new(192, 0, 2, 2), 80);
+        session.reply = None;
+        assert_eq!(format_line(&session), "1700000000123 7 192.0.2.1:50000 alice\\x20smith Connect 192.0.2.2:80 - 512 4096 1250\n");
+    }
+}
@@ -0,0 +1,290 @@
+//! Control socket for a running merino
+//!
+//! Operators connect to it to act on the server through its `Handle`, no
+//! restart needed. It only listens on loopback or a Unix socket, and is served
+//! on threads of its own, away from the SOCKS traffic.
+//!
+//! The protocol is line based. Each command is answered with zero or more
+//! lines of output followed by `OK`, or with a single `ERR <reason>` line:
+//!
+//! - `stats`: `NAME VALUE` lines for `active`, `total`, `open_fds`,
+//!   `empty_method_offers` and `maintenance`
+//! - `list`: a line per open connection, `ID CLIENT USER DESTINATION
+//!   BYTES_UP BYTES_DOWN` with `-` for missing values
+//! - `kill <id>`: close a connection, see `Handle::kill_connection`
+//! - `maintenance on|off`: enter or leave maintenance mode, replying
+//!   `Failure` to every request while on
+//! - `reload`: run the reload hook, e.g. to reload the users
+//! - `reopen`: reopen the access log, see `AccessLog::reopen`
+//! - `drain`: stop accepting connections, see `Handle::shutdown`
+//! - `quit`: close the control connection
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener};
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+#[cfg(unix)]
+use std::path::Path;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use crate::access_log::escape;
+use crate::{AccessLog, Handle, ResponseCode};
+
+/// Reloads configuration for the `reload` command, returning why it failed
+pub type ReloadHook = Arc<dyn Fn() -> Result<(), String> + Send + Sync>;
+
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+/// Control socket serving the commands in the module docs
+pub struct AdminServer {
+    listener: Listener,
+    control: Control,
+}
+
+/// What the commands act on
+struct Control {
+    handle: Handle,
+    access_log: Option<AccessLog>,
+    reload: Option<ReloadHook>,
+}
+
+impl AdminServer {
+    /// Listen on `addr`, which has to be a loopback address
+    pub fn bind_tcp(addr: SocketAddr, handle: Handle) -> io::Result<Self> {
+        if !addr.ip().is_loopback() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("admin socket must be on loopback, not {}", addr.ip())));
+        }
+        Ok(AdminServer::new(Listener::Tcp(TcpListener::bind(addr)?), handle))
+    }
+
+    /// Listen on a Unix socket at `path`
+    ///
+    /// Anyone who can connect to the socket controls merino, so put it in a
+    /// directory only the operator can reach.
+    #[cfg(unix)]
+    pub fn bind_unix<P: AsRef<Path>>(path: P, handle: Handle) -> io::Result<Self> {
+        Ok(AdminServer::new(Listener::Unix(UnixListener::bind(path)?), handle))
+    }
+
+    fn new(listener: Listener, handle: Handle) -> Self {
+        AdminServer {
+            listener,
+            control: Control { handle, access_log: None, reload: None },
+        }
+    }
+
+    /// Let the `reopen` command reopen `access_log`
+    pub fn with_access_log(mut self, access_log: AccessLog) -> Self {
+        self.control.access_log = Some(access_log);
+        self
+    }
+
+    /// Run `reload` for the `reload` command
+    pub fn with_reload(mut self, reload: ReloadHook) -> Self {
+        self.control.reload = Some(reload);
+        self
+    }
+
+    /// Address a TCP control socket is listening on
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match &self.listener {
+            Listener::Tcp(listener) => listener.local_addr().ok(),
+            #[cfg(unix)]
+            Listener::Unix(_) => None,
+        }
+    }
+
+    /// Accept control connections on a new thread, serving each one on a
+    /// thread of its own
+    pub fn spawn(self) -> JoinHandle<()> {
+        let control = Arc::new(self.control);
+        let listener = self.listener;
+        thread::spawn(move || loop {
+            let served = match &listener {
+                Listener::Tcp(listener) => listener.accept().and_then(|(stream, _)| {
+                    let reader = BufReader::new(stream.try_clone()?);
+                    serve_connection(reader, stream, control.clone());
+                    Ok(())
+                }),
+                #[cfg(unix)]
+                Listener::Unix(listener) => listener.accept().and_then(|(stream, _)| {
+                    let reader = BufReader::new(stream.try_clone()?);
+                    serve_connection(reader, stream, control.clone());
+                    Ok(())
+                }),
+            };
+            if let Err(e) = served {
+                warn!("Failed to accept admin connection: {}", e);
+            }
+        })
+    }
+}
+
+/// Serve the commands of one control connection on a new thread
+fn serve_connection<R, W>(reader: R, mut writer: W, control: Arc<Control>)
+where
+    R: BufRead + Send + 'static,
+    W: Write + Send + 'static,
+{
+    thread::spawn(move || {
+        for line in reader.lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            let response = match control.execute(&line) {
+                Ok(None) => break,
+                Ok(Some(mut lines)) => {
+                    lines.push("OK".to_string());
+                    lines
+                },
+                Err(reason) => vec![format!("ERR {}", reason)],
+            };
+            if writer.write_all(format!("{}\n", response.join("\n")).as_bytes()).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+impl Control {
+    /// Run a command line, returning its output, or `None` to hang up
+    fn execute(&self, line: &str) -> Result<Option<Vec<String>>, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        debug!("Admin command: {}", line);
+        let output = match words[..] {
+            ["stats"] => vec![
+                format!("active {}", self.handle.active_connections()),
+                format!("total {}", self.handle.total_connections()),
+                format!("open_fds {}", self.handle.estimated_open_fds()),
+                format!("empty_method_offers {}", self.handle.empty_method_offers()),
+                format!("maintenance {}", if self.handle.in_maintenance() { "on" } else { "off" }),
+            ],
+            ["list"] => self.handle.list_sessions().into_iter().map(|session| format!("{} {} {} {} {} {}",
+                session.conn_id,
+                session.peer_addr,
+                session.username.as_deref().map(escape).unwrap_or_else(|| "-".to_string()),
+                session.destination.map(|address| escape(&address.to_string())).unwrap_or_else(|| "-".to_string()),
+                session.bytes.up,
+                session.bytes.down,
+            )).collect(),
+            ["kill", id] => {
+                let id = id.parse().map_err(|_| format!("invalid connection ID {}", id))?;
+                if !self.handle.kill_connection(id) {
+                    return Err(format!("no connection {}", id));
+                }
+                Vec::new()
+            },
+            ["maintenance", "on"] => {
+                self.handle.set_maintenance(ResponseCode::Failure);
+                Vec::new()
+            },
+            ["maintenance", "off"] => {
+                self.handle.clear_maintenance();
+                Vec::new()
+            },
+            ["reload"] => {
+                let reload = self.reload.as_ref().ok_or("nothing to reload")?;
+                reload()?;
+                Vec::new()
+            },
+            ["reopen"] => {
+                let access_log = self.access_log.as_ref().ok_or("no access log")?;
+                access_log.reopen().map_err(|e| e.to_string())?;
+                Vec::new()
+            },
+            ["drain"] => {
+                self.handle.shutdown();
+                Vec::new()
+            },
+            ["quit"] => return Ok(None),
+            _ => return Err(format!("unknown command: {}", line.trim())),
+        };
+        Ok(Some(output))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AuthMethods, Merino, Options};
+    use std::net::TcpStream;
+
+    /// Send `command` and read the response up to its `OK` or `ERR` line
+    fn command<S: io::Read + Write>(stream: &mut BufReader<S>, command: &str) -> Vec<String> {
+        stream.get_mut().write_all(format!("{}\n", command).as_bytes()).unwrap();
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            stream.read_line(&mut line).unwrap();
+            let line = line.trim_end().to_string();
+            let done = line == "OK" || line.starts_with("ERR ");
+            lines.push(line);
+            if done {
+                return lines;
+            }
+        }
+    }
+
+    fn merino() -> Merino {
+        Merino::with_options(0, "127.0.0.1".to_string(), vec![AuthMethods::NoAuth as u8], Vec::new(), Options::default()).unwrap()
+    }
+
+    #[test]
+    fn tcp_commands() {
+        let merino = merino();
+        let handle = merino.handle();
+        let admin = AdminServer::bind_tcp("127.0.0.1:0".parse().unwrap(), merino.handle()).unwrap();
+        let addr = admin.local_addr().unwrap();
+        admin.spawn();
+
+        let mut stream = BufReader::new(TcpStream::connect(addr).unwrap());
+        let stats = command(&mut stream, "stats");
+        assert_eq!(stats[0], "active 0");
+        assert_eq!(stats.last().unwrap(), "OK");
+
+        assert_eq!(command(&mut stream, "maintenance on"), vec!["OK"]);
+        assert!(handle.in_maintenance());
+        assert!(command(&mut stream, "stats").contains(&"maintenance on".to_string()));
+        assert_eq!(command(&mut stream, "maintenance off"), vec!["OK"]);
+        assert!(!handle.in_maintenance());
+
+        assert_eq!(command(&mut stream, "list"), vec!["OK"]);
+        assert_eq!(command(&mut stream, "kill 42"), vec!["ERR no connection 42"]);
+        assert_eq!(command(&mut stream, "reload"), vec!["ERR nothing to reload"]);
+        assert_eq!(command(&mut stream, "launch"), vec!["ERR unknown command: launch"]);
+    }
+
+    #[test]
+    fn loopback_only() {
+        let merino = merino();
+        assert!(AdminServer::bind_tcp("0.0.0.0:0".parse().unwrap(), merino.handle()).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_reload() {
+        use std::os::unix::net::UnixStream;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let path = std::env::temp_dir().join(format!("merino-admin-{}.sock", std::process::id()));
+        let reloads = Arc::new(AtomicUsize::new(0));
+        let counter = reloads.clone();
+        AdminServer::bind_unix(&path, merino().handle()).unwrap()
+            .with_reload(Arc::new(move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            }))
+            .spawn();
+
+        let mut stream = BufReader::new(UnixStream::connect(&path).unwrap());
+        assert_eq!(command(&mut stream, "reload"), vec!["OK"]);
+        assert_eq!(reloads.load(Ordering::SeqCst), 1);
+        assert_eq!(command(&mut stream, "reopen"), vec!["ERR no access log"]);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,160 @@
+//! Authentication method subnegotiation
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::lockout::AuthLockout;
+use crate::protocol::{self, build_auth_reply, AuthStatus};
+use crate::{AuthMethods, ConnContext, CredentialStore, User, UsernameNormalizer};
+
+/// A stream an `AuthHandler` runs its subnegotiation over
+pub trait AuthStream: Read + Write {}
+
+impl<T: Read + Write> AuthStream for T {}
+
+/// Longest `Options::auth_failure_delay` honoured, so a misconfiguration
+/// can't stall failed logins indefinitely
+pub const MAX_AUTH_FAILURE_DELAY: Duration = Duration::from_secs(30);
+
+/// Result of an authentication subnegotiation
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AuthOutcome {
+    /// The client may send requests, as `username` if the method has users
+    Granted { username: Option<String> },
+    /// The client is turned away and the connection closed
+    Denied,
+}
+
+/// Implements a SOCKS5 authentication method
+///
+/// Install handlers for IANA-assigned (0x03-0x7F) or private (0x80-0xFE)
+/// methods with `Options::auth_handlers`. `NoAuthHandler` and
+/// `UserPassHandler` implement the built-in methods.
+pub trait AuthHandler: Send + Sync {
+    /// METHOD code the handler implements
+    fn method(&self) -> u8;
+
+    /// Run the method's subnegotiation with the client on `ctx`, once the
+    /// method was selected
+    ///
+    /// Any reply telling the client it was denied is up to the handler.
+    fn authenticate(&self, ctx: &ConnContext, stream: &mut dyn AuthStream) -> io::Result<AuthOutcome>;
+}
+
+/// `AuthMethods::NoAuth`: everyone is let in
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoAuthHandler;
+
+impl AuthHandler for NoAuthHandler {
+    fn method(&self) -> u8 {
+        AuthMethods::NoAuth as u8
+    }
+
+    fn authenticate(&self, _ctx: &ConnContext, _stream: &mut dyn AuthStream) -> io::Result<AuthOutcome> {
+        Ok(AuthOutcome::Granted { username: None })
+    }
+}
+
+/// `AuthMethods::UserPass` (RFC 1929), checked against a `CredentialStore`
+pub struct UserPassHandler {
+    credentials: Arc<dyn CredentialStore>,
+    normalizer: Option<UsernameNormalizer>,
+    lockout: Option<Arc<AuthLockout>>,
+    failure_delay: Duration,
+}
+
+impl UserPassHandler {
+    /// Authenticate users against `credentials`
+    pub fn new(credentials: Arc<dyn CredentialStore>) -> Self {
+        UserPassHandler { credentials, normalizer: None, lockout: None, failure_delay: Duration::ZERO }
+    }
+
+    /// Rewrite usernames with `normalizer` before looking them up
+    pub fn with_normalizer(mut self, normalizer: UsernameNormalizer) -> Self {
+        self.normalizer = Some(normalizer);
+        self
+    }
+
+    /// Refuse clients `lockout` has locked out without checking their
+    /// credentials, and record failures with it
+    pub(crate) fn with_lockout(mut self, lockout: Arc<AuthLockout>) -> Self {
+        self.lockout = Some(lockout);
+        self
+    }
+
+    /// Wait between half of `delay` and all of it before telling a client
+    /// its login failed, see `Options::auth_failure_delay`
+    pub(crate) fn with_failure_delay(mut self, delay: Duration) -> Self {
+        self.failure_delay = delay.min(MAX_AUTH_FAILURE_DELAY);
+        self
+    }
+
+    /// Hold a failed login back for a random part of `failure_delay`
+    fn tarpit(&self) {
+        if self.failure_delay.is_zero() {
+            return;
+        }
+        // Randomized so the reply time doesn't tell a locked out client
+        // apart from a wrong password
+        let nanos = self.failure_delay.as_nanos() as u64;
+        let jitter = RandomState::new().build_hasher().finish() % (nanos / 2 + 1);
+        thread::sleep(Duration::from_nanos(nanos - jitter));
+    }
+}
+
+impl AuthHandler for UserPassHandler {
+    fn method(&self) -> u8 {
+        AuthMethods::UserPass as u8
+    }
+
+    fn authenticate(&self, ctx: &ConnContext, stream: &mut dyn AuthStream) -> io::Result<AuthOutcome> {
+        let credentials = protocol::read_credentials(stream)?;
+
+        let ip = ctx.peer_addr.ip();
+        if self.lockout.as_ref().is_some_and(|lockout| lockout.is_locked(ip)) {
+            debug!("Access Denied. {} is locked out", ip);
+            self.tarpit();
+            stream.write_all(&build_auth_reply(AuthStatus::Failure))?;
+            return Ok(AuthOutcome::Denied);
+        }
+
+        // Credentials that aren't valid UTF-8 can't match any user
+        let user = match (String::from_utf8(credentials.username), String::from_utf8(credentials.password)) {
+            (Ok(username), Ok(password)) => {
+                let username = match &self.normalizer {
+                    Some(normalize) => normalize(&username),
+                    None => username,
+                };
+                Some(User { username, password })
+            },
+            _ => None
+        };
+
+        // Authenticate passwords
+        match user {
+            Some(user) if self.credentials.authorize(ctx, &user.username, &user.password) => {
+                debug!("Access Granted. User: {}", user.username);
+                if let Some(lockout) = &self.lockout {
+                    lockout.record_success(ip);
+                }
+                stream.write_all(&build_auth_reply(AuthStatus::Success))?;
+                Ok(AuthOutcome::Granted { username: Some(user.username) })
+            },
+            user => {
+                match user {
+                    Some(user) => debug!("Access Denied. User: {}", user.username),
+                    None => debug!("Access Denied. Credentials are not valid UTF-8")
+                }
+                if self.lockout.as_ref().is_some_and(|lockout| lockout.record_failure(ip)) {
+                    warn!("Locking out {} after repeated authentication failures", ip);
+                }
+                self.tarpit();
+                stream.write_all(&build_auth_reply(AuthStatus::Failure))?;
+                Ok(AuthOutcome::Denied)
+            }
+        }
+    }
+}
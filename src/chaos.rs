@@ -0,0 +1,104 @@
+//! Injected latency for testing SOCKS clients
+//!
+//! People building clients can point them at a merino that's slow on purpose,
+//! to exercise their timeouts and retries. Never turn this on for real
+//! traffic: it only ever makes the proxy worse.
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Write};
+use std::net::Shutdown;
+use std::thread;
+use std::time::Duration;
+
+use crate::TunnelEnd;
+
+/// Delays to inject, see `Options::chaos`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Chaos {
+    /// Wait this long before replying to a request
+    pub reply_delay: Duration,
+    /// Add a random delay of up to this much to every reply and relayed chunk
+    pub jitter: Duration,
+    /// Relay at most this many bytes per second in each direction of a
+    /// tunnel. `None` relays at full speed.
+    pub relay_rate: Option<u64>,
+}
+
+impl Chaos {
+    /// Wait before a reply
+    pub(crate) fn delay_reply(&self) {
+        thread::sleep(self.reply_delay + self.random_jitter());
+    }
+
+    /// Up to `jitter`, at random
+    fn random_jitter(&self) -> Duration {
+        let nanos = self.jitter.as_nanos() as u64;
+        if nanos == 0 {
+            return Duration::ZERO;
+        }
+        Duration::from_nanos(RandomState::new().build_hasher().finish() % (nanos + 1))
+    }
+}
+
+/// Holds back everything written to the tunnel end it wraps as `Chaos` says
+pub(crate) struct Throttled<W> {
+    inner: W,
+    chaos: Chaos,
+}
+
+impl<W> Throttled<W> {
+    pub(crate) fn new(inner: W, chaos: Chaos) -> Self {
+        Throttled { inner, chaos }
+    }
+}
+
+impl<W: Write> Write for Throttled<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // At most a second's worth at a time, then wait out that second
+        let len = match self.chaos.relay_rate {
+            Some(rate) => buf.len().min(rate.max(1) as usize),
+            None => buf.len(),
+        };
+        let written = self.inner.write(&buf[..len])?;
+        let pause = match self.chaos.relay_rate {
+            Some(rate) => Duration::from_secs_f64(written as f64 / rate.max(1) as f64),
+            None => Duration::ZERO,
+        };
+        thread::sleep(pause + self.chaos.random_jitter());
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: TunnelEnd> TunnelEnd for Throttled<W> {
+    fn close(&mut self, how: Shutdown) -> io::Result<()> {
+        self.inner.close(how)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn relay_rate() {
+        let chaos = Chaos { relay_rate: Some(1000), ..Chaos::default() };
+        let mut throttled = Throttled::new(Vec::new(), chaos);
+
+        let start = Instant::now();
+        throttled.write_all(&[0; 300]).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        assert_eq!(throttled.inner.len(), 300);
+    }
+
+    #[test]
+    fn jitter_bounds() {
+        let chaos = Chaos { jitter: Duration::from_millis(5), ..Chaos::default() };
+        assert!((0..100).all(|_| chaos.random_jitter() <= chaos.jitter));
+        assert_eq!(Chaos::default().random_jitter(), Duration::ZERO);
+    }
+}
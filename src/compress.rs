@@ -0,0 +1,145 @@
+//! Compressed tunnels between two merino instances
+//!
+//! A merino forwarding to an `Options::upstream` offers the private
+//! `AuthMethods::CompressedTunnel` method. If the upstream merino picks it,
+//! the request and reply are exchanged as usual and everything relayed after
+//! them is a raw deflate stream in each direction. Every relayed chunk is
+//! sync flushed, so interactive traffic isn't held back waiting for more.
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+
+use std::io::{self, Chain, Cursor, Read, Write};
+use std::net::Shutdown;
+
+use crate::auth::{AuthHandler, AuthOutcome, AuthStream};
+use crate::{AuthMethods, ClientStream, ConnContext, TunnelEnd};
+
+/// `AuthMethods::CompressedTunnel`: everyone is let in, like `NoAuth`, and
+/// the relayed data is compressed
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CompressedTunnelHandler;
+
+impl AuthHandler for CompressedTunnelHandler {
+    fn method(&self) -> u8 {
+        AuthMethods::CompressedTunnel as u8
+    }
+
+    fn authenticate(&self, _ctx: &ConnContext, _stream: &mut dyn AuthStream) -> io::Result<AuthOutcome> {
+        Ok(AuthOutcome::Granted { username: None })
+    }
+}
+
+/// Compresses everything written to the stream it wraps
+pub(crate) struct Deflater<S: ClientStream> {
+    encoder: DeflateEncoder<S>,
+}
+
+impl<S: ClientStream> Deflater<S> {
+    pub(crate) fn new(stream: S) -> Self {
+        Deflater { encoder: DeflateEncoder::new(stream, Compression::fast()) }
+    }
+}
+
+impl<S: ClientStream> Write for Deflater<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.encoder.write_all(buf)?;
+        self.encoder.flush()?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.encoder.flush()
+    }
+}
+
+impl<S: ClientStream> TunnelEnd for Deflater<S> {
+    fn close(&mut self, how: Shutdown) -> io::Result<()> {
+        // End the deflate stream before the TCP one
+        if how != Shutdown::Read {
+            self.encoder.try_finish().unwrap_or(());
+        }
+        self.encoder.get_ref().shutdown(how)
+    }
+}
+
+/// Decompresses everything read from the stream it wraps
+pub(crate) struct Inflater<S: ClientStream> {
+    decoder: DeflateDecoder<Chain<Cursor<Vec<u8>>, S>>,
+}
+
+impl<S: ClientStream> Inflater<S> {
+    /// Decompress `buffered`, compressed data already read from `stream`,
+    /// followed by the rest of `stream`
+    pub(crate) fn new(buffered: Vec<u8>, stream: S) -> Self {
+        Inflater { decoder: DeflateDecoder::new(Cursor::new(buffered).chain(stream)) }
+    }
+}
+
+impl<S: ClientStream> Read for Inflater<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.decoder.read(buf)
+    }
+}
+
+impl<S: ClientStream> TunnelEnd for Inflater<S> {
+    fn close(&mut self, how: Shutdown) -> io::Result<()> {
+        self.decoder.get_ref().get_ref().1.shutdown(how)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{duplex, read_n};
+
+    #[test]
+    fn round_trip() {
+        let (local, remote) = duplex();
+        let mut deflater = Deflater::new(local);
+        let mut inflater = Inflater::new(Vec::new(), remote);
+
+        // Each write can be read back straight away
+        for message in &[&b"hello"[..], b"world", &[0u8; 10_000]] {
+            deflater.write_all(message).unwrap();
+            assert_eq!(read_n(&mut inflater, message.len()), message.to_vec());
+        }
+
+        deflater.close(Shutdown::Write).unwrap();
+        let mut rest = Vec::new();
+        inflater.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn compresses() {
+        let (local, mut remote) = duplex();
+        let mut deflater = Deflater::new(local);
+
+        deflater.write_all(&[b'a'; 10_000]).unwrap();
+        deflater.close(Shutdown::Write).unwrap();
+        let mut compressed = Vec::new();
+        remote.read_to_end(&mut compressed).unwrap();
+        assert!(compressed.len() < 100);
+    }
+
+    #[test]
+    fn buffered_data_comes_first() {
+        let (mut local, remote) = duplex();
+
+        let mut compressed = Vec::new();
+        let mut encoder = DeflateEncoder::new(&mut compressed, Compression::fast());
+        encoder.write_all(b"data sent with the request").unwrap();
+        encoder.finish().unwrap();
+
+        // Part of the stream was read along with the request
+        let rest = compressed.split_off(compressed.len() / 2);
+        local.write_all(&rest).unwrap();
+        local.shutdown(Shutdown::Write).unwrap();
+
+        let mut inflater = Inflater::new(compressed, remote);
+        let mut data = Vec::new();
+        inflater.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"data sent with the request".to_vec());
+    }
+}
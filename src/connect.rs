@@ -0,0 +1,500 @@
+//! Outbound connections to proxy targets
+use socket2::{Domain, SockRef, Socket, Type};
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+/// Settings applied to outbound sockets before they connect
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Outbound {
+    /// `SO_MARK` for policy routing (Linux only)
+    pub fwmark: Option<u32>,
+    /// Give up on each address after this long
+    pub timeout: Option<Duration>,
+    /// Inclusive range to pick the local source port from
+    pub port_range: Option<(u16, u16)>,
+    /// Send the first data in the SYN with TCP Fast Open (Linux only)
+    pub fast_open: bool,
+    /// Network namespace file to create sockets in (Linux only)
+    pub netns: Option<PathBuf>,
+}
+
+/// Where in the source port range the next connection starts looking, so
+/// consecutive connections don't all fight over the lowest port
+static NEXT_PORT: AtomicUsize = AtomicUsize::new(0);
+
+/// Connect to the first reachable address in `addrs`
+///
+/// With a `delay`, this is a "happy eyeballs" connect (RFC 8305): address
+/// families are interleaved and a new attempt is started every `delay` until
+/// one succeeds, so an unreachable family doesn't hold up the connection.
+/// Without one, each address is tried in turn like `TcpStream::connect`.
+/// Either way a failed address only moves on to the next one, and the error
+/// of the last attempt is returned once all of them have failed.
+pub(crate) fn connect(addrs: &[SocketAddr], delay: Option<Duration>, outbound: Outbound) -> io::Result<TcpStream> {
+    match delay {
+        Some(delay) if addrs.len() > 1 => happy_eyeballs(interleave_families(addrs), delay, outbound),
+        _ => {
+            let mut last_err = None;
+            for addr in addrs {
+                match connect_addr(*addr, &outbound) {
+                    Ok(stream) => return Ok(stream),
+                    Err(e) => last_err = Some(e),
+                }
+            }
+            Err(last_err.unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "could not resolve to any addresses")))
+        }
+    }
+}
+
+/// Connect to `addr` with the `outbound` settings
+fn connect_addr(addr: SocketAddr, outbound: &Outbound) -> io::Result<TcpStream> {
+    if (Outbound { timeout: None, ..outbound.clone() }) == Outbound::default() {
+        return match outbound.timeout {
+            Some(timeout) => TcpStream::connect_timeout(&addr, timeout),
+            None => TcpStream::connect(addr),
+        };
+    }
+
+    let (lo, hi) = match outbound.port_range {
+        Some(range) => range,
+        None => return connect_socket(addr, 0, outbound),
+    };
+    if lo > hi {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid source port range {}-{}", lo, hi)));
+    }
+
+    // Try every port in the range once, moving on from ones already in use
+    let len = usize::from(hi - lo) + 1;
+    let start = NEXT_PORT.fetch_add(1, Ordering::Relaxed);
+    for i in 0..len {
+        let port = lo + ((start + i) % len) as u16;
+        match connect_socket(addr, port, outbound) {
+            Err(e) if matches!(e.kind(), io::ErrorKind::AddrInUse | io::ErrorKind::AddrNotAvailable) => continue,
+            result => return result,
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::AddrInUse, format!("no free source port in {}-{} to connect to {}", lo, hi, addr)))
+}
+
+/// Connect to `addr` from the local `port` (0 lets the OS choose)
+fn connect_socket(addr: SocketAddr, port: u16, outbound: &Outbound) -> io::Result<TcpStream> {
+    let new_socket = || Socket::new(Domain::for_address(addr), Type::STREAM, None);
+    let socket = match &outbound.netns {
+        Some(netns) => in_netns(netns, new_socket)?,
+        None => new_socket()?,
+    };
+    if let Some(mark) = outbound.fwmark {
+        set_mark(&socket, mark)?;
+    }
+    if outbound.fast_open {
+        set_fast_open_connect(&socket)?;
+    }
+    if port != 0 {
+        let any: IpAddr = if addr.is_ipv4() { Ipv4Addr::UNSPECIFIED.into() } else { Ipv6Addr::UNSPECIFIED.into() };
+        socket.bind(&SocketAddr::new(any, port).into())?;
+    }
+    match outbound.timeout {
+        Some(timeout) => socket.connect_timeout(&addr.into(), timeout)?,
+        None => socket.connect(&addr.into())?,
+    }
+    Ok(socket.into())
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn set_mark(socket: &Socket, mark: u32) -> io::Result<()> {
+    socket.set_mark(mark)
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn set_mark(_socket: &Socket, _mark: u32) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "SO_MARK is not supported on this platform"))
+}
+
+/// Run `f` on a new thread switched into the network namespace at `path`,
+/// e.g. `/var/run/netns/vpn`
+///
+/// A socket belongs to the namespace it was created in for the rest of its
+/// life, so `f` only needs to create it. The thread ends with `f` rather than
+/// switching back, so the caller's thread never leaves its own namespace even
+/// if switching fails halfway. Switching needs `CAP_SYS_ADMIN`.
+#[cfg(all(target_os = "linux", feature = "netns"))]
+fn in_netns<T: Send>(path: &Path, f: impl FnOnce() -> io::Result<T> + Send) -> io::Result<T> {
+    use nix::sched::{setns, CloneFlags};
+    use std::fs::File;
+
+    let target = File::open(path)?;
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            setns(&target, CloneFlags::CLONE_NEWNET)?;
+            f()
+        })
+        .join()
+        .unwrap_or_else(|_| Err(io::Error::other("network namespace thread panicked")))
+    })
+}
+
+#[cfg(not(all(target_os = "linux", feature = "netns")))]
+fn in_netns<T: Send>(_path: &Path, _f: impl FnOnce() -> io::Result<T> + Send) -> io::Result<T> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "network namespaces need Linux and the netns feature"))
+}
+
+/// Defer the handshake until the first write, so its data can go in the SYN
+///
+/// Needs Linux 4.11 or later with client support enabled in the
+/// `net.ipv4.tcp_fastopen` sysctl (bit 0, on by default). Without a cookie
+/// from an earlier connection the kernel falls back to a regular handshake.
+#[cfg(all(target_os = "linux", feature = "tcp-fastopen"))]
+fn set_fast_open_connect(socket: &Socket) -> io::Result<()> {
+    use nix::sys::socket::{setsockopt, sockopt::TcpFastOpenConnect};
+    setsockopt(socket, TcpFastOpenConnect, &true).map_err(io::Error::from)
+}
+
+#[cfg(not(all(target_os = "linux", feature = "tcp-fastopen")))]
+fn set_fast_open_connect(_socket: &Socket) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "TCP Fast Open needs Linux and the tcp-fastopen feature"))
+}
+
+/// Set the scope ID of link-local IPv6 addresses in `addrs`
+///
+/// SOCKS5 has no way to carry a scope (zone) ID, and a link-local address is
+/// only routable with one. Addresses that already have a scope are left alone.
+/// Without a `scope_id` link-local targets are rejected rather than connected
+/// to with a scope of 0.
+pub(crate) fn apply_scope_id(addrs: &mut [SocketAddr], scope_id: Option<u32>) -> io::Result<()> {
+    for addr in addrs.iter_mut() {
+        if let SocketAddr::V6(addr) = addr {
+            if addr.ip().is_unicast_link_local() && addr.scope_id() == 0 {
+                match scope_id {
+                    Some(scope_id) => addr.set_scope_id(scope_id),
+                    None => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("link-local target {} requires a scope ID", addr))),
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Set the kernel send and receive buffer sizes of `stream`
+pub(crate) fn set_buffer_size(stream: &TcpStream, size: usize) -> io::Result<()> {
+    let socket = SockRef::from(stream);
+    socket.set_send_buffer_size(size)?;
+    socket.set_recv_buffer_size(size)
+}
+
+/// Mark the packets of `stream` with the 6-bit DSCP value `dscp`
+///
+/// This sets the IP TOS byte, or the traffic class on IPv6 sockets.
+pub(crate) fn set_dscp(stream: &TcpStream, dscp: u8) -> io::Result<()> {
+    if dscp > 0x3F {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("invalid DSCP value {}", dscp)));
+    }
+
+    let socket = SockRef::from(stream);
+    let tos = u32::from(dscp) << 2;
+    if stream.local_addr()?.is_ipv6() {
+        return set_tclass_v6(&socket, tos);
+    }
+
+    #[cfg(not(any(target_os = "fuchsia", target_os = "redox", target_os = "solaris", target_os = "illumos", target_os = "haiku")))]
+    return socket.set_tos(tos);
+
+    #[cfg(any(target_os = "fuchsia", target_os = "redox", target_os = "solaris", target_os = "illumos", target_os = "haiku"))]
+    return Err(io::Error::new(io::ErrorKind::Unsupported, "TOS is not supported on this platform"));
+}
+
+#[cfg(any(target_os = "android", target_os = "dragonfly", target_os = "freebsd", target_os = "fuchsia", target_os = "linux", target_os = "macos", target_os = "netbsd", target_os = "openbsd"))]
+fn set_tclass_v6(socket: &SockRef, tclass: u32) -> io::Result<()> {
+    socket.set_tclass_v6(tclass)
+}
+
+#[cfg(not(any(target_os = "android", target_os = "dragonfly", target_os = "freebsd", target_os = "fuchsia", target_os = "linux", target_os = "macos", target_os = "netbsd", target_os = "openbsd")))]
+fn set_tclass_v6(_socket: &SockRef, _tclass: u32) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "IPv6 traffic class is not supported on this platform"))
+}
+
+/// Limit the IP TTL of packets sent on `stream`, or the hop limit on IPv6
+/// sockets
+pub(crate) fn set_ttl(stream: &TcpStream, ttl: u32) -> io::Result<()> {
+    if stream.local_addr()?.is_ipv6() {
+        return SockRef::from(stream).set_unicast_hops_v6(ttl);
+    }
+    stream.set_ttl(ttl)
+}
+
+/// Wait up to `timeout` for `stream` to have data to read, and return up to
+/// `len` bytes of it without consuming them
+///
+/// Returns whatever arrived first, so a slow target may show less than
+/// `len` bytes. Nothing arriving in time returns no bytes at all.
+pub(crate) fn peek(stream: &TcpStream, len: usize, timeout: Duration) -> io::Result<Vec<u8>> {
+    let previous = stream.read_timeout()?;
+    // A zero timeout is an error, not an instant one
+    stream.set_read_timeout(Some(timeout.max(Duration::from_millis(1))))?;
+    let mut buf = vec![0; len];
+    let peeked = stream.peek(&mut buf);
+    stream.set_read_timeout(previous)?;
+
+    match peeked {
+        Ok(n) => {
+            buf.truncate(n);
+            Ok(buf)
+        },
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Race connection attempts, starting a new one every `delay`
+fn happy_eyeballs(addrs: Vec<SocketAddr>, delay: Duration, outbound: Outbound) -> io::Result<TcpStream> {
+    let (tx, rx) = mpsc::channel();
+    let mut tx = Some(tx);
+    let mut pending = 0;
+    let mut last_err = None;
+
+    let mut addrs = addrs.into_iter();
+    loop {
+        // Start the next attempt
+        if let (Some(addr), Some(tx)) = (addrs.next(), &tx) {
+            let tx = tx.clone();
+            let outbound = outbound.clone();
+            pending += 1;
+            trace!("Attempting connection to {}", addr);
+            thread::spawn(move || {
+                // Losing connections are dropped once the receiver is gone
+                tx.send(connect_addr(addr, &outbound)).unwrap_or(());
+            });
+        }
+        else if pending == 0 {
+            break;
+        }
+        if addrs.len() == 0 {
+            // Leave the senders to the attempts, so one that panics can't
+            // keep `recv` waiting forever
+            tx = None;
+        }
+
+        // Wait for an attempt to finish, or until the next one is due
+        let result = if addrs.len() > 0 {
+            match rx.recv_timeout(delay) {
+                Ok(result) => result,
+                Err(_) => continue,
+            }
+        }
+        else {
+            rx.recv().map_err(|_| io::Error::other("connection attempt thread hung up"))?
+        };
+
+        pending -= 1;
+        match result {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_err = Some(e),
+        }
+    }
+
+    Err(last_err.unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "could not resolve to any addresses")))
+}
+
+/// Reorder `addrs` to alternate between address families, starting with the
+/// family of the first address
+fn interleave_families(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let first_v6 = addrs.first().is_some_and(SocketAddr::is_ipv6);
+    let (first, second): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs.iter().copied().partition(|addr| addr.is_ipv6() == first_v6);
+
+    let mut second = second.into_iter();
+    let mut interleaved = Vec::with_capacity(addrs.len());
+    for addr in first {
+        interleaved.push(addr);
+        interleaved.extend(second.next());
+    }
+    interleaved.extend(second);
+    interleaved
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    /// An address on loopback with nothing listening on it
+    fn closed_addr() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+    }
+
+    #[test]
+    fn link_local_scope_id() {
+        let mut addrs: Vec<SocketAddr> = vec!["[fe80::1]:80".parse().unwrap(), "[2001:db8::1]:80".parse().unwrap()];
+        apply_scope_id(&mut addrs, Some(3)).unwrap();
+
+        match (addrs[0], addrs[1]) {
+            (SocketAddr::V6(link_local), SocketAddr::V6(global)) => {
+                assert_eq!(link_local.scope_id(), 3);
+                assert_eq!(global.scope_id(), 0);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn link_local_without_scope_id() {
+        let mut addrs: Vec<SocketAddr> = vec!["[fe80::1]:80".parse().unwrap()];
+        assert!(apply_scope_id(&mut addrs, None).is_err());
+
+        let mut addrs: Vec<SocketAddr> = vec!["[2001:db8::1]:80".parse().unwrap(), "127.0.0.1:80".parse().unwrap()];
+        assert!(apply_scope_id(&mut addrs, None).is_ok());
+    }
+
+    #[test]
+    fn buffer_size() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        set_buffer_size(&stream, 64 * 1024).unwrap();
+
+        // The OS may round or double the value, but never below what was asked
+        let socket = SockRef::from(&stream);
+        assert!(socket.send_buffer_size().unwrap() >= 64 * 1024);
+        assert!(socket.recv_buffer_size().unwrap() >= 64 * 1024);
+    }
+
+    #[test]
+    fn dscp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        set_dscp(&stream, 46).unwrap();
+        assert_eq!(SockRef::from(&stream).tos().unwrap(), 46 << 2);
+
+        assert!(set_dscp(&stream, 64).is_err());
+    }
+
+    #[test]
+    fn ttl() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        set_ttl(&stream, 7).unwrap();
+        assert_eq!(stream.ttl().unwrap(), 7);
+
+        // Skip IPv6 where it isn't available
+        if let Ok(listener) = TcpListener::bind("[::1]:0") {
+            let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            set_ttl(&stream, 7).unwrap();
+            assert_eq!(SockRef::from(&stream).unicast_hops_v6().unwrap(), 7);
+        }
+    }
+
+    #[test]
+    fn interleaves_families() {
+        let addrs: Vec<SocketAddr> = ["[::1]:1", "[::1]:2", "[::1]:3", "127.0.0.1:1", "127.0.0.1:2"]
+            .iter()
+            .map(|a| a.parse().unwrap())
+            .collect();
+
+        let expected: Vec<SocketAddr> = ["[::1]:1", "127.0.0.1:1", "[::1]:2", "127.0.0.1:2", "[::1]:3"]
+            .iter()
+            .map(|a| a.parse().unwrap())
+            .collect();
+
+        assert_eq!(interleave_families(&addrs), expected);
+    }
+
+    #[test]
+    fn happy_eyeballs_skips_failed_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addrs = [closed_addr(), listener.local_addr().unwrap()];
+
+        let stream = connect(&addrs, Some(Duration::from_millis(50)), Outbound::default()).unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), listener.local_addr().unwrap());
+    }
+
+    #[test]
+    fn happy_eyeballs_all_failed() {
+        let addrs = [closed_addr(), closed_addr()];
+        assert!(connect(&addrs, Some(Duration::from_millis(50)), Outbound::default()).is_err());
+    }
+
+    #[test]
+    fn sequential_skips_failed_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addrs = [closed_addr(), listener.local_addr().unwrap()];
+        let outbound = Outbound { timeout: Some(Duration::from_secs(5)), ..Outbound::default() };
+
+        let stream = connect(&addrs, None, outbound).unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), listener.local_addr().unwrap());
+    }
+
+    #[test]
+    fn source_port_range() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        // Find two free ports next to each other to use as the range
+        let (lo, taken) = loop {
+            let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+            let port = taken.local_addr().unwrap().port();
+            if port < u16::MAX && TcpListener::bind(("127.0.0.1", port + 1)).is_ok() {
+                break (port, taken);
+            }
+        };
+        let outbound = Outbound { port_range: Some((lo, lo + 1)), ..Outbound::default() };
+
+        // The first port is taken, so the connection comes from the second
+        let stream = connect(&[listener.local_addr().unwrap()], None, outbound.clone()).unwrap();
+        assert_eq!(stream.local_addr().unwrap().port(), lo + 1);
+
+        // Now the range is exhausted
+        let e = connect(&[listener.local_addr().unwrap()], None, outbound).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::AddrInUse);
+        drop(taken);
+    }
+
+    #[cfg(all(target_os = "linux", feature = "tcp-fastopen"))]
+    #[test]
+    fn fast_open() {
+        use std::io::{Read, Write};
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let outbound = Outbound { fast_open: true, ..Outbound::default() };
+        let mut stream = connect(&[listener.local_addr().unwrap()], None, outbound).unwrap();
+
+        // The handshake only happens with the first write
+        stream.write_all(b"ping").unwrap();
+        let (mut remote, _) = listener.accept().unwrap();
+        let mut ping = [0u8; 4];
+        remote.read_exact(&mut ping).unwrap();
+        assert_eq!(&ping, b"ping");
+    }
+
+    #[test]
+    fn netns() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let outbound = Outbound { netns: Some(PathBuf::from("/proc/self/ns/net")), ..Outbound::default() };
+        let namespace = || std::fs::read_link("/proc/thread-self/ns/net").ok();
+        let before = namespace();
+
+        match connect(&[listener.local_addr().unwrap()], None, outbound) {
+            Ok(_) => assert!(listener.accept().is_ok()),
+            // Switching namespaces needs CAP_SYS_ADMIN
+            #[cfg(all(target_os = "linux", feature = "netns"))]
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::PermissionDenied),
+            #[cfg(not(all(target_os = "linux", feature = "netns")))]
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::Unsupported),
+        }
+        // Only a thread of its own ever switches
+        assert_eq!(namespace(), before);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn fwmark() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let outbound = Outbound { fwmark: Some(42), ..Outbound::default() };
+
+        match connect(&[listener.local_addr().unwrap()], None, outbound) {
+            Ok(stream) => assert_eq!(SockRef::from(&stream).mark().unwrap(), 42),
+            // Setting the mark needs CAP_NET_ADMIN
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::PermissionDenied),
+        }
+    }
+}
@@ -1,199 +1,2296 @@
 #![forbid(unsafe_code)]
 #[macro_use] extern crate serde_derive;
 #[macro_use] extern crate log;
-use snafu::{Snafu};
+use snafu::{ResultExt, Snafu};
+use socket2::{Domain, Socket, Type};
 
+use std::collections::HashMap;
 use std::io::prelude::*;
-use std::io::copy;
+use std::io::{self, copy};
 use std::error::Error;
-use std::net::{Shutdown, TcpStream, TcpListener, SocketAddr, SocketAddrV4, SocketAddrV6, Ipv4Addr, Ipv6Addr, ToSocketAddrs};
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::net::{Shutdown, TcpStream, TcpListener, UdpSocket, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{thread};
 
+mod access_log;
+#[cfg(feature = "admin")]
+mod admin;
+mod auth;
+#[cfg(feature = "chaos")]
+mod chaos;
+#[cfg(feature = "compression")]
+mod compress;
+mod connect;
+mod lockout;
+mod pool;
+pub mod protocol;
+mod proxy_protocol;
+mod resolve;
+mod rules;
+mod udp;
+mod upstream;
+
+pub use crate::access_log::AccessLog;
+#[cfg(feature = "admin")]
+pub use crate::admin::{AdminServer, ReloadHook};
+pub use crate::auth::{AuthHandler, AuthOutcome, AuthStream, NoAuthHandler, UserPassHandler, MAX_AUTH_FAILURE_DELAY};
+#[cfg(feature = "chaos")]
+pub use crate::chaos::Chaos;
+#[cfg(feature = "compression")]
+pub use crate::compress::CompressedTunnelHandler;
+pub use crate::protocol::{build_reply, AddrType, Address, SockCommand};
+pub use crate::proxy_protocol::{ProxyProtocol, ProxyProtocolVersion};
+pub use crate::resolve::{CachingResolver, Resolver, SystemResolver};
+pub use crate::rules::{Policy, Rule, RuleError, RuleSet, RuleTarget};
+#[cfg(feature = "regex")]
+pub use crate::rules::DomainPattern;
+pub use crate::udp::UdpSourceFilter;
+use crate::lockout::AuthLockout;
+use crate::pool::UpstreamPool;
+use crate::protocol::{ProtocolError, Request, RESERVED, SOCKS_VERSION};
+
+#[cfg(any(test, feature = "testutils"))]
+pub(crate) mod testing;
+#[cfg(test)]
+mod tests;
+
+/// Test harness for driving merino from integration tests
+///
+/// Clients are served over in-memory [`MemoryStream`](testutils::MemoryStream)s
+/// instead of sockets, so the whole handshake can be scripted byte by byte.
+/// Only built with the `testutils` feature, and not covered by semver: anything
+/// in here may change or go away in any release.
+#[cfg(feature = "testutils")]
+pub mod testutils {
+    pub use crate::protocol::{encode_request, parse_request, Request};
+    pub use crate::testing::{
+        duplex, duplex_with_local, read_n, read_to_end, spawn_client, spawn_client_with, MemoryStream, LOCAL_ADDR, PEER_ADDR,
+    };
+}
+
+#[derive(Clone,Debug, PartialEq, Deserialize)]
+pub struct User {
+    pub username: String,
+    password: String
+}
+
+
+/// Source of truth for username/password authentication
+///
+/// Implement this to authenticate against a database, LDAP, an API, etc. and
+/// install it with `Merino::set_credential_store`.
+pub trait CredentialStore: Send + Sync {
+    /// Whether `password` is valid for `username`
+    fn verify(&self, username: &str, password: &str) -> bool;
+
+    /// Whether the client on `ctx` may authenticate as `username` with
+    /// `password`
+    ///
+    /// Defaults to `verify`, override it to take the connection into account.
+    fn authorize(&self, _ctx: &ConnContext, username: &str, password: &str) -> bool {
+        self.verify(username, password)
+    }
+}
+
+impl CredentialStore for Vec<User> {
+    fn verify(&self, username: &str, password: &str) -> bool {
+        self.iter().any(|user| user.username == username && constant_time_eq(user.password.as_bytes(), password.as_bytes()))
+    }
+}
+
+/// What is stored for each user of a `UserStore`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UserRecord {
+    password: String,
+}
+
+/// Users keyed by username, the `CredentialStore` behind the users given to
+/// `Merino::new`
+///
+/// A username listed more than once keeps the last password listed for it;
+/// `validate_users` reports such lists.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UserStore {
+    users: HashMap<String, UserRecord>,
+}
+
+impl UserStore {
+    /// Store `users` by username
+    pub fn new(users: Vec<User>) -> Self {
+        let users = users.into_iter()
+            .map(|user| (user.username, UserRecord { password: user.password }))
+            .collect();
+        UserStore { users }
+    }
+
+    /// The record stored for `username`, if it's a user
+    pub fn get(&self, username: &str) -> Option<&UserRecord> {
+        self.users.get(username)
+    }
+
+    pub fn len(&self) -> usize {
+        self.users.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+}
+
+impl From<Vec<User>> for UserStore {
+    fn from(users: Vec<User>) -> Self {
+        UserStore::new(users)
+    }
+}
+
+impl CredentialStore for UserStore {
+    fn verify(&self, username: &str, password: &str) -> bool {
+        self.get(username).is_some_and(|record| constant_time_eq(record.password.as_bytes(), password.as_bytes()))
+    }
+}
+
+/// Compare `a` and `b` in time depending only on their lengths, so a wrong
+/// password can't be guessed byte by byte from how long it took to reject
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Variable holding a comma separated list of `username:password` pairs
+pub const USERS_ENV: &str = "MERINO_USERS";
+
+/// Prefix of variables holding a single `username:password` pair each, e.g.
+/// `MERINO_USER_1`
+pub const USER_ENV_PREFIX: &str = "MERINO_USER_";
+
+/// Error loading users from the environment
+///
+/// Messages name the offending variable but never include its value, which
+/// holds a password.
+#[derive(Debug, PartialEq, Eq, Snafu)]
+pub enum UsersEnvError {
+    #[snafu(display("{} is not valid unicode", var))]
+    NotUnicode { var: String },
+    #[snafu(display("entry {} of {} is not a username:password pair", entry, var))]
+    MalformedUser { var: String, entry: usize },
+}
+
+/// Load users from `MERINO_USERS` and `MERINO_USER_<n>` variables
+///
+/// `MERINO_USERS` is a comma separated list of `username:password` pairs.
+/// Each `MERINO_USER_<n>` variable holds one pair, and unlike the list may
+/// have commas in the password. Usernames end at the first `:` and can't be
+/// empty. Returns no users if none of the variables are set.
+pub fn users_from_env() -> Result<Vec<User>, UsersEnvError> {
+    users_from_vars(std::env::vars_os())
+}
+
+fn users_from_vars<I: IntoIterator<Item = (OsString, OsString)>>(vars: I) -> Result<Vec<User>, UsersEnvError> {
+    let mut list = None;
+    let mut numbered = Vec::new();
+    for (var, value) in vars {
+        let var = match var.into_string() {
+            Ok(var) => var,
+            Err(_) => continue,
+        };
+
+        let is_list = var == USERS_ENV;
+        let number = var.strip_prefix(USER_ENV_PREFIX).and_then(|n| n.parse::<u64>().ok());
+        if !is_list && number.is_none() {
+            continue;
+        }
+
+        let value = value.into_string().map_err(|_| UsersEnvError::NotUnicode { var: var.clone() })?;
+        match number {
+            Some(number) => numbered.push((number, var, value)),
+            None => list = Some(value),
+        }
+    }
+
+    let mut users = Vec::new();
+    if let Some(list) = list {
+        for (entry, pair) in list.split(',').map(str::trim).filter(|pair| !pair.is_empty()).enumerate() {
+            users.push(parse_user(pair).ok_or_else(|| UsersEnvError::MalformedUser { var: USERS_ENV.to_string(), entry: entry + 1 })?);
+        }
+    }
+
+    numbered.sort();
+    for (_, var, pair) in numbered {
+        users.push(parse_user(&pair).ok_or(UsersEnvError::MalformedUser { var, entry: 1 })?);
+    }
+
+    Ok(users)
+}
+
+/// Error opening one of merino's listeners
+#[derive(Debug, Snafu)]
+pub enum ListenError {
+    #[snafu(display("failed to bind {}: {}", addr, source))]
+    Bind { addr: String, source: io::Error },
+}
+
+/// Passwords shorter than this are flagged as weak by `validate_users`
+pub const MIN_PASSWORD_LEN: usize = 8;
+
+/// Problem with a user list found by `validate_users`
+///
+/// Like `UsersEnvError`, messages never include passwords.
+#[derive(Debug, PartialEq, Eq, Snafu)]
+pub enum ValidationIssue {
+    #[snafu(display("user {} is listed more than once", username))]
+    DuplicateUsername { username: String },
+    #[snafu(display("user #{} has an empty username", position))]
+    EmptyUsername { position: usize },
+    #[snafu(display("user {} has an empty password", username))]
+    EmptyPassword { username: String },
+    #[snafu(display("user {} has a weak password", username))]
+    WeakPassword { username: String },
+}
+
+/// Check a user list for duplicate usernames and empty usernames or passwords
+///
+/// Only the last password listed for a user is kept, so a username listed
+/// twice can't log in with the first password, which is rarely intended. With
+/// `check_weak`, passwords shorter than `MIN_PASSWORD_LEN` or equal to the
+/// username are reported too. Positions count from 1.
+pub fn validate_users(users: &[User], check_weak: bool) -> Result<(), Vec<ValidationIssue>> {
+    let mut issues = Vec::new();
+    let mut seen = HashMap::new();
+    for (position, user) in users.iter().enumerate() {
+        if user.username.is_empty() {
+            issues.push(ValidationIssue::EmptyUsername { position: position + 1 });
+            continue;
+        }
+
+        let count = seen.entry(user.username.as_str()).or_insert(0);
+        *count += 1;
+        if *count == 2 {
+            issues.push(ValidationIssue::DuplicateUsername { username: user.username.clone() });
+        }
+
+        if user.password.is_empty() {
+            issues.push(ValidationIssue::EmptyPassword { username: user.username.clone() });
+        }
+        else if check_weak && (user.password.chars().count() < MIN_PASSWORD_LEN || user.password == user.username) {
+            issues.push(ValidationIssue::WeakPassword { username: user.username.clone() });
+        }
+    }
+
+    if issues.is_empty() { Ok(()) } else { Err(issues) }
+}
+
+/// Parse a `username:password` pair
+fn parse_user(pair: &str) -> Option<User> {
+    match pair.split_once(':') {
+        Some((username, password)) if !username.is_empty() => Some(User { username: username.to_string(), password: password.to_string() }),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Snafu)]
+/// Possible SOCKS5 Response Codes
+pub enum ResponseCode {
+    Success = 0x00,
+    #[snafu(display("SOCKS5 Server Failure"))]
+    Failure = 0x01,
+    #[snafu(display("SOCKS5 Rule failure"))]
+    RuleFailure = 0x02,
+    #[snafu(display("network unreachable"))]
+    NetworkUnreachable = 0x03,
+    #[snafu(display("host unreachable"))]
+    HostUnreachable = 0x04,
+    #[snafu(display("connection refused"))]
+    ConnectionRefused = 0x05,
+    #[snafu(display("TTL expired"))]
+    TtlExpired = 0x06,
+    #[snafu(display("Command not supported"))]
+    CommandNotSupported = 0x07,
+    #[snafu(display("Addr Type not supported"))]
+    AddrTypeNotSupported = 0x08
+}
+
+impl ResponseCode {
+    /// Parse Byte to ResponseCode
+    fn from_code(n: u8) -> Option<ResponseCode> {
+        match n {
+            0x00 => Some(ResponseCode::Success),
+            0x01 => Some(ResponseCode::Failure),
+            0x02 => Some(ResponseCode::RuleFailure),
+            0x03 => Some(ResponseCode::NetworkUnreachable),
+            0x04 => Some(ResponseCode::HostUnreachable),
+            0x05 => Some(ResponseCode::ConnectionRefused),
+            0x06 => Some(ResponseCode::TtlExpired),
+            0x07 => Some(ResponseCode::CommandNotSupported),
+            0x08 => Some(ResponseCode::AddrTypeNotSupported),
+            _ => None
+        }
+    }
+}
+
+impl From<&io::Error> for ResponseCode {
+    /// Map a connection error to the closest SOCKS5 reply
+    fn from(error: &io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::ConnectionRefused => ResponseCode::ConnectionRefused,
+            io::ErrorKind::HostUnreachable => ResponseCode::HostUnreachable,
+            io::ErrorKind::NetworkUnreachable => ResponseCode::NetworkUnreachable,
+            io::ErrorKind::TimedOut => ResponseCode::TtlExpired,
+            _ => ResponseCode::Failure
+        }
+    }
+}
+
+/// Format of the per-request log line
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human readable text
+    Text,
+    /// One JSON object per request, see `RequestLog`
+    Json,
+}
+
+/// Address family to try first when a target resolves to both
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FamilyPreference {
+    /// Keep the resolver's order
+    System,
+    /// Try IPv4 addresses first
+    V4,
+    /// Try IPv6 addresses first
+    V6,
+}
+
+impl std::str::FromStr for FamilyPreference {
+    type Err = String;
+
+    /// Parse `system`, `ipv4` or `ipv6`
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "system" => Ok(FamilyPreference::System),
+            "ipv4" => Ok(FamilyPreference::V4),
+            "ipv6" => Ok(FamilyPreference::V6),
+            _ => Err(format!("invalid address family '{}', expected system, ipv4 or ipv6", name)),
+        }
+    }
+}
+
+/// A request log entry, as emitted with `LogFormat::Json`
+#[derive(Debug, Serialize)]
+pub struct RequestLog {
+    /// Time of the request in milliseconds since the Unix epoch
+    pub timestamp: u128,
+    /// Server assigned ID of the connection the request was made on
+    pub conn_id: u64,
+    pub source_ip: IpAddr,
+    /// Client's source port, to match with the target's logs
+    pub source_port: u16,
+    /// User the client authenticated as, `None` without USERPASS
+    pub username: Option<String>,
+    pub command: String,
+    /// Requested domain name or IP address
+    pub dest_host: String,
+    pub dest_port: u16,
+    /// One of `ipv4`, `ipv6` or `domain`
+    pub addr_type: &'static str,
+}
+
+/// Hook to inspect or rewrite a reply before it is sent to the client
+pub type ReplyHook = Arc<dyn Fn(&ConnContext, &mut Vec<u8>) + Send + Sync>;
+
+/// Rewrites a client's username before it is authenticated, see
+/// `Options::username_normalizer`
+pub type UsernameNormalizer = Arc<dyn Fn(&str) -> String + Send + Sync>;
+
+/// Hook called with the host a CONNECT asked for and what it resolved to,
+/// see `Options::resolve_hook`
+pub type ResolveHook = Arc<dyn Fn(&ConnContext, &str, &[SocketAddr]) -> bool + Send + Sync>;
+
+/// Hook called with what a CONNECT target sent first, see
+/// `Options::connect_probe`
+pub type ProbeHook = Arc<dyn Fn(&ConnContext, &Address, &[u8]) -> bool + Send + Sync>;
+
+/// Hook called once a request's session is over, see `Options::session_hooks`
+pub type SessionHook = Arc<dyn Fn(&SessionSummary) + Send + Sync>;
+
+/// Hook called when a client is turned away, see `Options::on_rejected`
+pub type RejectHook = Arc<dyn Fn(SocketAddr, RejectReason) + Send + Sync>;
+
+/// Why a client was turned away, passed to `Options::on_rejected`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RejectReason {
+    /// Over `Options::max_connections`, including queued connections that
+    /// never got a slot
+    ConnectionCap,
+    /// The `rules` or the `resolve_hook` refused the CONNECT
+    Denied,
+    /// The user was at their limit of open requests
+    RateLimited,
+    /// Over the `Options::max_open_fds` budget
+    Overloaded,
+}
+
+/// What happened to a request, passed to session hooks once the connection
+/// closes
+#[derive(Clone, Debug)]
+pub struct SessionSummary {
+    /// The connection the request was made on
+    pub ctx: ConnContext,
+    pub command: SockCommand,
+    pub address: Address,
+    /// Last reply sent to the client, `None` if it went away first
+    pub reply: Option<ResponseCode>,
+    /// Bytes relayed from the client
+    pub bytes_up: u64,
+    /// Bytes relayed to the client
+    pub bytes_down: u64,
+    /// When the connection was accepted
+    pub started: SystemTime,
+    /// How long the connection lasted
+    pub duration: Duration,
+}
+
+/// What's known about a client connection, as seen by hooks
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnContext {
+    /// Server assigned ID of the connection
+    pub conn_id: u64,
+    pub peer_addr: SocketAddr,
+    /// Authentication method (METHOD code, see `AuthMethods`) negotiated with
+    /// the client
+    pub auth_method: Option<u8>,
+    /// Username the client authenticated as with USERPASS
+    pub username: Option<String>,
+}
+
+impl ConnContext {
+    /// Context of a connection that hasn't authenticated yet
+    pub fn new(conn_id: u64, peer_addr: SocketAddr) -> Self {
+        ConnContext {
+            conn_id,
+            peer_addr,
+            auth_method: None,
+            username: None,
+        }
+    }
+}
+
+/// Client Authentication Methods
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuthMethods {
+    /// No Authentication
+    NoAuth = 0x00,
+    // GssApi = 0x01,
+    /// Authenticate with a username / password
+    UserPass = 0x02,
+    /// Private method for merino-to-merino tunnels: no authentication, and
+    /// relayed data is compressed. Needs the `compression` feature.
+    CompressedTunnel = 0x8C,
+    /// Cannot authenticate
+    NoMethods = 0xFF
+}
+
+/// A bidirectional byte stream a SOCKS5 client can be served over
+pub(crate) trait ClientStream: Read + Write + Send + Sized + 'static {
+    /// Address of the local end of the stream
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+    /// Shutdown the read, write or both halves of the stream
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+    /// Create a new handle to the same underlying stream
+    fn try_clone(&self) -> io::Result<Self>;
+    /// Set the kernel send and receive buffer sizes (`SO_SNDBUF`/`SO_RCVBUF`)
+    fn set_buffer_size(&self, size: usize) -> io::Result<()>;
+    /// Mark outgoing packets with a DSCP value
+    fn set_dscp(&self, dscp: u8) -> io::Result<()>;
+    /// Make reads give up with `WouldBlock` or `TimedOut` after `timeout`
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    /// Make writes give up with `WouldBlock` or `TimedOut` after `timeout`
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    /// Turn Nagle's algorithm off (`TCP_NODELAY`) or back on
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()>;
+}
+
+impl ClientStream for TcpStream {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::local_addr(self)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        TcpStream::try_clone(self)
+    }
+
+    fn set_buffer_size(&self, size: usize) -> io::Result<()> {
+        connect::set_buffer_size(self, size)
+    }
+
+    fn set_dscp(&self, dscp: u8) -> io::Result<()> {
+        connect::set_dscp(self, dscp)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        TcpStream::set_nodelay(self, nodelay)
+    }
+}
+
+/// An end of a tunnel that `relay_half` can close
+pub(crate) trait TunnelEnd {
+    /// Shutdown the read, write or both halves of the tunnel end
+    fn close(&mut self, how: Shutdown) -> io::Result<()>;
+}
+
+impl<T: ClientStream> TunnelEnd for T {
+    fn close(&mut self, how: Shutdown) -> io::Result<()> {
+        self.shutdown(how)
+    }
+}
+
+/// Optional server settings
+///
+/// `Options::default()` matches the behavior of `Merino::new`.
+#[derive(Clone)]
+pub struct Options {
+    /// Maximum number of pending connections queued by the OS before `accept`.
+    ///
+    /// `None` keeps the standard library's default. The OS may silently clamp
+    /// the value: Linux caps it at `net.core.somaxconn` and other platforms
+    /// have similar limits of their own.
+    pub listen_backlog: Option<i32>,
+
+    /// Delay before racing the next address when a target resolves to several
+    /// ("happy eyeballs"). `None` tries each address in turn until one connects.
+    pub happy_eyeballs_delay: Option<Duration>,
+
+    /// Scope ID used for link-local IPv6 targets (`fe80::/10`), which SOCKS5
+    /// can't carry. `None` rejects link-local targets instead.
+    pub link_local_scope_id: Option<u32>,
+
+    /// Return from `serve` once there have been no active connections for this
+    /// long. `None` serves forever.
+    pub idle_shutdown: Option<Duration>,
+
+    /// Called with the CONNECT success reply (see `build_reply`) right before
+    /// it is sent, once the target connection is established.
+    pub connect_reply_hook: Option<ReplyHook>,
+
+    /// Called with up to `connect_probe_bytes` the target of a CONNECT sent
+    /// within `connect_probe_timeout` of connecting, before the client is
+    /// replied to. Returning `false` refuses the request with `RuleFailure`,
+    /// e.g. to only let SSH through by its banner. The bytes are only peeked
+    /// at, so the client still gets all of them. Targets of protocols where
+    /// the client speaks first send nothing, so the hook gets no bytes. Not
+    /// called for compressed `upstream` tunnels.
+    pub connect_probe: Option<ProbeHook>,
+
+    /// Most bytes `connect_probe` is shown
+    pub connect_probe_bytes: usize,
+
+    /// How long to wait for the target to send something for `connect_probe`
+    pub connect_probe_timeout: Duration,
+
+    /// Format of the per-request log line
+    pub log_format: LogFormat,
+
+    /// Which sources may send datagrams through a UDP association
+    pub udp_source_filter: UdpSourceFilter,
+
+    /// Kernel send and receive buffer size for both ends of a CONNECT tunnel,
+    /// bounding the memory a single connection can hold. `None` keeps the OS
+    /// default.
+    ///
+    /// The OS treats this as a hint: Linux doubles the value to account for
+    /// bookkeeping overhead and clamps it to `net.core.wmem_max`/`rmem_max`,
+    /// and other platforms may round it or enforce their own minimum.
+    pub socket_buffer_size: Option<usize>,
+
+    /// Maximum number of connections served at once. `None` is unlimited.
+    pub max_connections: Option<usize>,
+
+    /// File descriptor budget, normally a bit under the `RLIMIT_NOFILE` soft
+    /// limit. Connections that could take merino over it are closed straight
+    /// away instead of failing halfway through the handshake. Usage is
+    /// estimated at `FDS_PER_CONNECTION` for every active connection, on top
+    /// of the listeners and spare `upstream` connections. `None` is unlimited.
+    pub max_open_fds: Option<usize>,
+
+    /// When over `max_connections`, read the client's request and reply
+    /// `ResponseCode::Failure` instead of closing the connection right away,
+    /// so the client can tell why it was turned away. Costs a thread per
+    /// rejected connection.
+    pub overload_reply: bool,
+
+    /// When over `max_connections`, hold new connections for up to this long
+    /// in case a slot frees up, instead of turning them away right away. Each
+    /// queued connection waits on its own thread. Those still waiting at the
+    /// deadline are handled as without a queue. `None` doesn't queue.
+    pub overload_queue_timeout: Option<Duration>,
+
+    /// DSCP value (0-63) to mark CONNECT traffic to the target with, so it
+    /// can be classified by the network. `None` leaves the TOS untouched.
+    ///
+    /// This is best-effort: IPv6 traffic class support varies by platform and
+    /// failures are only logged.
+    pub dscp: Option<u8>,
+
+    /// Also mark traffic back to the client with `dscp`
+    pub dscp_client: bool,
+
+    /// Reply `RuleFailure` to a CONNECT to one of the server's own listening
+    /// addresses instead of looping back into the proxy
+    pub reject_self_connect: bool,
+
+    /// Reply `AddrTypeNotSupported` to requests naming a domain, and drop UDP
+    /// datagrams addressed to one, so the proxy never does DNS lookups and
+    /// clients have to send IP addresses
+    pub literal_only: bool,
+
+    /// Address types requests may name. Requests for any other type are
+    /// replied `AddrTypeNotSupported`, e.g. leave out `AddrType::V6` to turn
+    /// away clients asking for IPv6 literals. All three are allowed by default.
+    pub allowed_addr_types: Vec<AddrType>,
+
+    /// Never connect to IPv6 targets: IPv6 results of a lookup are dropped
+    /// and requests for IPv6 addresses are answered `AddrTypeNotSupported`.
+    /// For egress networks where IPv6 is broken or not allowed.
+    pub disable_ipv6: bool,
+
+    /// Never connect to IPv4 targets, the counterpart of `disable_ipv6` for
+    /// IPv6-only networks
+    pub disable_ipv4: bool,
+
+    /// Family to try first when a target resolves to both. With
+    /// `happy_eyeballs_delay` the preferred family starts the race and the
+    /// families still alternate; without it every address of the preferred
+    /// family is tried before any other. `System` keeps the resolver's order.
+    pub address_family_preference: FamilyPreference,
+
+    /// Reply `Failure` to requests with a nonzero reserved (RSV) byte. Such
+    /// requests are malformed, but some clients send them anyway.
+    pub strict_reserved: bool,
+
+    /// Send a PROXY protocol header with the client's address to CONNECT
+    /// targets, before any data from the client
+    pub proxy_protocol: Option<ProxyProtocol>,
+
+    /// Expect every connection to start with a PROXY protocol (v1 or v2)
+    /// header, as added by a load balancer in front of merino. The client
+    /// address it carries is used in place of the peer address for logging and
+    /// hooks. Connections without a header are dropped.
+    pub expect_proxy_protocol: bool,
+
+    /// Cut a CONNECT or BIND tunnel once this many bytes have been relayed,
+    /// counting both directions. `None` is unlimited.
+    pub max_session_bytes: Option<u64>,
+
+    /// Delay replies and throttle tunnels on purpose, for testing how
+    /// clients cope with a slow proxy. Never set this for real traffic.
+    /// `None` by default.
+    #[cfg(feature = "chaos")]
+    pub chaos: Option<Chaos>,
+
+    /// Set `SO_REUSEADDR` on the listening socket, so a new instance can bind
+    /// the port while connections to the old one are still closing. Unix
+    /// always sets it, like `TcpListener::bind`, so this only changes other
+    /// platforms.
+    pub reuse_addr: bool,
+
+    /// Set `SO_REUSEPORT` on the listening socket, letting several processes
+    /// listen on the same port. On Linux the kernel spreads connections
+    /// across them. Unix only, binding fails elsewhere.
+    pub reuse_port: bool,
+
+    /// Answer a connection that sends `HEALTH_CHECK_REQUEST` with
+    /// `HEALTH_CHECK_RESPONSE` and close it, as a cheap liveness probe for
+    /// TCP health checkers. The request starts with `M` (0x4D), which no
+    /// SOCKS version byte can be mistaken for.
+    pub health_check: bool,
+
+    /// Resolves the domain names of CONNECT targets. Wrap it in a
+    /// `CachingResolver` to skip repeated lookups.
+    pub resolver: Arc<dyn Resolver>,
+
+    /// Called in order with a summary of every request once its connection
+    /// closes, e.g. `AccessLog::hook`. Connections that never get as far as a
+    /// request aren't reported.
+    pub session_hooks: Vec<SessionHook>,
+
+    /// Access log every request is recorded in, before the `session_hooks`
+    /// are called. Unlike a log installed with `AccessLog::hook`, it can be
+    /// reopened with `Merino::reopen_access_log`.
+    pub access_log: Option<AccessLog>,
+
+    /// Called with the client's address whenever a connection or request is
+    /// turned away for one of the `RejectReason`s, e.g. to alert when the
+    /// proxy is undersized. Called on the serving thread for connections
+    /// rejected before the handshake, so it should be quick.
+    pub on_rejected: Option<RejectHook>,
+
+    /// Retry a CONNECT's name lookup or connection this many times when it
+    /// fails with a transient error, before replying with the error. Errors
+    /// that won't go away on their own, like an invalid address, aren't
+    /// retried. With `connect_timeout` set, retries also stop once waiting
+    /// for the next one would take longer than that since the first attempt.
+    pub connect_retries: u32,
+
+    /// Delay before the first retry, doubled for each one after it
+    pub connect_retry_backoff: Duration,
+
+    /// Which destinations CONNECT may reach. Denied requests are answered
+    /// with `RuleFailure`. Allows everything by default.
+    pub rules: RuleSet,
+
+    /// Destination ports to count relayed bytes for separately, see
+    /// `Handle::bytes_by_port`. Traffic to any other port is counted under
+    /// `PortBucket::Other`, which keeps the number of counters bounded.
+    pub tracked_ports: Vec<u16>,
+
+    /// Handlers for authentication methods beyond `NoAuth` and `UserPass`,
+    /// e.g. a private token scheme. Each handler's method is offered in
+    /// addition to the server's `auth_methods`, and preferred over the
+    /// built-in ones in the order given.
+    pub auth_handlers: Vec<Arc<dyn AuthHandler>>,
+
+    /// IP TTL (hop limit on IPv6) for CONNECT traffic to the target. `None`
+    /// keeps the OS default.
+    pub outbound_ttl: Option<u32>,
+
+    /// Address to report as BND.ADDR in CONNECT, BIND and UDP ASSOCIATE
+    /// replies in place of the local address, e.g. the public IP when merino
+    /// is behind NAT. Sockets are still bound locally and the port is kept.
+    pub advertised_addr: Option<IpAddr>,
+
+    /// Kill connections that haven't relayed any data for this long, checked
+    /// by a background thread. A safety net against sessions leaked by
+    /// half-open connections. `None` never reaps.
+    pub reap_idle: Option<Duration>,
+
+    /// Firewall mark (`SO_MARK`) to set on connections to CONNECT targets, so
+    /// policy routing can send proxied traffic through its own table. Linux
+    /// only, and needs `CAP_NET_ADMIN`; connecting fails otherwise.
+    pub fwmark: Option<u32>,
+
+    /// Rewrite USERPASS usernames before they are checked against the
+    /// credentials, e.g. to lowercase them or strip a `@realm` suffix. The
+    /// rewritten name is the one logged and reported. `None` keeps names as
+    /// sent.
+    pub username_normalizer: Option<UsernameNormalizer>,
+
+    /// Give up on each address of a CONNECT target after this long. A domain
+    /// resolving to several addresses has each of them tried in turn, so a
+    /// blackholed address only costs this much before the next is tried.
+    /// It also bounds the time spent on `connect_retries`. `None` leaves it
+    /// to the OS, which can take minutes.
+    pub connect_timeout: Option<Duration>,
+
+    /// Inclusive range of local ports to connect to CONNECT targets from, for
+    /// egress firewalls that filter on source port. Ports already in use are
+    /// skipped, and the CONNECT fails once the whole range is. `None` lets the
+    /// OS pick.
+    pub outbound_port_range: Option<(u16, u16)>,
+
+    /// Close the connection of any client offering more than this many
+    /// authentication methods, without a reply. There are only a handful of
+    /// methods, so real clients stay well below the default of 16. 255
+    /// accepts anything.
+    pub max_auth_methods: u8,
+
+    /// Close the connection of any client that sends more than this many
+    /// bytes before its request is complete, counting a PROXY protocol header
+    /// and the authentication subnegotiation. Each message is bounded on its
+    /// own, this bounds custom `auth_handlers` that keep on reading too.
+    pub max_handshake_bytes: u64,
+
+    /// Rules for clients that didn't authenticate as a user, e.g. with
+    /// `NoAuth`, in place of `rules`. Offering both `NoAuth` and `UserPass`
+    /// then makes authentication optional: clients that log in get `rules`,
+    /// anonymous ones get these. `None` applies `rules` to everyone.
+    pub anonymous_rules: Option<RuleSet>,
+
+    /// How long `serve` lets open connections finish after
+    /// `Handle::shutdown` before killing them
+    pub shutdown_drain_timeout: Duration,
+
+    /// Called with the domain name of a CONNECT or UDP datagram and the
+    /// addresses it resolved to, before `rules` are applied or anything is
+    /// sent. Returning `false` refuses a CONNECT with `RuleFailure` and drops
+    /// a datagram, e.g. to catch names rebound to internal addresses. Not
+    /// called for IP addresses.
+    pub resolve_hook: Option<ResolveHook>,
+
+    /// Close a direction of a CONNECT or BIND tunnel once nothing has been
+    /// read from its source for this long: the target for data going to the
+    /// client, the client for data going to the target. Set it generously to
+    /// allow for servers that think for a long time. `None` waits forever.
+    pub relay_read_timeout: Option<Duration>,
+
+    /// Close a direction of a tunnel once a write to its destination has been
+    /// blocked for this long, because the client or target stopped reading.
+    /// This catches slow readers holding on to a session. `None` waits
+    /// forever.
+    pub relay_write_timeout: Option<Duration>,
+
+    /// Connect to CONNECT targets with TCP Fast Open, so the client's first
+    /// data goes out in the SYN and a round trip is saved on repeat
+    /// connections. Needs the `tcp-fastopen` feature and Linux 4.11 or later
+    /// with client support enabled in the `net.ipv4.tcp_fastopen` sysctl;
+    /// connecting fails otherwise.
+    ///
+    /// The handshake is only started by the first write to the target, so
+    /// the success reply is sent before the target is known to be reachable,
+    /// and protocols where the server speaks first never connect. Only turn
+    /// this on for client-first protocols like HTTP and TLS.
+    pub tcp_fast_open: bool,
+
+    /// Network namespace to connect to CONNECT targets from, as a namespace
+    /// file like `/var/run/netns/vpn`. Listeners stay in the server's own
+    /// namespace. Needs the `netns` feature, Linux and `CAP_SYS_ADMIN`.
+    pub netns: Option<PathBuf>,
+
+    /// SOCKS5 proxy to send CONNECT requests through instead of connecting to
+    /// targets directly, normally another merino. It must not require
+    /// authentication. Targets are still resolved locally for the `rules`.
+    pub upstream: Option<SocketAddr>,
+
+    /// Offer the `upstream` a compressed tunnel (`AuthMethods::CompressedTunnel`),
+    /// falling back to plain relaying if it doesn't support one. Only pays
+    /// off for compressible traffic over slow links. Needs the `compression`
+    /// feature.
+    pub upstream_compression: bool,
+
+    /// Connections to the `upstream` to keep open ahead of time, so requests
+    /// don't wait for a TCP handshake with it. A connection to a SOCKS5 proxy
+    /// carries a single request, so they're never reused: each one taken is
+    /// replaced in the background. 0 opens connections on demand.
+    pub upstream_pool_size: usize,
+
+    /// How long a spare `upstream` connection is kept before it's considered
+    /// stale. Keep this below any timeout the upstream has for connections
+    /// that haven't sent anything yet.
+    pub upstream_pool_idle: Duration,
+
+    /// Most requests a single authenticated user may have open at once.
+    /// Requests over the limit are refused with `RuleFailure`. Anonymous
+    /// clients aren't limited. `None` is unlimited.
+    pub max_connections_per_user: Option<usize>,
+
+    /// Failed `UserPass` logins after which a client IP is locked out. While
+    /// locked out its logins are refused without checking the credentials.
+    /// `None` never locks clients out.
+    pub auth_failure_threshold: Option<u32>,
+
+    /// How long a lockout lasts after the last failed login. Failures further
+    /// apart than this don't count towards `auth_failure_threshold`.
+    pub auth_lockout_duration: Duration,
+
+    /// How long to hold back the reply to a failed `UserPass` login, to slow
+    /// down password guessing. Each failure waits a random time between half
+    /// of this and all of it, capped at `MAX_AUTH_FAILURE_DELAY`. Every client
+    /// is served on its own thread, so only the failing connection waits, but
+    /// it keeps its place under `max_connections` meanwhile. Zero replies
+    /// straight away.
+    pub auth_failure_delay: Duration,
+
+    /// Limits for particular users, in place of `max_connections_per_user`
+    pub user_connection_limits: HashMap<String, usize>,
+
+    /// Destination ports to log a warning about when requested, e.g.
+    /// `COMMON_SENSITIVE_PORTS`. Requests are still served, so abuse can be
+    /// watched for before rolling out `rules` that block it.
+    pub sensitive_ports: Vec<u16>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            listen_backlog: None,
+            // Recommended by RFC 8305
+            happy_eyeballs_delay: Some(Duration::from_millis(250)),
+            link_local_scope_id: None,
+            idle_shutdown: None,
+            connect_reply_hook: None,
+            connect_probe: None,
+            connect_probe_bytes: 64,
+            connect_probe_timeout: Duration::from_secs(1),
+            log_format: LogFormat::Text,
+            udp_source_filter: UdpSourceFilter::Strict,
+            socket_buffer_size: None,
+            max_connections: None,
+            max_open_fds: None,
+            overload_reply: false,
+            overload_queue_timeout: None,
+            dscp: None,
+            dscp_client: false,
+            reject_self_connect: false,
+            literal_only: false,
+            allowed_addr_types: vec![AddrType::V4, AddrType::Domain, AddrType::V6],
+            disable_ipv6: false,
+            disable_ipv4: false,
+            address_family_preference: FamilyPreference::System,
+            strict_reserved: false,
+            proxy_protocol: None,
+            expect_proxy_protocol: false,
+            max_session_bytes: None,
+            #[cfg(feature = "chaos")]
+            chaos: None,
+            reuse_addr: false,
+            reuse_port: false,
+            health_check: false,
+            resolver: Arc::new(SystemResolver),
+            session_hooks: Vec::new(),
+            access_log: None,
+            on_rejected: None,
+            connect_retries: 0,
+            connect_retry_backoff: Duration::from_millis(100),
+            rules: RuleSet::default(),
+            tracked_ports: Vec::new(),
+            auth_handlers: Vec::new(),
+            outbound_ttl: None,
+            advertised_addr: None,
+            reap_idle: None,
+            fwmark: None,
+            username_normalizer: None,
+            connect_timeout: None,
+            outbound_port_range: None,
+            max_auth_methods: 16,
+            max_handshake_bytes: 8 * 1024,
+            anonymous_rules: None,
+            shutdown_drain_timeout: Duration::from_secs(30),
+            resolve_hook: None,
+            relay_read_timeout: None,
+            relay_write_timeout: None,
+            tcp_fast_open: false,
+            netns: None,
+            upstream: None,
+            upstream_compression: false,
+            upstream_pool_size: 0,
+            upstream_pool_idle: Duration::from_secs(10),
+            max_connections_per_user: None,
+            auth_failure_threshold: None,
+            auth_lockout_duration: Duration::from_secs(300),
+            auth_failure_delay: Duration::ZERO,
+            user_connection_limits: HashMap::new(),
+            sensitive_ports: Vec::new(),
+        }
+    }
+}
+
+impl Options {
+    /// Check whether a CONNECT to `address` would succeed, without relaying
+    ///
+    /// This goes through the same resolution and connect path as a live
+    /// request and reports the reply a client would receive. The test
+    /// connection is closed straight away.
+    pub fn check(&self, address: &Address) -> ResponseCode {
+        match self.connect(address) {
+            Ok(_) => ResponseCode::Success,
+            Err(error) => {
+                debug!("Check for {} failed: {}", address, error);
+                ResponseCode::from(&error)
+            }
+        }
+    }
+
+    /// Resolve `address` and open a connection to it
+    fn connect(&self, address: &Address) -> io::Result<TcpStream> {
+        let mut sock_addr = self.retry(|| self.resolve(address))?;
+        self.retain_families(&mut sock_addr, address)?;
+        self.retry(|| self.connect_to(&sock_addr))
+    }
+
+    /// Call `attempt` until it succeeds, fails permanently, `connect_retries`
+    /// run out or `connect_timeout` is spent
+    fn retry<T>(&self, mut attempt: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+        let start = Instant::now();
+        let mut backoff = self.connect_retry_backoff;
+        let mut retries = 0;
+        loop {
+            let within_budget = || self.connect_timeout.is_none_or(|budget| start.elapsed() + backoff <= budget);
+            match attempt() {
+                Err(e) if retries < self.connect_retries && is_transient(&e) && within_budget() => {
+                    retries += 1;
+                    debug!("Retrying after {:?} ({}/{}): {}", backoff, retries, self.connect_retries, e);
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                },
+                result => return result,
+            }
+        }
+    }
+
+    /// Address to report in a reply for the locally `bound` address
+    fn advertised(&self, bound: SocketAddr) -> SocketAddr {
+        match self.advertised_addr {
+            Some(ip) => SocketAddr::new(ip, bound.port()),
+            None => bound,
+        }
+    }
+
+    /// Resolve `address` to the socket addresses a CONNECT would try
+    fn resolve(&self, address: &Address) -> io::Result<Vec<SocketAddr>> {
+        let mut sock_addr = match address {
+            Address::Domain(domain, port) => {
+                let domain = std::str::from_utf8(domain).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                self.resolver.resolve(domain, *port)?
+            },
+            _ => address.to_socket_addrs()?,
+        };
+        connect::apply_scope_id(&mut sock_addr, self.link_local_scope_id)?;
+        Ok(sock_addr)
+    }
+
+    /// Whether connecting to `ip` is allowed by `disable_ipv4` and `disable_ipv6`
+    fn allows_family(&self, ip: IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(_) => !self.disable_ipv4,
+            IpAddr::V6(_) => !self.disable_ipv6,
+        }
+    }
+
+    /// Move the addresses of the preferred family to the front, keeping the
+    /// order within each family
+    fn order_families(&self, sock_addr: &mut [SocketAddr]) {
+        match self.address_family_preference {
+            FamilyPreference::System => {},
+            FamilyPreference::V4 => sock_addr.sort_by_key(SocketAddr::is_ipv6),
+            FamilyPreference::V6 => sock_addr.sort_by_key(SocketAddr::is_ipv4),
+        }
+    }
+
+    /// Drop the resolved addresses of `address` in a disabled family, failing
+    /// with `HostUnreachable` if none are left
+    fn retain_families(&self, sock_addr: &mut Vec<SocketAddr>, address: &Address) -> io::Result<()> {
+        sock_addr.retain(|addr| self.allows_family(addr.ip()));
+        if sock_addr.is_empty() {
+            let error = format!("{} has no addresses in an enabled address family", address);
+            return Err(io::Error::new(io::ErrorKind::HostUnreachable, error));
+        }
+        Ok(())
+    }
+
+    /// Rules that apply to the client on `ctx`, depending on whether it
+    /// authenticated as a user
+    pub fn rules_for(&self, ctx: &ConnContext) -> &RuleSet {
+        match (&self.anonymous_rules, &ctx.username) {
+            (Some(anonymous), None) => anonymous,
+            _ => &self.rules,
+        }
+    }
+
+    /// How many requests `username` may have open at once, if limited
+    pub fn user_connection_limit(&self, username: &str) -> Option<usize> {
+        self.user_connection_limits.get(username).copied().or(self.max_connections_per_user)
+    }
+
+    /// Tell the `on_rejected` hook that `peer` was turned away
+    fn rejected(&self, peer: SocketAddr, reason: RejectReason) {
+        if let Some(hook) = &self.on_rejected {
+            hook(peer, reason);
+        }
+    }
+
+    /// Open a connection to the first reachable address of `sock_addr`
+    fn connect_to(&self, sock_addr: &[SocketAddr]) -> io::Result<TcpStream> {
+        trace!("Connecting to: {:?}", sock_addr);
+
+        connect::connect(sock_addr, self.happy_eyeballs_delay, connect::Outbound {
+            fwmark: self.fwmark,
+            timeout: self.connect_timeout,
+            port_range: self.outbound_port_range,
+            fast_open: self.tcp_fast_open,
+            netns: self.netns.clone(),
+        })
+    }
+}
+
+/// Ports commonly abused through open proxies: SMTP, telnet, SMB and RDP. A
+/// starting point for `Options::sensitive_ports`.
+pub const COMMON_SENSITIVE_PORTS: &[u16] = &[23, 25, 445, 465, 587, 3389];
+
+/// What a health checker sends, see `Options::health_check`
+pub const HEALTH_CHECK_REQUEST: &[u8] = b"MERINO-HEALTH\n";
+
+/// What merino answers a health check with
+pub const HEALTH_CHECK_RESPONSE: &[u8] = b"OK\n";
+
+/// File descriptors a relaying CONNECT holds: the client's and the target's
+/// sockets, a clone of each to shut them down on demand, and the clones the
+/// two relay threads work on. See `Options::max_open_fds`.
+pub const FDS_PER_CONNECTION: usize = 7;
+
+/// File descriptors open before any connection, stdin, stdout and stderr
+const BASE_FDS: usize = 3;
+
+/// How often `serve` wakes up to check the idle timeout
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How often `serve` polls when it has several listeners, which bounds the
+/// latency added to accepting a connection
+const MULTI_ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// How often relay threads wake up to check whether their session was
+/// cancelled
+const RELAY_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How often the reaper looks for idle connections, at most
+const REAP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often a draining server checks whether its connections are done
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How often a queued connection checks for a free slot
+const QUEUE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How long a BIND listener waits for the incoming connection
+const BIND_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Destination port traffic is counted under, see `Handle::bytes_by_port`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum PortBucket {
+    /// One of `Options::tracked_ports`
+    Port(u16),
+    /// Every other port
+    Other,
+}
+
+/// Bytes relayed in each direction
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ByteCounts {
+    /// From clients to targets
+    pub up: u64,
+    /// From targets to clients
+    pub down: u64,
+}
+
+/// An open connection, see `Handle::list_sessions`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SessionInfo {
+    pub conn_id: u64,
+    /// The client, as reported by a PROXY protocol header if there is one
+    pub peer_addr: SocketAddr,
+    /// User the client authenticated as, if any
+    pub username: Option<String>,
+    /// Requested destination, `None` until the request has been read
+    pub destination: Option<Address>,
+    pub started: SystemTime,
+    /// Bytes relayed so far, for CONNECT and BIND
+    pub bytes: ByteCounts,
+}
+
+/// Shuts down one of a session's streams, see `Handle::kill_connection`
+type StreamKiller = Box<dyn Fn() + Send>;
+
+/// Liveness of a connection, shared between its threads and the registry
+struct SessionStatus {
+    /// Checked by the relay between reads, see `Handle::kill_connection`
+    cancelled: AtomicBool,
+    /// When data was last relayed (or the connection opened), in milliseconds
+    /// since `epoch`
+    last_active: AtomicU64,
+    epoch: Instant,
+    /// Bytes relayed so far from the client to the target
+    bytes_up: AtomicU64,
+    /// Bytes relayed so far from the target to the client
+    bytes_down: AtomicU64,
+    /// Who the connection is and where it's going, filled in as the
+    /// handshake goes along
+    details: Mutex<SessionDetails>,
+}
+
+/// The parts of `SessionInfo` that are only known once the handshake is done
+struct SessionDetails {
+    peer_addr: SocketAddr,
+    username: Option<String>,
+    destination: Option<Address>,
+    started: SystemTime,
+}
+
+impl SessionStatus {
+    fn new(peer_addr: SocketAddr) -> Self {
+        SessionStatus {
+            cancelled: AtomicBool::new(false),
+            last_active: AtomicU64::new(0),
+            epoch: Instant::now(),
+            bytes_up: AtomicU64::new(0),
+            bytes_down: AtomicU64::new(0),
+            details: Mutex::new(SessionDetails { peer_addr, username: None, destination: None, started: SystemTime::now() }),
+        }
+    }
+
+    /// Snapshot of connection `conn_id` for `Handle::list_sessions`
+    fn info(&self, conn_id: u64) -> SessionInfo {
+        let details = self.details.lock().unwrap();
+        SessionInfo {
+            conn_id,
+            peer_addr: details.peer_addr,
+            username: details.username.clone(),
+            destination: details.destination.clone(),
+            started: details.started,
+            bytes: ByteCounts {
+                up: self.bytes_up.load(Ordering::Relaxed),
+                down: self.bytes_down.load(Ordering::Relaxed),
+            },
+        }
+    }
+
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Record activity on the connection
+    fn touch(&self) {
+        self.last_active.store(self.epoch.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Time since the connection was last active
+    fn idle(&self) -> Duration {
+        self.epoch.elapsed().saturating_sub(Duration::from_millis(self.last_active.load(Ordering::Relaxed)))
+    }
+}
+
+/// How to stop an open connection
+struct SessionControl {
+    status: Arc<SessionStatus>,
+    streams: Vec<StreamKiller>,
+}
+
+/// Runtime state shared between the accept loop and connection threads
+struct ServerState {
+    active_connections: AtomicUsize,
+    last_active: Mutex<Instant>,
+    next_conn_id: AtomicU64,
+    /// Reply code sent to every request in maintenance mode, 0 (`Success`) when off
+    maintenance: AtomicU8,
+    /// Addresses the server is listening on
+    listen_addrs: Vec<SocketAddr>,
+    started: Instant,
+    /// Connections served, not counting ones turned away when overloaded
+    total_connections: AtomicU64,
+    peak_connections: AtomicUsize,
+    /// Bytes relayed from clients to targets
+    bytes_up: AtomicU64,
+    /// Bytes relayed from targets to clients
+    bytes_down: AtomicU64,
+    /// Bytes relayed by destination port, added as sessions close
+    port_bytes: Mutex<HashMap<PortBucket, ByteCounts>>,
+    /// Streams of every open connection by ID, to shut them down on demand
+    sessions: Mutex<HashMap<u64, SessionControl>>,
+    /// Clients that offered no authentication methods at all
+    empty_method_offers: AtomicU64,
+    /// Set by `Handle::shutdown` to make `serve` drain and return
+    shutdown_requested: AtomicBool,
+    /// Open requests by user, for `Options::max_connections_per_user`. Users
+    /// without any are left out.
+    user_connections: Mutex<HashMap<String, usize>>,
+    /// Failed logins, for `Options::auth_failure_threshold`
+    auth_lockout: Option<Arc<AuthLockout>>,
+    /// Spare connections, for `Options::upstream_pool_size`
+    upstream_pool: Option<Arc<UpstreamPool>>,
+}
+
+impl ServerState {
+    fn new(listen_addrs: Vec<SocketAddr>) -> Self {
+        ServerState {
+            listen_addrs,
+            active_connections: AtomicUsize::new(0),
+            last_active: Mutex::new(Instant::now()),
+            next_conn_id: AtomicU64::new(0),
+            maintenance: AtomicU8::new(ResponseCode::Success as u8),
+            started: Instant::now(),
+            total_connections: AtomicU64::new(0),
+            peak_connections: AtomicUsize::new(0),
+            bytes_up: AtomicU64::new(0),
+            bytes_down: AtomicU64::new(0),
+            port_bytes: Mutex::new(HashMap::new()),
+            sessions: Mutex::new(HashMap::new()),
+            empty_method_offers: AtomicU64::new(0),
+            shutdown_requested: AtomicBool::new(false),
+            user_connections: Mutex::new(HashMap::new()),
+            auth_lockout: None,
+            upstream_pool: None,
+        }
+    }
+
+    /// Log the totals since the server started
+    fn log_summary(&self) {
+        info!("Served {} connections (peak {} concurrent), relayed {} bytes up and {} bytes down in {:?}",
+              self.total_connections.load(Ordering::SeqCst),
+              self.peak_connections.load(Ordering::SeqCst),
+              self.bytes_up.load(Ordering::SeqCst),
+              self.bytes_down.load(Ordering::SeqCst),
+              self.started.elapsed()
+        );
+    }
+
+    /// Rough number of file descriptors in use, see `Options::max_open_fds`
+    fn estimated_open_fds(&self) -> usize {
+        let spares = self.upstream_pool.as_ref().map_or(0, |pool| pool.size());
+        BASE_FDS + self.listen_addrs.len() + spares + self.active_connections.load(Ordering::SeqCst) * FDS_PER_CONNECTION
+    }
+
+    /// Reply code to send if in maintenance mode
+    fn maintenance(&self) -> Option<ResponseCode> {
+        match ResponseCode::from_code(self.maintenance.load(Ordering::SeqCst)) {
+            Some(ResponseCode::Success) => None,
+            code => code,
+        }
+    }
+
+    /// Whether `target` is one of the server's own listening addresses
+    ///
+    /// A listener on an unspecified address is reachable through loopback and
+    /// `local_ip`, the address the client reached the server on.
+    fn is_listen_addr(&self, target: SocketAddr, local_ip: IpAddr) -> bool {
+        self.listen_addrs.iter().any(|listen| {
+            listen.port() == target.port() && (listen.ip() == target.ip() || listen.ip().is_unspecified() && (target.ip().is_loopback() || target.ip().is_unspecified() || target.ip() == local_ip))
+        })
+    }
+
+    /// Count a closed session's bytes under its destination `bucket`
+    fn add_port_bytes(&self, bucket: PortBucket, up: u64, down: u64) {
+        let mut port_bytes = self.port_bytes.lock().unwrap();
+        let counts = port_bytes.entry(bucket).or_default();
+        counts.up += up;
+        counts.down += down;
+    }
+
+    /// Start tracking connection `conn_id`, which stops relaying once
+    /// `status` is cancelled
+    fn open_session(&self, conn_id: u64, status: Arc<SessionStatus>) {
+        self.sessions.lock().unwrap().insert(conn_id, SessionControl { status, streams: Vec::new() });
+    }
+
+    /// Shut down `stream` along with the rest of connection `conn_id`
+    ///
+    /// Does nothing if the connection isn't tracked, e.g. because it was
+    /// killed already. Its client stream is shut down then, so it can't get
+    /// any further.
+    fn track_stream<S: ClientStream>(&self, conn_id: u64, stream: S) {
+        if let Some(control) = self.sessions.lock().unwrap().get_mut(&conn_id) {
+            control.streams.push(Box::new(move || stream.shutdown(Shutdown::Both).unwrap_or(())));
+        }
+    }
+
+    /// Stop tracking connection `conn_id` once it's over
+    fn close_session(&self, conn_id: u64) {
+        self.sessions.lock().unwrap().remove(&conn_id);
+    }
+
+    /// Cancel connection `conn_id` and shut down all of its streams
+    fn kill_session(&self, conn_id: u64) -> bool {
+        // Shut down outside the lock
+        let control = self.sessions.lock().unwrap().remove(&conn_id);
+        match control {
+            Some(control) => {
+                info!("Killing connection {}", conn_id);
+                control.status.cancelled.store(true, Ordering::SeqCst);
+                control.streams.iter().for_each(|kill| kill());
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Snapshot of every open connection, ordered by ID
+    fn list_sessions(&self) -> Vec<SessionInfo> {
+        // Only clone the handles under the registry lock, so accepting isn't held up
+        let statuses: Vec<(u64, Arc<SessionStatus>)> = self.sessions.lock().unwrap().iter()
+            .map(|(conn_id, control)| (*conn_id, control.status.clone()))
+            .collect();
+
+        let mut sessions: Vec<SessionInfo> = statuses.iter().map(|(conn_id, status)| status.info(*conn_id)).collect();
+        sessions.sort_by_key(|session| session.conn_id);
+        sessions
+    }
+
+    /// Kill every open connection
+    fn kill_all_sessions(&self) {
+        let conn_ids: Vec<u64> = self.sessions.lock().unwrap().keys().copied().collect();
+        for conn_id in conn_ids {
+            self.kill_session(conn_id);
+        }
+    }
+
+    /// Kill every connection that has been idle for longer than `timeout`
+    fn reap_idle(&self, timeout: Duration) {
+        let idle: Vec<u64> = self.sessions.lock().unwrap().iter()
+            .filter(|(_, control)| control.status.idle() > timeout)
+            .map(|(conn_id, _)| *conn_id)
+            .collect();
+
+        for conn_id in idle {
+            warn!("Reaping connection {}, idle for over {:?}", conn_id, timeout);
+            self.kill_session(conn_id);
+        }
+    }
+
+    /// Whether no connection has been active for at least `timeout`
+    fn idle_for(&self, timeout: Duration) -> bool {
+        self.active_connections.load(Ordering::SeqCst) == 0 && self.last_active.lock().unwrap().elapsed() >= timeout
+    }
+}
+
+/// Counts a connection as active for as long as it's alive
+struct ConnectionGuard {
+    state: Arc<ServerState>,
+}
+
+impl ConnectionGuard {
+    fn new(state: Arc<ServerState>) -> Self {
+        let active = state.active_connections.fetch_add(1, Ordering::SeqCst) + 1;
+        ConnectionGuard::counted(state, active)
+    }
+
+    /// Count a connection as active, unless there are `max` already
+    fn try_new(state: Arc<ServerState>, max: usize) -> Option<Self> {
+        let active = state.active_connections
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |active| (active < max).then_some(active + 1))
+            .ok()? + 1;
+        Some(ConnectionGuard::counted(state, active))
+    }
+
+    /// Update the statistics for a connection that made it `active`
+    fn counted(state: Arc<ServerState>, active: usize) -> Self {
+        state.peak_connections.fetch_max(active, Ordering::SeqCst);
+        state.total_connections.fetch_add(1, Ordering::SeqCst);
+        *state.last_active.lock().unwrap() = Instant::now();
+        ConnectionGuard { state }
+    }
+}
+
+/// Counts a connection towards its user's `Options::max_connections_per_user`
+/// for as long as it's alive
+struct UserSlot {
+    state: Arc<ServerState>,
+    username: String,
+}
+
+impl UserSlot {
+    /// Take one of `username`'s slots, unless all `limit` are taken
+    fn acquire(state: Arc<ServerState>, username: String, limit: usize) -> Option<Self> {
+        {
+            let mut user_connections = state.user_connections.lock().unwrap();
+            // Check before inserting, users without connections are left out
+            if user_connections.get(&username).copied().unwrap_or(0) >= limit {
+                return None;
+            }
+            *user_connections.entry(username.clone()).or_insert(0) += 1;
+        }
+        Some(UserSlot { state, username })
+    }
+}
+
+impl Drop for UserSlot {
+    fn drop(&mut self) {
+        let mut user_connections = self.state.user_connections.lock().unwrap();
+        if let Some(active) = user_connections.get_mut(&self.username) {
+            *active -= 1;
+            if *active == 0 {
+                user_connections.remove(&self.username);
+            }
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        *self.state.last_active.lock().unwrap() = Instant::now();
+        self.state.active_connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Handle to control a running `Merino` from other threads
+#[derive(Clone)]
+pub struct Handle {
+    state: Arc<ServerState>,
+}
+
+impl Handle {
+    /// Enter maintenance mode: connections are still accepted, but every
+    /// request is answered with `code` and closed without contacting the target
+    pub fn set_maintenance(&self, code: ResponseCode) {
+        // `Success` would mean "off", so never store it
+        let code = if code == ResponseCode::Success { ResponseCode::Failure } else { code };
+        info!("Entering maintenance mode, replying {:?}", code);
+        self.state.maintenance.store(code as u8, Ordering::SeqCst);
+    }
+
+    /// Leave maintenance mode and serve requests normally again
+    pub fn clear_maintenance(&self) {
+        info!("Leaving maintenance mode");
+        self.state.maintenance.store(ResponseCode::Success as u8, Ordering::SeqCst);
+    }
+
+    /// Whether maintenance mode is on
+    pub fn in_maintenance(&self) -> bool {
+        self.state.maintenance().is_some()
+    }
+
+    /// Number of connections currently being served, in the handshake or
+    /// relaying
+    pub fn active_connections(&self) -> usize {
+        self.state.active_connections.load(Ordering::SeqCst)
+    }
+
+    /// Rough number of file descriptors in use, see `Options::max_open_fds`
+    pub fn estimated_open_fds(&self) -> usize {
+        self.state.estimated_open_fds()
+    }
+
+    /// Number of connections served since the server was created, not
+    /// counting ones turned away when over `max_connections`
+    pub fn total_connections(&self) -> u64 {
+        self.state.total_connections.load(Ordering::SeqCst)
+    }
+
+    /// Forcibly close connection `conn_id` (see `ConnContext::conn_id`),
+    /// shutting down both the client's stream and the target's
+    ///
+    /// Returns `false` if there's no such connection open.
+    pub fn kill_connection(&self, conn_id: u64) -> bool {
+        self.state.kill_session(conn_id)
+    }
+
+    /// Bytes relayed by CONNECT and BIND sessions, by the port in the request
+    ///
+    /// Sessions are counted once they close. Only buckets that have seen a
+    /// session are present.
+    pub fn bytes_by_port(&self) -> HashMap<PortBucket, ByteCounts> {
+        self.state.port_bytes.lock().unwrap().clone()
+    }
+
+    /// Snapshot of every open connection: who it is, where it's going and
+    /// how much it has relayed so far
+    pub fn list_sessions(&self) -> Vec<SessionInfo> {
+        self.state.list_sessions()
+    }
+
+    /// Stop accepting connections and make `serve` return once the open ones
+    /// are done, or `Options::shutdown_drain_timeout` has passed
+    pub fn shutdown(&self) {
+        info!("Shutdown requested");
+        self.state.shutdown_requested.store(true, Ordering::SeqCst);
+
+        // Wake up a blocking accept with a connection of our own
+        for addr in &self.state.listen_addrs {
+            let mut addr = *addr;
+            if addr.ip().is_unspecified() {
+                addr.set_ip(if addr.is_ipv4() { Ipv4Addr::LOCALHOST.into() } else { Ipv6Addr::LOCALHOST.into() });
+            }
+            TcpStream::connect_timeout(&addr, Duration::from_secs(1)).map(drop).unwrap_or(());
+        }
+    }
+
+    /// Number of clients that sent a greeting with no authentication methods
+    /// at all, which usually means a broken client or a scanner
+    pub fn empty_method_offers(&self) -> u64 {
+        self.state.empty_method_offers.load(Ordering::SeqCst)
+    }
+}
+
+/// What clients of one listener may do, see `Merino::with_listeners`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ListenerPolicy {
+    /// Authentication methods offered on this listener
+    pub auth_methods: Vec<u8>,
+    /// Rules for CONNECT requests on this listener, in place of
+    /// `Options::rules` and `Options::anonymous_rules`. `None` uses those.
+    pub rules: Option<RuleSet>,
+}
+
+/// A listening socket and the policy its connections are served under
+struct Listener {
+    socket: TcpListener,
+    policy: Arc<ListenerPolicy>,
+}
+
+pub struct Merino {
+    listeners: Vec<Listener>,
+    credentials: Arc<dyn CredentialStore>,
+    options: Arc<Options>,
+    state: Arc<ServerState>
+}
+
+impl Merino {
+    /// Create a new Merino instance
+    pub fn new(port: u16,  ip: String, auth_methods: Vec<u8>, users: Vec<User>) -> Result<Self, Box<dyn Error>> {
+        Merino::with_options(port, ip, auth_methods, users, Options::default())
+    }
+
+    /// Create a new Merino instance with custom `Options`
+    ///
+    /// `ip` may be a comma-separated list of addresses, to listen on each of
+    /// them on `port`.
+    pub fn with_options(port: u16, ip: String, auth_methods: Vec<u8>, users: Vec<User>, options: Options) -> Result<Self, Box<dyn Error>> {
+        let mut listeners = Vec::new();
+        for ip in ip.split(',').map(str::trim) {
+            info!("Listening on {}:{}", ip, port);
+            listeners.push(bind(&format!("{}:{}", ip, port), &options)?);
+        }
+
+        Merino::from_listeners(listeners, auth_methods, users, options)
+    }
+
+    /// Create a new Merino instance serving an already bound `listener`,
+    /// e.g. one inherited through socket activation
+    pub fn from_listener(listener: TcpListener, auth_methods: Vec<u8>, users: Vec<User>) -> Result<Self, Box<dyn Error>> {
+        Merino::from_listener_with_options(listener, auth_methods, users, Options::default())
+    }
+
+    /// Create a new Merino instance with custom `Options` serving an already
+    /// bound `listener`
+    ///
+    /// The listener settings in `options` (`listen_backlog`, `reuse_addr` and
+    /// `reuse_port`) have no effect, as the socket is already listening.
+    pub fn from_listener_with_options(listener: TcpListener, auth_methods: Vec<u8>, users: Vec<User>, options: Options) -> Result<Self, Box<dyn Error>> {
+        info!("Listening on {}", listener.local_addr()?);
+        Merino::from_listeners(vec![listener], auth_methods, users, options)
+    }
+
+    /// Create a new Merino instance serving several already bound listeners,
+    /// each under its own `ListenerPolicy`
+    ///
+    /// For example, `NoAuth` on a port only reachable from a trusted network
+    /// next to `UserPass` on a public one. Everything else in `options`
+    /// applies to all of them, and `max_connections` counts them together.
+    pub fn with_listeners(listeners: Vec<(TcpListener, ListenerPolicy)>, users: Vec<User>, options: Options) -> Result<Self, Box<dyn Error>> {
+        if listeners.is_empty() {
+            return Err(Box::new(io::Error::new(io::ErrorKind::InvalidInput, "no listeners to serve")));
+        }
+
+        let listeners = listeners.into_iter()
+            .map(|(socket, policy)| {
+                info!("Listening on {} with auth methods {:?}", socket.local_addr()?, policy.auth_methods);
+                Ok(Listener { socket, policy: Arc::new(policy) })
+            })
+            .collect::<io::Result<_>>()?;
+        Merino::serving(listeners, users, options)
+    }
+
+    fn from_listeners(listeners: Vec<TcpListener>, auth_methods: Vec<u8>, users: Vec<User>, options: Options) -> Result<Self, Box<dyn Error>> {
+        let policy = Arc::new(ListenerPolicy { auth_methods, rules: None });
+        let listeners = listeners.into_iter().map(|socket| Listener { socket, policy: policy.clone() }).collect();
+        Merino::serving(listeners, users, options)
+    }
+
+    fn serving(listeners: Vec<Listener>, users: Vec<User>, options: Options) -> Result<Self, Box<dyn Error>> {
+        let listen_addrs = listeners.iter().map(|listener| listener.socket.local_addr()).collect::<io::Result<_>>()?;
+        let mut state = ServerState::new(listen_addrs);
+        state.auth_lockout = options.auth_failure_threshold
+            .map(|threshold| Arc::new(AuthLockout::new(threshold, options.auth_lockout_duration)));
+        if options.upstream.is_some() && options.upstream_pool_size > 0 {
+            state.upstream_pool = Some(Arc::new(UpstreamPool::new(options.upstream_pool_size, options.upstream_pool_idle)));
+        }
+
+        Ok(Merino {
+            listeners,
+            credentials: Arc::new(UserStore::new(users)),
+            options: Arc::new(options),
+            state: Arc::new(state)
+        })
+    }
+
+    /// Authenticate users against `store` instead of the users given to `new`
+    pub fn set_credential_store<C: CredentialStore + 'static>(&mut self, store: C) {
+        self.credentials = Arc::new(store);
+    }
+
+    /// Reopen `Options::access_log` from its file, for external log rotation
+    ///
+    /// Does nothing without an access log. To reopen it while `serve` runs,
+    /// keep a clone of the `AccessLog` and call `AccessLog::reopen` on it.
+    pub fn reopen_access_log(&self) -> io::Result<()> {
+        match &self.options.access_log {
+            Some(access_log) => access_log.reopen(),
+            None => Ok(()),
+        }
+    }
+
+    /// Get a `Handle` to control the server while it's serving
+    pub fn handle(&self) -> Handle {
+        Handle { state: self.state.clone() }
+    }
+
+    /// Local address the server is listening on, the first one if there are
+    /// several
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listeners[0].socket.local_addr()
+    }
+
+    /// Local addresses of all the listeners
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.listeners.iter().map(|listener| listener.socket.local_addr()).collect()
+    }
+
+    /// Snapshot of every open connection, see `Handle::list_sessions`
+    pub fn list_sessions(&self) -> Vec<SessionInfo> {
+        self.state.list_sessions()
+    }
+
+    /// Options this instance serves with, e.g. to `check` a destination
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    pub fn serve(&mut self) -> Result<(), Box<dyn Error>> {
+        info!("Serving Connections...");
+
+        if let Some(timeout) = self.options.reap_idle {
+            spawn_reaper(Arc::downgrade(&self.state), timeout);
+        }
+
+        if let Some(pool) = &self.state.upstream_pool {
+            spawn_pool_fill(pool.clone(), self.options.clone());
+        }
+
+        // Poll for connections so the idle timeout can be checked in between,
+        // and so several listeners can be served from this thread
+        let poll = self.options.idle_shutdown.is_some() || self.listeners.len() > 1;
+        if poll {
+            for listener in &self.listeners {
+                listener.socket.set_nonblocking(true)?;
+            }
+        }
+        let poll_interval = if self.listeners.len() > 1 { MULTI_ACCEPT_POLL_INTERVAL } else { ACCEPT_POLL_INTERVAL };
+
+        loop {
+            let mut accepted = false;
+            for listener in &self.listeners {
+                if self.state.shutdown_requested.load(Ordering::SeqCst) {
+                    break;
+                }
+                match listener.socket.accept() {
+                    Ok((stream, remote)) => {
+                        accepted = true;
+                        // The connection may be `Handle::shutdown` waking us up
+                        if self.state.shutdown_requested.load(Ordering::SeqCst) {
+                            break;
+                        }
+                        // One bad connection shouldn't stop the others from being served
+                        if let Err(e) = self.dispatch(stream, remote, &listener.policy) {
+                            warn!("Dropping connection from {}: {}", remote, e);
+                        }
+                    },
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {},
+                    Err(e) => warn!("Failed to accept connection: {}", e)
+                }
+            }
 
-/// Version of socks
-const SOCKS_VERSION: u8 = 0x05;
+            if self.state.shutdown_requested.load(Ordering::SeqCst) {
+                self.drain();
+                return Ok(());
+            }
 
-const RESERVED: u8 = 0x00;
+            if poll && !accepted {
+                if let Some(timeout) = self.options.idle_shutdown {
+                    if self.state.idle_for(timeout) {
+                        info!("No active connections for {:?}, shutting down", timeout);
+                        self.state.log_summary();
+                        return Ok(());
+                    }
+                }
+                thread::sleep(poll_interval);
+            }
+        }
+    }
 
-#[derive(Clone,Debug, PartialEq, Deserialize)]
-pub struct User {
-    pub username: String,
-    password: String
-}
+    /// Close the listeners and wait for open connections to finish, killing
+    /// whatever is left after `shutdown_drain_timeout`
+    fn drain(&mut self) {
+        self.listeners.clear();
 
+        let timeout = self.options.shutdown_drain_timeout;
+        let start = Instant::now();
+        info!("Draining {} connections for up to {:?}", self.state.active_connections.load(Ordering::SeqCst), timeout);
+        while self.state.active_connections.load(Ordering::SeqCst) > 0 && start.elapsed() < timeout {
+            thread::sleep(DRAIN_POLL_INTERVAL);
+        }
 
-#[derive(Debug, Snafu)]
-/// Possible SOCKS5 Response Codes
-enum ResponseCode {
-    Success = 0x00,
-    #[snafu(display("SOCKS5 Server Failure"))]
-    Failure = 0x01,
-    #[snafu(display("SOCKS5 Rule failure"))]
-    RuleFailure = 0x02,
-    #[snafu(display("network unreachable"))]
-    NetworkUnreachable = 0x03,
-    #[snafu(display("host unreachable"))]
-    HostUnreachable = 0x04,
-    #[snafu(display("connection refused"))]
-    ConnectionRefused = 0x05,
-    #[snafu(display("TTL expired"))]
-    TtlExpired = 0x06,
-    #[snafu(display("Command not supported"))]
-    CommandNotSupported = 0x07,
-    #[snafu(display("Addr Type not supported"))]
-    AddrTypeNotSupported = 0x08
+        let remaining = self.state.active_connections.load(Ordering::SeqCst);
+        if remaining > 0 {
+            warn!("Killing {} connections still open after {:?}", remaining, timeout);
+            self.state.kill_all_sessions();
+        }
+        self.state.log_summary();
+    }
+
+    /// Serve an accepted connection on a new thread
+    fn dispatch(&self, stream: TcpStream, remote: SocketAddr, policy: &Arc<ListenerPolicy>) -> io::Result<()> {
+        // Accepted sockets may inherit the listener's nonblocking mode
+        stream.set_nonblocking(false)?;
+
+        if let Some(max) = self.options.max_open_fds {
+            let open = self.state.estimated_open_fds();
+            if open + FDS_PER_CONNECTION > max {
+                warn!("Closing connection from {}: about {} of {} file descriptors are in use", remote, open, max);
+                self.options.rejected(remote, RejectReason::Overloaded);
+                return Ok(());
+            }
+        }
+
+        // Take the slot in the same step as checking for one, so queued
+        // connections taking slots can't push the count past the cap
+        let guard = match self.options.max_connections {
+            Some(max) => ConnectionGuard::try_new(self.state.clone(), max),
+            None => Some(ConnectionGuard::new(self.state.clone())),
+        };
+        let overloaded = guard.is_none();
+        if let (true, Some(max), Some(timeout)) = (overloaded, self.options.max_connections, self.options.overload_queue_timeout) {
+            self.queue(stream, remote, policy, max, timeout);
+            return Ok(());
+        }
+        if overloaded && !self.options.overload_reply {
+            debug!("Overloaded, closing connection from {}", remote);
+            self.options.rejected(remote, RejectReason::ConnectionCap);
+            return Ok(());
+        }
+
+        // Rejected connections don't count as active
+        let conn_id = self.state.next_conn_id.fetch_add(1, Ordering::SeqCst);
+
+        // TODO Optimize this
+        let mut client = SOCKClient::new(stream, ConnContext::new(conn_id, remote), self.credentials.clone(), policy.auth_methods.clone(), self.options.clone(), self.state.clone());
+        client.policy = Some(policy.clone());
+        client.overloaded = overloaded;
+        thread::spawn(move || {
+            let _guard = guard;
+            client.run();
+        });
+
+        Ok(())
+    }
+
+    /// Hold on to a connection that came in over `max` until a slot frees up,
+    /// serving it then, or turning it away after `timeout`
+    fn queue(&self, stream: TcpStream, remote: SocketAddr, policy: &Arc<ListenerPolicy>, max: usize, timeout: Duration) {
+        debug!("Overloaded, queueing connection from {}", remote);
+        let conn_id = self.state.next_conn_id.fetch_add(1, Ordering::SeqCst);
+        let mut client = SOCKClient::new(stream, ConnContext::new(conn_id, remote), self.credentials.clone(), policy.auth_methods.clone(), self.options.clone(), self.state.clone());
+        client.policy = Some(policy.clone());
+        let state = self.state.clone();
+        let overload_reply = self.options.overload_reply;
+        let options = self.options.clone();
+
+        thread::spawn(move || {
+            let deadline = Instant::now() + timeout;
+            let guard = loop {
+                if let Some(guard) = ConnectionGuard::try_new(state.clone(), max) {
+                    break Some(guard);
+                }
+                if Instant::now() >= deadline {
+                    break None;
+                }
+                thread::sleep(QUEUE_POLL_INTERVAL);
+            };
+
+            match guard {
+                Some(_guard) => client.run(),
+                None if overload_reply => {
+                    client.overloaded = true;
+                    client.run();
+                },
+                None => {
+                    debug!("No slot freed up within {:?}, closing connection from {}", timeout, remote);
+                    options.rejected(remote, RejectReason::ConnectionCap);
+                },
+            }
+        });
+    }
 }
 
-/// DST.addr variant types
-#[derive(PartialEq)]
-enum AddrType {
-    V4 = 0x01,
-    Domain = 0x03,
-    V6 = 0x04,
+/// Top up the upstream `pool` on a new thread, unless it is being topped up
+/// already
+fn spawn_pool_fill(pool: Arc<UpstreamPool>, options: Arc<Options>) {
+    if let Some(upstream) = options.upstream {
+        pool.spawn_fill(move || options.connect_to(&[upstream]));
+    }
 }
 
-impl AddrType {
-    /// Parse Byte to Command
-    fn from(n: usize) -> Option<AddrType> {
-        match n {
-            1 => Some(AddrType::V4),
-            3 => Some(AddrType::Domain),
-            4 => Some(AddrType::V6),
-            _ => None
+/// Reap idle connections every `REAP_INTERVAL` until the server is gone
+fn spawn_reaper(state: Weak<ServerState>, timeout: Duration) {
+    thread::spawn(move || {
+        loop {
+            thread::sleep(REAP_INTERVAL.min(timeout));
+            match state.upgrade() {
+                Some(state) => state.reap_idle(timeout),
+                None => return,
+            }
+        }
+    });
+}
+
+/// Copy `reader` to `writer` until EOF, adding the bytes read to `total`
+///
+/// Once `total` goes over `limit` only the bytes up to the limit are written
+/// and the copy stops. With a read timeout set on `reader`, the copy also
+/// stops within a timeout of `status` being cancelled, or once nothing has
+/// been read for `idle_timeout`. Every read marks `status` active, and
+/// every write is added to `relayed` as it happens.
+fn copy_counted<R: Read, W: Write>(reader: &mut R, writer: &mut W, total: &AtomicU64, limit: Option<u64>, status: &SessionStatus, idle_timeout: Option<Duration>, relayed: &AtomicU64) -> io::Result<u64> {
+    let mut buf = [0u8; 8 * 1024];
+    let mut copied = 0;
+    let mut last_read = Instant::now();
+
+    loop {
+        if status.is_cancelled() {
+            return Ok(copied);
+        }
+
+        let n = match reader.read(&mut buf) {
+            Ok(0) => return Ok(copied),
+            Ok(n) => n,
+            // Read timeouts surface as `WouldBlock` on unix and `TimedOut` on windows
+            Err(ref e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                if idle_timeout.is_some_and(|timeout| last_read.elapsed() >= timeout) {
+                    debug!("Nothing read for {:?}, closing this direction", last_read.elapsed());
+                    return Ok(copied);
+                }
+                continue;
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        status.touch();
+        last_read = Instant::now();
+
+        let before = total.fetch_add(n as u64, Ordering::SeqCst);
+        let allowed = match limit {
+            Some(limit) => limit.saturating_sub(before).min(n as u64) as usize,
+            None => n,
+        };
+
+        // Count each write as it lands, so a failed copy still says how much got through
+        let mut written = 0;
+        while written < allowed {
+            match writer.write(&buf[written..allowed]) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write relayed data")),
+                Ok(n) => {
+                    written += n;
+                    copied += n as u64;
+                    relayed.fetch_add(n as u64, Ordering::Relaxed);
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        if allowed < n {
+            return Ok(copied);
         }
     }
+}
 
-    // /// Return the size of the AddrType
-    // fn size(&self) -> u8 {
-    //     match self {
-    //         AddrType::V4 => 4,
-    //         AddrType::Domain => 1,
-    //         AddrType::V6 => 16
-    //     }
-    // }
+/// The reply code to send a client whose request failed with `error`
+fn error_code(error: &(dyn Error + 'static)) -> ResponseCode {
+    if let Some(code) = error.downcast_ref::<ResponseCode>() {
+        *code
+    }
+    else if let Some(error) = error.downcast_ref::<io::Error>() {
+        ResponseCode::from(error)
+    }
+    else if let Some(error) = error.downcast_ref::<ProtocolError>() {
+        error.response_code()
+    }
+    else {
+        ResponseCode::Failure
+    }
 }
 
-/// SOCK5 CMD Type
-#[derive(Debug)]
-enum SockCommand {
-    Connect = 0x01,
-    Bind = 0x02,
-    UdpAssosiate = 0x3
+/// Whether `error` means the client disconnected, which is routine during the
+/// handshake and leaves no one to send a reply to
+fn is_disconnect(error: &(dyn Error + 'static)) -> bool {
+    let error = match error.downcast_ref::<ProtocolError>() {
+        Some(ProtocolError::Io { source }) => source,
+        _ => match error.downcast_ref::<io::Error>() {
+            Some(error) => error,
+            None => return false,
+        }
+    };
+
+    matches!(error.kind(),
+        io::ErrorKind::UnexpectedEof
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::BrokenPipe)
 }
 
-impl SockCommand {
-    /// Parse Byte to Command
-    fn from(n: usize) -> Option<SockCommand> {
-        match n {
-            1 => Some(SockCommand::Connect),
-            2 => Some(SockCommand::Bind),
-            3 => Some(SockCommand::UdpAssosiate),
-            _ => None
+/// Accept a single connection on `listener`, giving up after `timeout`
+fn accept_timeout(listener: &TcpListener, timeout: Duration) -> io::Result<(TcpStream, SocketAddr)> {
+    let start = Instant::now();
+    listener.set_nonblocking(true)?;
+
+    loop {
+        match listener.accept() {
+            Ok((stream, addr)) => {
+                stream.set_nonblocking(false)?;
+                return Ok((stream, addr));
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if start.elapsed() >= timeout {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "timed out waiting for incoming connection"));
+                }
+                thread::sleep(ACCEPT_POLL_INTERVAL);
+            },
+            Err(e) => return Err(e)
         }
     }
 }
 
+/// Whether `peer` may connect to a BIND listener opened for `requested`
+///
+/// An unspecified address allows any peer.
+fn bind_peer_allowed(requested: &Address, peer: IpAddr) -> io::Result<bool> {
+    match requested {
+        Address::Ipv4(ip, _) if ip.is_unspecified() => Ok(true),
+        Address::Ipv6(ip, _) if ip.is_unspecified() => Ok(true),
+        _ => Ok(requested.to_socket_addrs()?.iter().any(|addr| addr.ip() == peer))
+    }
+}
 
-/// Client Authentication Methods
-pub enum AuthMethods {
-    /// No Authentication
-    NoAuth = 0x00,
-    // GssApi = 0x01,
-    /// Authenticate with a username / password
-    UserPass = 0x02,
-    /// Cannot authenticate
-    NoMethods = 0xFF
+/// How long the upstream proxy may stall during a handshake when
+/// `Options::connect_timeout` isn't set
+const UPSTREAM_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Listen backlog used when `Options::listen_backlog` isn't set, matching
+/// `TcpListener::bind`
+const DEFAULT_BACKLOG: i32 = 128;
+
+/// Relay one direction of a tunnel with `copy_counted`, then shut it down,
+/// returning the bytes relayed even if the copy ended with an error
+///
+/// If the copy stopped because the byte limit was reached or the session was
+/// cancelled, the whole tunnel is shut down so the other direction stops too.
+fn relay_half<R: Read + TunnelEnd, W: Write + TunnelEnd>(mut reader: R, mut writer: W, total: &AtomicU64, limit: Option<u64>, status: &SessionStatus, read_timeout: Option<Duration>, relayed: &AtomicU64) -> u64 {
+    let before = relayed.load(Ordering::Relaxed);
+    let bytes = match copy_counted(&mut reader, &mut writer, total, limit, status, read_timeout, relayed) {
+        Ok(bytes) => bytes,
+        // Resets and timeouts are how plenty of tunnels end, keep what made it
+        Err(e) => {
+            debug!("Relay stopped: {}", e);
+            relayed.load(Ordering::Relaxed) - before
+        },
+    };
+    if status.is_cancelled() {
+        debug!("Session cancelled, closing tunnel");
+        reader.close(Shutdown::Both).unwrap_or(());
+        writer.close(Shutdown::Both).unwrap_or(());
+    }
+    else if limit.is_some_and(|limit| total.load(Ordering::SeqCst) > limit) {
+        debug!("Session byte limit reached, closing tunnel");
+        reader.close(Shutdown::Both).unwrap_or(());
+        writer.close(Shutdown::Both).unwrap_or(());
+    }
+    else {
+        reader.close(Shutdown::Read).unwrap_or(());
+        writer.close(Shutdown::Write).unwrap_or(());
+    }
+    bytes
 }
 
-pub struct Merino {
-    listener: TcpListener,
-    users: Vec<User>,
-    auth_methods: Vec<u8>
+/// Whether a failed lookup or connection might succeed if tried again
+fn is_transient(error: &io::Error) -> bool {
+    !matches!(error.kind(),
+        io::ErrorKind::InvalidInput
+        | io::ErrorKind::InvalidData
+        | io::ErrorKind::PermissionDenied
+        | io::ErrorKind::AddrNotAvailable
+        | io::ErrorKind::Unsupported)
 }
 
-impl Merino {
-    /// Create a new Merino instance
-    pub fn new(port: u16,  ip: String, auth_methods: Vec<u8>, users: Vec<User>) -> Result<Self, Box<dyn Error>> {
-        info!("Listening on {}:{}", ip, port);
-        Ok(Merino {
-            listener: TcpListener::bind(format!("{}:{}", ip, port))?,
-            auth_methods,
-            users
-        })
+/// Bind a `TcpListener` to `addr`, applying the listener settings in `options`
+fn bind(addr: &str, options: &Options) -> Result<TcpListener, Box<dyn Error>> {
+    if options.listen_backlog.is_none() && !options.reuse_addr && !options.reuse_port {
+        return Ok(TcpListener::bind(addr).context(Bind { addr })?);
     }
+    let backlog = options.listen_backlog.unwrap_or(DEFAULT_BACKLOG);
 
-    pub fn serve(&mut self) -> Result<(), Box<dyn Error>> {
-        info!("Serving Connections...");
-        loop {
-            if let Ok((stream, _remote)) = self.listener.accept() {
-                    // TODO Optimize this
-                    let mut client = SOCKClient::new(stream, self.users.clone(), self.auth_methods.clone());
-                    thread::spawn(move || {
-                        match client.init() {
-                            Ok(_) => {},
-                            Err(error) => {
-                                error!("Error! {}", error);
-                                let error_text = format!("{}", error);
-                                
-
-                                let response: ResponseCode;
-
-                                if error_text.contains("Host") {
-                                    response = ResponseCode::HostUnreachable;
-                                }
-                                else if error_text.contains("Network"){
-                                    response = ResponseCode::NetworkUnreachable;
-                                }
-                                else if error_text.contains("ttl") {
-                                    response = ResponseCode::TtlExpired
-                                }
-                                else {
-                                    response = ResponseCode::Failure
-                                }
-
-                                if client.error(response).is_err() {
-                                    warn!("Failed to send error code");
-                                };
-                                if client.shutdown().is_err() {
-                                    warn!("Failed to shutdown TcpStream");
-                                };
-                            } 
-                        };
-                    });
+    let mut last_err = None;
+    for addr in addr.to_socket_addrs().context(Bind { addr })? {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
 
-            }
+        // Match `TcpListener::bind`, which sets SO_REUSEADDR on unix
+        if cfg!(unix) || options.reuse_addr {
+            socket.set_reuse_address(true)?;
+        }
+
+        if options.reuse_port {
+            #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+            socket.set_reuse_port(true)?;
+
+            #[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos")))))]
+            return Err("SO_REUSEPORT is not supported on this platform".into());
+        }
+
+        match socket.bind(&addr.into()).and_then(|_| socket.listen(backlog)) {
+            Ok(()) => return Ok(socket.into()),
+            Err(e) => last_err = Some((addr.to_string(), e)),
         }
     }
+
+    let (addr, source) = last_err.unwrap_or_else(|| (addr.to_string(), io::Error::new(io::ErrorKind::InvalidInput, "could not resolve to any addresses")));
+    Err(Box::new(ListenError::Bind { addr, source }))
 }
 
-struct SOCKClient {
-    stream: TcpStream,
+struct SOCKClient<T: ClientStream> {
+    stream: T,
+    ctx: ConnContext,
     auth_nmethods: u8,
     auth_methods: Vec<u8>,
-    authed_users: Vec<User>,
-    socks_version: u8
+    credentials: Arc<dyn CredentialStore>,
+    socks_version: u8,
+    /// Reply `Failure` to the request instead of serving it
+    overloaded: bool,
+    options: Arc<Options>,
+    state: Arc<ServerState>,
+    /// When the connection was accepted
+    started: SystemTime,
+    /// What happened so far, for the session hooks
+    session: Session,
+    /// Cancellation and last activity, see `Handle::kill_connection`
+    status: Arc<SessionStatus>,
+    /// Counts the connection towards its user's limit while held
+    user_slot: Option<UserSlot>,
+    /// Data the client sent along with its request, to pass on to the target
+    /// before relaying
+    early_data: Vec<u8>,
+    /// Whether the built-in `CompressedTunnelHandler` let the client in, so
+    /// its end of the tunnel is compressed
+    compressed_client: bool,
+    /// Bytes the client may still send before its request is complete
+    handshake_remaining: u64,
+    /// Policy of the listener the connection came in on
+    policy: Option<Arc<ListenerPolicy>>,
+}
+
+/// Progress of a connection's request, reported as a `SessionSummary`
+#[derive(Default)]
+struct Session {
+    request: Option<(SockCommand, Address)>,
+    reply: Option<ResponseCode>,
+    bytes_up: u64,
+    bytes_down: u64,
 }
 
-impl SOCKClient {
+impl<T: ClientStream> SOCKClient<T> {
     /// Create a new SOCKClient
-    pub fn new(stream: TcpStream, authed_users: Vec<User>, auth_methods: Vec<u8>) -> Self {
+    fn new(stream: T, ctx: ConnContext, credentials: Arc<dyn CredentialStore>, auth_methods: Vec<u8>, options: Arc<Options>, state: Arc<ServerState>) -> Self {
+        let handshake_remaining = options.max_handshake_bytes;
         SOCKClient {
             stream,
             auth_nmethods: 0,
             socks_version: 0,
-            authed_users,
-            auth_methods
+            overloaded: false,
+            credentials,
+            auth_methods,
+            options,
+            state,
+            status: Arc::new(SessionStatus::new(ctx.peer_addr)),
+            ctx,
+            started: SystemTime::now(),
+            session: Session::default(),
+            user_slot: None,
+            early_data: Vec::new(),
+            compressed_client: false,
+            handshake_remaining,
+            policy: None,
+        }
+    }
+
+    /// Serve the connection to completion, replying to any error, then count
+    /// the session's bytes and report it to the session hooks
+    fn run(&mut self) {
+        self.state.open_session(self.ctx.conn_id, self.status.clone());
+        match self.stream.try_clone() {
+            Ok(stream) => self.state.track_stream(self.ctx.conn_id, stream),
+            Err(e) => warn!("Can't track connection {} to kill it: {}", self.ctx.conn_id, e),
+        }
+
+        let result = self.init();
+        self.state.close_session(self.ctx.conn_id);
+
+        if let Err(error) = result {
+            // Past the handshake, errors come from the target and the client
+            // still needs a reply
+            if self.session.request.is_none() && is_disconnect(error.as_ref()) {
+                debug!("Client {} went away during the handshake: {}", self.ctx.peer_addr, error);
+                self.shutdown().unwrap_or(());
+                return;
+            }
+
+            error!("Error! {}", error);
+            let response = error_code(error.as_ref());
+
+            if self.error(response).is_err() {
+                warn!("Failed to send error code");
+            };
+            if self.shutdown().is_err() {
+                warn!("Failed to shutdown TcpStream");
+            };
         }
+
+        let (command, address) = match self.session.request.take() {
+            Some(request) => request,
+            None => return,
+        };
+
+        if command != SockCommand::UdpAssosiate {
+            let bucket = if self.options.tracked_ports.contains(&address.port()) { PortBucket::Port(address.port()) } else { PortBucket::Other };
+            self.state.add_port_bytes(bucket, self.session.bytes_up, self.session.bytes_down);
+        }
+
+        if !self.options.session_hooks.is_empty() || self.options.access_log.is_some() {
+            let summary = SessionSummary {
+                ctx: self.ctx.clone(),
+                command,
+                address,
+                reply: self.session.reply,
+                bytes_up: self.session.bytes_up,
+                bytes_down: self.session.bytes_down,
+                started: self.started,
+                duration: self.started.elapsed().unwrap_or_default(),
+            };
+            if let Some(access_log) = &self.options.access_log {
+                if let Err(e) = access_log.record(&summary) {
+                    warn!("Failed to write access log: {}", e);
+                }
+            }
+            for hook in &self.options.session_hooks {
+                hook(&summary);
+            }
+        }
+    }
+
+    /// The client stream, for reading the handshake within `max_handshake_bytes`
+    fn handshake(&mut self) -> HandshakeStream<'_, T> {
+        HandshakeStream { stream: &mut self.stream, remaining: &mut self.handshake_remaining, peer_addr: self.ctx.peer_addr }
     }
 
-    /// Check if username + password pair are valid
-    fn authed(&self, user: &User) -> bool {
-        self.authed_users.contains(user)
+    /// Send a reply to the request, recording its code for the session hooks
+    fn reply(&mut self, code: ResponseCode, bind_addr: SocketAddr) -> io::Result<()> {
+        self.session.reply = Some(code);
+        #[cfg(feature = "chaos")]
+        if let Some(chaos) = &self.options.chaos {
+            chaos.delay_reply();
+        }
+        self.stream.write_all(&build_reply(code, bind_addr))?;
+        self.stream.flush()
     }
 
-    /// Send an error to the client
+    /// Send an error reply to the client
     pub fn error(&mut self, r: ResponseCode) -> Result<(), Box<dyn Error>> {
-        self.stream.write_all(&[5, r as u8])?;
+        self.reply(r, SocketAddr::from(([0, 0, 0, 0], 0)))?;
         Ok(())
     }
 
@@ -203,22 +2300,48 @@ impl SOCKClient {
         Ok(())
     }
 
-    fn init(&mut self) -> Result<(), Box<dyn Error>> {
-        debug!("New connection from: {}", self.stream.peer_addr()?.ip());
+    pub fn init(&mut self) -> Result<(), Box<dyn Error>> {
+        debug!("New connection from: {}", self.ctx.peer_addr);
+
+        // Recover the real client address from the load balancer's header
+        if self.options.expect_proxy_protocol {
+            if let Some(source) = proxy_protocol::read_header(&mut self.handshake())? {
+                debug!("Connection from {} is for {}", self.ctx.peer_addr, source);
+                self.ctx.peer_addr = source;
+                self.status.details.lock().unwrap().peer_addr = source;
+            }
+        }
+
         let mut header = [0u8; 2];
         // Read a byte from the stream and determine the version being requested
-        self.stream.read_exact(&mut header)?;
+        self.handshake().read_exact(&mut header)?;
 
         self.socks_version = header[0];
         self.auth_nmethods = header[1];
 
         trace!("Version: {} Auth nmethods: {}", self.socks_version, self.auth_nmethods);
 
+        if self.options.health_check && header == HEALTH_CHECK_REQUEST[..2] {
+            let mut rest = [0u8; HEALTH_CHECK_REQUEST.len() - 2];
+            self.handshake().read_exact(&mut rest)?;
+
+            if rest == HEALTH_CHECK_REQUEST[2..] {
+                trace!("Health check from {}", self.ctx.peer_addr);
+                self.stream.write_all(HEALTH_CHECK_RESPONSE)?;
+            }
+            self.shutdown()?;
+        }
         // Handle SOCKS4 requests
-        if header[0] != SOCKS_VERSION {
+        else if header[0] != SOCKS_VERSION {
             warn!("Init: Unsupported version: SOCKS{}", self.socks_version);
             self.shutdown()?;
         }
+        // Reject clients with implausibly long method lists outright
+        else if self.auth_nmethods > self.options.max_auth_methods {
+            warn!("Client {} offered {} auth methods, more than the {} allowed", self.ctx.peer_addr, self.auth_nmethods, self.options.max_auth_methods);
+            self.shutdown()?;
+            return Err(Box::new(io::Error::new(io::ErrorKind::InvalidData, "too many auth methods offered")));
+        }
         // Valid SOCKS5
         else {
             // Authenticate w/ client
@@ -231,119 +2354,194 @@ impl SOCKClient {
     }
 
     fn auth(&mut self) -> Result<(), Box<dyn Error>> {
-        debug!("Authenticating w/ {}", self.stream.peer_addr()?.ip());
+        debug!("Authenticating w/ {}", self.ctx.peer_addr);
         // Get valid auth methods
-        let methods = self.get_avalible_methods()?;
+        let (offered, methods) = self.get_avalible_methods()?;
         trace!("methods: {:?}", methods);
 
-        let mut response = [0u8; 2];
-
-        // Set the version in the response
-        response[0] = SOCKS_VERSION;
-        
-        if methods.contains(&(AuthMethods::UserPass as u8)) {
-            // Set the default auth method (NO AUTH)
-            response[1] = AuthMethods::UserPass as u8;
-
-            debug!("Sending USER/PASS packet");
-            self.stream.write_all(&response)?;
-
-            let mut header = [0u8;2];
-
-            // Read a byte from the stream and determine the version being requested
-            self.stream.read_exact(&mut header)?;
-
-            // debug!("Auth Header: [{}, {}]", header[0], header[1]);
-
-            // Username parsing
-            let ulen = header[1];
-
-            let mut username = Vec::with_capacity(ulen as usize);
+        let handler = match self.select_auth_handler(&methods) {
+            Some(handler) => handler,
+            None => {
+                if self.auth_nmethods == 0 {
+                    self.state.empty_method_offers.fetch_add(1, Ordering::SeqCst);
+                    warn!("Client {} offered no auth methods", self.ctx.peer_addr);
+                }
+                else {
+                    warn!("Client {} has no suitable auth methods, offered {:?}", self.ctx.peer_addr, offered);
+                }
+                self.stream.write_all(&[SOCKS_VERSION, AuthMethods::NoMethods as u8])?;
+                self.shutdown()?;
+                return Err(Box::new(ResponseCode::Failure));
+            }
+        };
 
-            // For some reason the vector needs to actually be full
-            for _ in 0..ulen {
-                username.push(0);
+        let method = handler.method();
+        debug!("Selected auth method {:#04x}", method);
+        self.stream.write_all(&[SOCKS_VERSION, method])?;
+
+        let mut stream = HandshakeStream { stream: &mut self.stream, remaining: &mut self.handshake_remaining, peer_addr: self.ctx.peer_addr };
+        match handler.authenticate(&self.ctx, &mut stream)? {
+            AuthOutcome::Granted { username } => {
+                match &username {
+                    Some(username) => info!("Authenticated {} with method {:#04x} as {}", self.ctx.peer_addr, method, username),
+                    None => info!("Authenticated {} with method {:#04x}", self.ctx.peer_addr, method),
+                }
+                self.ctx.auth_method = Some(method);
+                // Custom handlers are picked first and may claim the method too
+                self.compressed_client = cfg!(feature = "compression")
+                    && method == AuthMethods::CompressedTunnel as u8
+                    && !self.options.auth_handlers.iter().any(|handler| handler.method() == method);
+                self.status.details.lock().unwrap().username = username.clone();
+                self.ctx.username = username;
+            },
+            AuthOutcome::Denied => {
+                // Shutdown
+                self.shutdown()?;
             }
+        }
 
-            self.stream.read_exact(&mut username)?;
+        Ok(())
+    }
 
-            // Password Parsing
-            let mut plen = [0u8; 1];
-            self.stream.read_exact(&mut plen)?;
-            
+    /// Pick the handler for the most preferred of the client's `methods`
+    ///
+    /// Custom handlers come first, then `UserPass` and `NoAuth`.
+    fn select_auth_handler(&self, methods: &[u8]) -> Option<Arc<dyn AuthHandler>> {
+        let mut userpass = UserPassHandler::new(self.credentials.clone());
+        if let Some(normalizer) = &self.options.username_normalizer {
+            userpass = userpass.with_normalizer(normalizer.clone());
+        }
+        if let Some(lockout) = &self.state.auth_lockout {
+            userpass = userpass.with_lockout(lockout.clone());
+        }
+        userpass = userpass.with_failure_delay(self.options.auth_failure_delay);
+
+        #[allow(unused_mut)]
+        let mut builtin: Vec<Arc<dyn AuthHandler>> = vec![
+            Arc::new(userpass),
+            Arc::new(NoAuthHandler),
+        ];
+        #[cfg(feature = "compression")]
+        builtin.push(Arc::new(CompressedTunnelHandler));
+
+        self.options.auth_handlers.iter().cloned()
+            .chain(builtin.into_iter().filter(|handler| self.auth_methods.contains(&handler.method())))
+            .find(|handler| methods.contains(&handler.method()))
+    }
 
-            let mut password = Vec::with_capacity(plen[0] as usize);
+    /// Handles a client
+    pub fn handle_client(&mut self) -> Result<(), Box<dyn Error>> {
+        debug!("Handling requests for {} (auth: {:?})", self.ctx.peer_addr, self.ctx.auth_method);
+        // Read request
+        // loop {
+            // Parse Request
+            let (req, early_data) = SOCKSReq::from_stream(&mut self.handshake())?;
+            self.early_data = early_data;
+            self.session.request = Some((req.command, req.address.clone()));
+            self.status.details.lock().unwrap().destination = Some(req.address.clone());
 
-            // For some reason the vector needs to actually be full
-            for _ in 0..plen[0] {
-                password.push(0);
+            // Log Request
+            match self.options.log_format {
+                LogFormat::Text => {
+                    info!("New Request: Source: {}, User: {}, Command: {:?} Addr: {}", 
+                          self.ctx.peer_addr,
+                          self.ctx.username.as_deref().unwrap_or("anonymous"),
+                          req.command, 
+                          req.address
+                    );
+                },
+                LogFormat::Json => {
+                    let entry = RequestLog {
+                        timestamp: SystemTime::now().duration_since(UNIX_EPOCH).map(|t| t.as_millis()).unwrap_or(0),
+                        conn_id: self.ctx.conn_id,
+                        source_ip: self.ctx.peer_addr.ip(),
+                        source_port: self.ctx.peer_addr.port(),
+                        username: self.ctx.username.clone(),
+                        command: format!("{:?}", req.command),
+                        dest_host: req.address.host_only(),
+                        dest_port: req.address.port(),
+                        addr_type: req.address.type_name(),
+                    };
+                    info!("{}", serde_json::to_string(&entry)?);
+                }
             }
 
-            self.stream.read_exact(&mut password)?;
 
-            let username_str = String::from_utf8(username)?;
-            let password_str = String::from_utf8(password)?;
-
-           let user = User { 
-                username: username_str,
-                password: password_str 
-            };
+            if self.options.sensitive_ports.contains(&req.address.port()) {
+                warn!("Request to sensitive port {} from {} (user: {}): {:?} {}",
+                      req.address.port(),
+                      self.ctx.peer_addr,
+                      self.ctx.username.as_deref().unwrap_or("anonymous"),
+                      req.command,
+                      req.address
+                );
+            }
 
-            // Authenticate passwords
-            if self.authed(&user) {
-                debug!("Access Granted. User: {}", user.username);
-                let response = [1, ResponseCode::Success as u8];
-                self.stream.write_all(&response)?;
-            } 
-            else {
-                debug!("Access Denied. User: {}", user.username);
-                let response = [1, ResponseCode::Failure as u8];
-                self.stream.write_all(&response)?;
-
-                // Shutdown 
+            // Turn everyone away without contacting the target
+            if let Some(code) = self.state.maintenance() {
+                debug!("Maintenance mode, replying {:?}", code);
+                self.reply(code, SocketAddr::from(([0, 0, 0, 0], 0)))?;
                 self.shutdown()?;
+                return Ok(());
+            }
 
+            if self.overloaded {
+                debug!("Overloaded, replying {:?}", ResponseCode::Failure);
+                self.options.rejected(self.ctx.peer_addr, RejectReason::ConnectionCap);
+                self.reply(ResponseCode::Failure, SocketAddr::from(([0, 0, 0, 0], 0)))?;
+                self.shutdown()?;
+                return Ok(());
             }
 
-            Ok(())
-        }
-        else if methods.contains(&(AuthMethods::NoAuth as u8)) {
-            // set the default auth method (no auth)
-            response[1] = AuthMethods::NoAuth as u8;
-            debug!("Sending NOAUTH packet");
-            self.stream.write_all(&response)?;
-            Ok(())
-        }
-        else {
-            warn!("Client has no suitable Auth methods!");
-            response[1] = AuthMethods::NoMethods as u8;
-            self.stream.write_all(&response)?;
-            self.shutdown()?;
-            Err(Box::new(ResponseCode::Failure))
-        }
+            if let Some(username) = self.ctx.username.clone() {
+                if let Some(limit) = self.options.user_connection_limit(&username) {
+                    match UserSlot::acquire(self.state.clone(), username.clone(), limit) {
+                        Some(slot) => self.user_slot = Some(slot),
+                        None => {
+                            warn!("User {} is at their limit of {} connections", username, limit);
+                            self.options.rejected(self.ctx.peer_addr, RejectReason::RateLimited);
+                            self.reply(ResponseCode::RuleFailure, SocketAddr::from(([0, 0, 0, 0], 0)))?;
+                            self.shutdown()?;
+                            return Ok(());
+                        }
+                    }
+                }
+            }
 
-    }
+            if self.options.strict_reserved && req.reserved != RESERVED {
+                warn!("Rejecting request with reserved byte {:#04x}", req.reserved);
+                self.reply(ResponseCode::Failure, SocketAddr::from(([0, 0, 0, 0], 0)))?;
+                self.shutdown()?;
+                return Ok(());
+            }
 
-    /// Handles a client
-    pub fn handle_client(&mut self) -> Result<(), Box<dyn Error>> {
-        debug!("Handling requests for {}", self.stream.peer_addr()?.ip());
-        // Read request
-        // loop {
-            // Parse Request
-            let req = SOCKSReq::from_stream(&mut self.stream)?;
-            
-            if req.addr_type == AddrType::V6 {
+            if !self.options.allowed_addr_types.contains(&req.address.addr_type()) {
+                debug!("Address type {:?} is not allowed, rejecting {}", req.address.addr_type(), req.address);
+                self.reply(ResponseCode::AddrTypeNotSupported, SocketAddr::from(([0, 0, 0, 0], 0)))?;
+                self.shutdown()?;
+                return Ok(());
             }
 
-            // Log Request
-            let displayed_addr = pretty_print_addr(&req.addr_type, &req.addr);
-            info!("New Request: Source: {}, Command: {:?} Addr: {}, Port: {}", 
-                  self.stream.peer_addr()?.ip(),
-                  req.command, 
-                  displayed_addr,
-                  req.port
-            );
+            if self.options.literal_only {
+                if let Address::Domain(..) = req.address {
+                    debug!("Domain names are disabled, rejecting {}", req.address);
+                    self.reply(ResponseCode::AddrTypeNotSupported, SocketAddr::from(([0, 0, 0, 0], 0)))?;
+                    self.shutdown()?;
+                    return Ok(());
+                }
+            }
 
+            let literal_ip = match req.address {
+                Address::Ipv4(ip, _) => Some(IpAddr::V4(ip)),
+                Address::Ipv6(ip, _) => Some(IpAddr::V6(ip)),
+                Address::Domain(..) => None,
+            };
+            if literal_ip.is_some_and(|ip| !self.options.allows_family(ip)) {
+                debug!("Address family is disabled, rejecting {}", req.address);
+                self.reply(ResponseCode::AddrTypeNotSupported, SocketAddr::from(([0, 0, 0, 0], 0)))?;
+                self.shutdown()?;
+                return Ok(());
+            }
 
             // Respond
             match req.command {
@@ -351,41 +2549,205 @@ impl SOCKClient {
                 SockCommand::Connect => {
                     debug!("Handling CONNECT Command");
 
-                    let sock_addr = addr_to_socket(&req.addr_type, &req.addr, req.port)?;
-
-                    trace!("Connecting to: {:?}", sock_addr);
-
-                    let target = TcpStream::connect(&sock_addr[..])?;
+                    // Port 0 is fine for UDP ASSOCIATE, but nothing listens on it
+                    if req.address.port() == 0 {
+                        warn!("Rejecting CONNECT to {} from {}: port 0 can't be connected to", req.address, self.ctx.peer_addr);
+                        self.reply(ResponseCode::Failure, SocketAddr::from(([0, 0, 0, 0], 0)))?;
+                        self.shutdown()?;
+                        return Ok(());
+                    }
+
+                    let host = match req.address {
+                        Address::Domain(..) => Some(req.address.host_only()),
+                        _ => None,
+                    };
+                    let rules = match self.policy.as_ref().and_then(|policy| policy.rules.as_ref()) {
+                        Some(rules) => rules,
+                        None => self.options.rules_for(&self.ctx),
+                    };
+                    // Pattern rules decide by name, so don't look up denied names
+                    if host.as_ref().and_then(|host| rules.policy_by_name(host)) == Some(Policy::Deny) {
+                        warn!("Rules deny CONNECT to {}", req.address);
+                        self.options.rejected(self.ctx.peer_addr, RejectReason::Denied);
+                        self.reply(ResponseCode::RuleFailure, SocketAddr::from(([0, 0, 0, 0], 0)))?;
+                        self.shutdown()?;
+                        return Ok(());
+                    }
+
+                    let mut sock_addr = self.options.retry(|| self.options.resolve(&req.address))?;
+                    self.options.retain_families(&mut sock_addr, &req.address)?;
+                    self.options.order_families(&mut sock_addr);
+
+                    if let (Some(hook), Some(host)) = (&self.options.resolve_hook, &host) {
+                        if !hook(&self.ctx, host, &sock_addr) {
+                            warn!("Resolve hook vetoed CONNECT to {} ({:?})", req.address, sock_addr);
+                            self.options.rejected(self.ctx.peer_addr, RejectReason::Denied);
+                            self.reply(ResponseCode::RuleFailure, SocketAddr::from(([0, 0, 0, 0], 0)))?;
+                            self.shutdown()?;
+                            return Ok(());
+                        }
+                    }
+                    sock_addr.retain(|addr| rules.allows(host.as_deref(), addr.ip()));
+                    if sock_addr.is_empty() {
+                        warn!("Rules deny CONNECT to {}", req.address);
+                        self.options.rejected(self.ctx.peer_addr, RejectReason::Denied);
+                        self.reply(ResponseCode::RuleFailure, SocketAddr::from(([0, 0, 0, 0], 0)))?;
+                        self.shutdown()?;
+                        return Ok(());
+                    }
+
+                    let local_ip = self.stream.local_addr()?.ip();
+                    if self.options.reject_self_connect && sock_addr.iter().any(|addr| self.state.is_listen_addr(*addr, local_ip)) {
+                        warn!("Rejecting CONNECT to {}, which is this proxy", req.address);
+                        self.reply(ResponseCode::RuleFailure, SocketAddr::from(([0, 0, 0, 0], 0)))?;
+                        self.shutdown()?;
+                        return Ok(());
+                    }
+
+                    let mut compress_target = false;
+                    let target = match self.options.upstream {
+                        Some(upstream) => {
+                            let spare = self.state.upstream_pool.as_ref().and_then(|pool| pool.take());
+                            if let Some(pool) = &self.state.upstream_pool {
+                                spawn_pool_fill(pool.clone(), self.options.clone());
+                            }
+                            let mut target = match spare {
+                                Some(target) => target,
+                                None => self.options.retry(|| self.options.connect_to(&[upstream]))?,
+                            };
+                            let compress = self.options.upstream_compression && cfg!(feature = "compression");
+                            let timeout = self.options.connect_timeout.unwrap_or(UPSTREAM_HANDSHAKE_TIMEOUT);
+                            compress_target = upstream::connect(&mut target, &req.address, compress, timeout)?;
+                            target
+                        },
+                        None => self.options.retry(|| self.options.connect_to(&sock_addr))?,
+                    };
+                    self.state.track_stream(self.ctx.conn_id, target.try_clone()?);
 
                     trace!("Connected!");
 
-                    self.stream.write_all(&[SOCKS_VERSION, ResponseCode::Success as u8, RESERVED, 1, 127, 0, 0, 1, 0, 0]).unwrap();
+                    if let Some(proxy_protocol) = &self.options.proxy_protocol {
+                        if proxy_protocol.destinations.contains(&target.peer_addr()?) {
+                            let header = proxy_protocol::encode(proxy_protocol.version, self.ctx.peer_addr, self.stream.local_addr()?);
+                            (&target).write_all(&header)?;
+                        }
+                    }
+
+                    if let Some(size) = self.options.socket_buffer_size {
+                        connect::set_buffer_size(&target, size)?;
+                        self.stream.set_buffer_size(size)?;
+                    }
+
+                    if let Some(dscp) = self.options.dscp {
+                        if let Err(e) = connect::set_dscp(&target, dscp) {
+                            warn!("Failed to set DSCP on connection to {}: {}", req.address, e);
+                        }
+                        if self.options.dscp_client {
+                            if let Err(e) = self.stream.set_dscp(dscp) {
+                                warn!("Failed to set DSCP on connection from {}: {}", self.ctx.peer_addr, e);
+                            }
+                        }
+                    }
+
+                    if let Some(ttl) = self.options.outbound_ttl {
+                        connect::set_ttl(&target, ttl)?;
+                    }
+
+                    if let (Some(probe), false) = (&self.options.connect_probe, compress_target) {
+                        let peeked = connect::peek(&target, self.options.connect_probe_bytes, self.options.connect_probe_timeout)?;
+                        if !probe(&self.ctx, &req.address, &peeked) {
+                            warn!("Probe rejected CONNECT to {} after {} bytes", req.address, peeked.len());
+                            target.shutdown(Shutdown::Both).unwrap_or(());
+                            self.reply(ResponseCode::RuleFailure, SocketAddr::from(([0, 0, 0, 0], 0)))?;
+                            self.shutdown()?;
+                            return Ok(());
+                        }
+                    }
+
+                    let mut reply = build_reply(ResponseCode::Success, self.options.advertised(target.local_addr()?));
+                    if let Some(hook) = &self.options.connect_reply_hook {
+                        hook(&self.ctx, &mut reply);
+                    }
+                    // Send the reply right away instead of letting Nagle hold it
+                    // back to coalesce with the first relayed data
+                    if let Err(e) = self.stream.set_nodelay(true) {
+                        debug!("Failed to set TCP_NODELAY on connection from {}: {}", self.ctx.peer_addr, e);
+                    }
+                    #[cfg(feature = "chaos")]
+                    if let Some(chaos) = &self.options.chaos {
+                        chaos.delay_reply();
+                    }
+                    if let Err(e) = self.stream.write_all(&reply).and_then(|_| self.stream.flush()) {
+                        // Don't leave the target waiting on a client that's gone
+                        debug!("Client {} went away before the CONNECT reply: {}", self.ctx.peer_addr, e);
+                        target.shutdown(Shutdown::Both).unwrap_or(());
+                        return Ok(());
+                    }
+                    self.session.reply = Some(ResponseCode::Success);
+
+                    self.relay(target, compress_target)?;
+                },
+                SockCommand::Bind => {
+                    debug!("Handling BIND Command");
 
-                    // Copy it all
-                    let mut outbound_in = target.try_clone()?;
-                    let mut outbound_out = target.try_clone()?;
-                    let mut inbound_in = self.stream.try_clone()?;
-                    let mut inbound_out = self.stream.try_clone()?;
+                    // Listen on the address the client reached us on
+                    let listener = TcpListener::bind(SocketAddr::new(self.stream.local_addr()?.ip(), 0))?;
 
+                    trace!("BIND listening on {}", listener.local_addr()?);
+                    self.reply(ResponseCode::Success, self.options.advertised(listener.local_addr()?))?;
 
-                    // Download Thread
-                    thread::spawn(move || {
-                        copy(&mut outbound_in, &mut inbound_out).is_ok();
-                        outbound_in.shutdown(Shutdown::Read).unwrap_or(());
-                        inbound_out.shutdown(Shutdown::Write).unwrap_or(());
-                    });
+                    let (target, remote) = accept_timeout(&listener, BIND_TIMEOUT)?;
+                    self.state.track_stream(self.ctx.conn_id, target.try_clone()?);
 
-                    // Upload Thread
-                    thread::spawn(move || {
-                        copy(&mut inbound_in, &mut outbound_out).is_ok();
-                        inbound_in.shutdown(Shutdown::Read).unwrap_or(());
-                        outbound_out.shutdown(Shutdown::Write).unwrap_or(());
-                    });
+                    // Only the host named in the request may connect
+                    if !bind_peer_allowed(&req.address, remote.ip())? {
+                        warn!("BIND: Rejecting connection from {}, expected {}", remote, req.address);
+                        self.reply(ResponseCode::RuleFailure, remote)?;
+                        self.shutdown()?;
+                        return Ok(());
+                    }
 
+                    trace!("BIND accepted connection from {}", remote);
+                    self.reply(ResponseCode::Success, remote)?;
 
+                    self.relay(target, false)?;
+                },
+                SockCommand::UdpAssosiate => {
+                    debug!("Handling UDP ASSOCIATE Command");
+
+                    // Relay from the address the client reached us on
+                    let socket = UdpSocket::bind(SocketAddr::new(self.stream.local_addr()?.ip(), 0))?;
+                    let source = udp::ClientSource::new(self.options.udp_source_filter, &req.address, self.ctx.peer_addr.ip());
+                    let destinations = udp::Destinations {
+                        options: self.options.clone(),
+                        rules: match self.policy.as_ref().and_then(|policy| policy.rules.as_ref()) {
+                            Some(rules) => rules.clone(),
+                            None => self.options.rules_for(&self.ctx).clone(),
+                        },
+                        state: self.state.clone(),
+                        local_ip: self.stream.local_addr()?.ip(),
+                        ctx: self.ctx.clone(),
+                    };
+
+                    trace!("UDP relay bound to {}", socket.local_addr()?);
+                    self.reply(ResponseCode::Success, self.options.advertised(socket.local_addr()?))?;
+
+                    let done = Arc::new(AtomicBool::new(false));
+                    let relay = {
+                        let done = done.clone();
+                        let status = self.status.clone();
+                        thread::spawn(move || udp::relay(socket, source, &destinations, &done, &status))
+                    };
+
+                    // The association lasts until the control connection closes
+                    copy(&mut self.stream, &mut io::sink()).unwrap_or(0);
+                    done.store(true, Ordering::SeqCst);
+
+                    match relay.join() {
+                        Ok(result) => result?,
+                        Err(_) => warn!("UDP relay thread panicked"),
+                    }
                 },
-                SockCommand::Bind => { },
-                SockCommand::UdpAssosiate => { },
             }
 
 
@@ -397,162 +2759,175 @@ impl SOCKClient {
         Ok(())
     }
 
-    /// Return the avalible methods based on `self.auth_nmethods`
-    fn get_avalible_methods(&mut self) -> Result<Vec<u8>, Box<dyn Error>> {
-        let mut methods: Vec<u8> = Vec::with_capacity(self.auth_nmethods as usize);
-        for _ in 0..self.auth_nmethods {
-            let mut method = [0u8; 1];
-            self.stream.read_exact(&mut method)?;
-            if self.auth_methods.contains(&method[0]) {
-                methods.append(&mut method.to_vec());
-            }
+    /// Relay data between the client and `target` until both sides are done
+    /// or the session is cancelled
+    fn relay(&mut self, target: TcpStream, compress_target: bool) -> Result<(), Box<dyn Error>> {
+        // Wake up regularly to check for cancellation. Clones share the timeout.
+        target.set_read_timeout(Some(RELAY_POLL_INTERVAL))?;
+        self.stream.set_read_timeout(Some(RELAY_POLL_INTERVAL))?;
+        target.set_write_timeout(self.options.relay_write_timeout)?;
+        self.stream.set_write_timeout(self.options.relay_write_timeout)?;
+
+        let early_data = std::mem::take(&mut self.early_data);
+        // Neither end can be compressed without the compression feature
+        if self.compressed_client || compress_target {
+            #[cfg(feature = "compression")]
+            return self.relay_compressed(target, early_data, self.compressed_client);
         }
-        Ok(methods)
-    }
-}
 
-/// Convert an address and AddrType to a SocketAddr
-fn addr_to_socket(addr_type: &AddrType, addr: &[u8], port: u16) -> Result<Vec<SocketAddr>, Box<dyn Error>> {
-    match addr_type {
-        AddrType::V6 => {
-            let new_addr = (0..8).map(|x| {
-                trace!("{} and {}", x * 2, (x * 2) + 1);
-                (u16::from(addr[(x * 2)]) << 8) | u16::from(addr[(x * 2) + 1])
-            }).collect::<Vec<u16>>();
+        // Whatever was read along with the request goes first
+        if !early_data.is_empty() {
+            trace!("Passing on {} bytes sent with the request", early_data.len());
+            (&target).write_all(&early_data)?;
+            self.status.bytes_up.fetch_add(early_data.len() as u64, Ordering::Relaxed);
+        }
 
+        let (inbound_in, inbound_out) = (self.stream.try_clone()?, self.stream.try_clone()?);
+        self.relay_halves(target.try_clone()?, inbound_out, inbound_in, target, early_data.len() as u64);
+        Ok(())
+    }
 
-            Ok(vec![SocketAddr::from(
-                SocketAddrV6::new(
-                    Ipv6Addr::new(
-                        new_addr[0], new_addr[1], new_addr[2], new_addr[3], new_addr[4], new_addr[5], new_addr[6], new_addr[7]), 
-                    port, 0, 0)
-            )])
-        },
-        AddrType::V4 => {
-            Ok(vec![SocketAddr::from(SocketAddrV4::new(Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]), port))])
-        },
-        AddrType::Domain => {
-            let mut domain = String::from_utf8_lossy(&addr[..]).to_string();
-            domain.push_str(&":");
-            domain.push_str(&port.to_string());
+    /// Relay over a compressed tunnel to another merino, which is either the
+    /// client (`compress_client`) or the target
+    #[cfg(feature = "compression")]
+    fn relay_compressed(&mut self, target: TcpStream, early_data: Vec<u8>, compress_client: bool) -> Result<(), Box<dyn Error>> {
+        use crate::compress::{Deflater, Inflater};
+
+        let (inbound_in, inbound_out) = (self.stream.try_clone()?, self.stream.try_clone()?);
+        if compress_client {
+            // Data sent with the request is already compressed
+            self.relay_halves(target.try_clone()?, Deflater::new(inbound_out), Inflater::new(early_data, inbound_in), target, 0);
+            return Ok(());
+        }
 
-            Ok(domain.to_socket_addrs()?.collect())
+        let mut outbound_out = Deflater::new(target.try_clone()?);
+        if !early_data.is_empty() {
+            trace!("Passing on {} bytes sent with the request", early_data.len());
+            outbound_out.write_all(&early_data)?;
+            self.status.bytes_up.fetch_add(early_data.len() as u64, Ordering::Relaxed);
         }
+        self.relay_halves(Inflater::new(Vec::new(), target), inbound_out, inbound_in, outbound_out, early_data.len() as u64);
+        Ok(())
+    }
 
+    /// Copy from the target to the client and from the client to the target
+    /// until both directions are done, `early` bytes having been sent up
+    /// already
+    fn relay_halves<DR, DW, UR, UW>(&mut self, outbound_in: DR, inbound_out: DW, inbound_in: UR, outbound_out: UW, early: u64)
+    where
+        DR: Read + TunnelEnd + Send + 'static,
+        DW: Write + TunnelEnd + Send + 'static,
+        UR: Read + TunnelEnd + Send + 'static,
+        UW: Write + TunnelEnd + Send + 'static,
+    {
+        #[cfg(feature = "chaos")]
+        if let Some(chaos) = self.options.chaos.clone() {
+            use crate::chaos::Throttled;
+            let inbound_out = Throttled::new(inbound_out, chaos.clone());
+            return self.copy_halves(outbound_in, inbound_out, inbound_in, Throttled::new(outbound_out, chaos), early);
+        }
+        self.copy_halves(outbound_in, inbound_out, inbound_in, outbound_out, early)
     }
-}
 
+    /// `relay_halves` once the ends are set up
+    fn copy_halves<DR, DW, UR, UW>(&mut self, outbound_in: DR, inbound_out: DW, inbound_in: UR, outbound_out: UW, early: u64)
+    where
+        DR: Read + TunnelEnd + Send + 'static,
+        DW: Write + TunnelEnd + Send + 'static,
+        UR: Read + TunnelEnd + Send + 'static,
+        UW: Write + TunnelEnd + Send + 'static,
+    {
+        let read_timeout = self.options.relay_read_timeout;
+        let total = Arc::new(AtomicU64::new(early));
+        let limit = self.options.max_session_bytes;
+
+        // Download Thread
+        let download = {
+            let total = total.clone();
+            let status = self.status.clone();
+            thread::spawn(move || relay_half(outbound_in, inbound_out, &total, limit, &status, read_timeout, &status.bytes_down))
+        };
 
-/// Convert an AddrType and address to String
-fn pretty_print_addr(addr_type: &AddrType, addr: &[u8]) -> String {
-    match addr_type {
-        AddrType::Domain => {
-            String::from_utf8_lossy(addr).to_string()
-        },
-        AddrType::V4 => {
-            addr.iter().map(std::string::ToString::to_string).collect::<Vec<String>>().join(".")
-        },
-        AddrType::V6 => {
-            let addr_16 = (0..8).map(|x| {
-                (u16::from(addr[(x * 2)]) << 8) | u16::from(addr[(x * 2) + 1])
-            }).collect::<Vec<u16>>();
+        // Upload Thread
+        let upload = {
+            let status = self.status.clone();
+            thread::spawn(move || relay_half(inbound_in, outbound_out, &total, limit, &status, read_timeout, &status.bytes_up))
+        };
 
-            addr_16.iter().map(|x| format!("{:x}", x)).collect::<Vec<String>>().join(":")
-        }
+        // Wait for both directions to finish so the session's lifetime is tracked
+        self.session.bytes_down = download.join().unwrap_or(0);
+        self.session.bytes_up = upload.join().unwrap_or(0) + early;
+        self.state.bytes_down.fetch_add(self.session.bytes_down, Ordering::SeqCst);
+        self.state.bytes_up.fetch_add(self.session.bytes_up, Ordering::SeqCst);
     }
-}
 
-/// Proxy User Request
-struct SOCKSReq {
-    pub version: u8,
-    pub command: SockCommand,
-    pub addr_type: AddrType,
-    pub addr: Vec<u8>,
-    pub port: u16
+    /// Return the avalible methods based on `self.auth_nmethods`
+    ///
+    /// All advertised method bytes are consumed, including ones the server
+    /// doesn't support, so the stream stays aligned for the next message.
+    ///
+    /// Returns every method the client offered along with the supported ones.
+    fn get_avalible_methods(&mut self) -> Result<(Vec<u8>, Vec<u8>), Box<dyn Error>> {
+        let auth_nmethods = self.auth_nmethods;
+        let offered = protocol::read_methods(&mut self.handshake(), auth_nmethods)?;
+
+        // Only keep the methods we support
+        let mut methods = offered.clone();
+        methods.retain(|method| self.auth_methods.contains(method) || self.options.auth_handlers.iter().any(|handler| handler.method() == *method));
+        Ok((offered, methods))
+    }
 }
 
-impl SOCKSReq {
-    /// Parse a SOCKS Req from a TcpStream
-    fn from_stream(stream: &mut TcpStream) -> Result<Self, Box<dyn Error>> {
-        let mut packet = [0u8; 4];
-        // Read a byte from the stream and determine the version being requested
-        stream.read_exact(&mut packet)?;
-
-        if packet[0] != SOCKS_VERSION {
-            warn!("from_stream Unsupported version: SOCKS{}", packet[0]);
-            stream.shutdown(Shutdown::Both)?;
+/// A client stream that closes the connection once the client sent more than
+/// `remaining` bytes
+struct HandshakeStream<'a, T: ClientStream> {
+    stream: &'a mut T,
+    remaining: &'a mut u64,
+    peer_addr: SocketAddr,
+}
 
+impl<T: ClientStream> Read for HandshakeStream<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if *self.remaining == 0 && !buf.is_empty() {
+            warn!("Client {} sent too long a handshake, closing the connection", self.peer_addr);
+            self.stream.shutdown(Shutdown::Both).unwrap_or(());
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "handshake too long"));
         }
 
-        // Get command
-        let mut command: SockCommand = SockCommand::Connect;
-        match SockCommand::from(packet[1] as usize) {
-            Some(com) => {
-                command = com;
-                Ok(())
-            },
-            None => {
-                warn!("Invalid Command");
-                stream.shutdown(Shutdown::Both)?;
-                Err(ResponseCode::CommandNotSupported)
-            }
-        }?;
-
-        // DST.address
+        // Stop right at the limit rather than at the end of the next buffer
+        let len = (*self.remaining).min(buf.len() as u64) as usize;
+        let n = self.stream.read(&mut buf[..len])?;
+        *self.remaining -= n as u64;
+        Ok(n)
+    }
+}
 
-        let mut addr_type: AddrType = AddrType::V6;
-        match AddrType::from(packet[3] as usize) {
-            Some(addr) => {
-                addr_type = addr ;
-                Ok(())
-            },
-            None => {
-                error!("No Addr");
-                stream.shutdown(Shutdown::Both)?;
-                Err(ResponseCode::AddrTypeNotSupported)
-            }
-        }?;
+impl<T: ClientStream> Write for HandshakeStream<'_, T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
 
-        trace!("Getting Addr");
-        // Get Addr from addr_type and stream
-        let addr: Result<Vec<u8>, Box<dyn Error>> = match addr_type {
-            AddrType::Domain => {
-                let mut dlen = [0u8; 1];
-                stream.read_exact(&mut dlen)?;
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
 
-                let mut domain = vec![0u8; dlen[0] as usize];
-                stream.read_exact(&mut domain)?;
+/// Proxy User Request
+type SOCKSReq = Request;
 
-                Ok(domain)
-            },
-            AddrType::V4 => {
-                let mut addr = [0u8; 4];
-                stream.read_exact(&mut addr)?;
-                Ok(addr.to_vec())
-            },
-            AddrType::V6 => {
-                let mut addr = [0u8; 16];
-                stream.read_exact(&mut addr)?;
-                Ok(addr.to_vec())
+impl SOCKSReq {
+    /// Parse a SOCKS Req from a client stream, along with any data the client
+    /// sent right behind it
+    ///
+    /// An invalid request is returned as the `ResponseCode` to reply with.
+    fn from_stream<R: Read>(stream: &mut R) -> Result<(Self, Vec<u8>), Box<dyn Error>> {
+        match protocol::read_request_buffered(stream) {
+            Ok(req) => Ok(req),
+            Err(ProtocolError::Io { source }) => Err(Box::new(source)),
+            Err(e) => {
+                warn!("from_stream: {}", e);
+                Err(Box::new(e.response_code()))
             }
-        };
-
-        let addr = addr?;
-
-        // read DST.port
-        let mut port = [0u8; 2];
-        stream.read_exact(&mut port)?;
-
-        // Merge two u8s into u16
-        let port = (u16::from(port[0]) << 8) | u16::from(port[1]);
-
-        // Return parsed request
-        Ok(SOCKSReq {
-            version: packet[0],
-            command,
-            addr_type,
-            addr,
-            port
-        })
+        }
     }
 }
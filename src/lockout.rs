@@ -0,0 +1,110 @@
+//! Locking out clients that keep failing authentication
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Entries kept before expired ones are swept out
+const SWEEP_THRESHOLD: usize = 1024;
+
+/// Failed authentication attempts by client IP
+///
+/// A client that fails `threshold` times, each within `duration` of the one
+/// before, is locked out until `duration` has passed since its last failure.
+pub(crate) struct AuthLockout {
+    threshold: u32,
+    duration: Duration,
+    clients: Mutex<HashMap<IpAddr, Failures>>,
+}
+
+struct Failures {
+    count: u32,
+    last: Instant,
+}
+
+impl AuthLockout {
+    pub(crate) fn new(threshold: u32, duration: Duration) -> Self {
+        AuthLockout {
+            threshold,
+            duration,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether `ip` is locked out right now
+    pub(crate) fn is_locked(&self, ip: IpAddr) -> bool {
+        let clients = self.clients.lock().unwrap();
+        clients.get(&ip).is_some_and(|failures| failures.count >= self.threshold && failures.last.elapsed() < self.duration)
+    }
+
+    /// Count a failed attempt by `ip`, returning whether that locked it out
+    pub(crate) fn record_failure(&self, ip: IpAddr) -> bool {
+        let mut clients = self.clients.lock().unwrap();
+        if clients.len() >= SWEEP_THRESHOLD {
+            let duration = self.duration;
+            clients.retain(|_, failures| failures.last.elapsed() < duration);
+        }
+
+        let now = Instant::now();
+        let failures = clients.entry(ip).or_insert(Failures { count: 0, last: now });
+        if now.duration_since(failures.last) >= self.duration {
+            failures.count = 0;
+        }
+        failures.count += 1;
+        failures.last = now;
+        failures.count == self.threshold
+    }
+
+    /// Forget the failures of `ip` once it authenticates
+    pub(crate) fn record_success(&self, ip: IpAddr) {
+        self.clients.lock().unwrap().remove(&ip);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn locks_after_threshold() {
+        let lockout = AuthLockout::new(3, Duration::from_secs(60));
+        let ip = IpAddr::from([192, 0, 2, 1]);
+
+        assert!(!lockout.record_failure(ip));
+        assert!(!lockout.record_failure(ip));
+        assert!(!lockout.is_locked(ip));
+        assert!(lockout.record_failure(ip));
+        assert!(lockout.is_locked(ip));
+
+        // Other clients aren't affected
+        assert!(!lockout.is_locked(IpAddr::from([192, 0, 2, 2])));
+    }
+
+    #[test]
+    fn expires() {
+        let lockout = AuthLockout::new(2, Duration::from_millis(50));
+        let ip = IpAddr::from([192, 0, 2, 1]);
+
+        lockout.record_failure(ip);
+        lockout.record_failure(ip);
+        assert!(lockout.is_locked(ip));
+        thread::sleep(Duration::from_millis(60));
+        assert!(!lockout.is_locked(ip));
+
+        // Failures spread out further than the window don't add up
+        assert!(!lockout.record_failure(ip));
+        assert!(!lockout.is_locked(ip));
+    }
+
+    #[test]
+    fn success_resets() {
+        let lockout = AuthLockout::new(2, Duration::from_secs(60));
+        let ip = IpAddr::from([192, 0, 2, 1]);
+
+        lockout.record_failure(ip);
+        lockout.record_success(ip);
+        assert!(!lockout.record_failure(ip));
+        assert!(!lockout.is_locked(ip));
+    }
+}
@@ -4,8 +4,10 @@
 use structopt::StructOpt;
 use merino::*;
 use std::error::Error;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::env;
+use std::net::SocketAddr;
+use std::time::Duration;
 
 /// Logo to be printed at when merino is run 
 const LOGO: &str = r"
@@ -27,7 +29,7 @@ struct Opt {
     port: u16,
 
     #[structopt(short = "i", long = "ip", default_value = "127.0.0.1")]
-    /// Set ip to listen on
+    /// Set ip to listen on, or several separated by commas
     ip: String,
 
     #[structopt(long = "no-auth")]
@@ -38,6 +40,311 @@ struct Opt {
     /// CSV File with username/password pairs
     users: Option<PathBuf>,
 
+    #[structopt(long = "env-users")]
+    /// Also load username/password pairs from MERINO_USERS ("user:pass,user:pass") and MERINO_USER_<n> ("user:pass")
+    env_users: bool,
+
+    #[structopt(long = "listen-backlog")]
+    /// Size of the listen backlog (defaults to the OS default)
+    listen_backlog: Option<i32>,
+
+    #[structopt(long = "idle-shutdown")]
+    /// Exit after this many seconds without any active connections
+    idle_shutdown: Option<u64>,
+
+    #[structopt(long = "json-logs")]
+    /// Log requests as JSON objects
+    json_logs: bool,
+
+    #[structopt(long = "max-connections")]
+    /// Maximum number of connections served at once
+    max_connections: Option<usize>,
+
+    #[structopt(long = "max-open-fds")]
+    /// Close new connections when about this many file descriptors are in use
+    max_open_fds: Option<usize>,
+
+    #[structopt(long = "overload-reply")]
+    /// Reply with a SOCKS failure instead of closing connections over --max-connections
+    overload_reply: bool,
+
+    #[structopt(long = "overload-queue-timeout")]
+    /// Milliseconds to hold connections over --max-connections waiting for a free slot
+    overload_queue_timeout: Option<u64>,
+
+    #[structopt(long = "max-connections-per-user")]
+    /// Maximum number of requests a single user may have open at once
+    max_connections_per_user: Option<usize>,
+
+    #[structopt(long = "auth-failure-threshold")]
+    /// Lock out client IPs after this many failed logins
+    auth_failure_threshold: Option<u32>,
+
+    #[structopt(long = "auth-lockout-duration", default_value = "300")]
+    /// How long a lockout lasts after the last failed login, in seconds
+    auth_lockout_duration: u64,
+
+    #[structopt(long = "auth-failure-delay", default_value = "0")]
+    /// Delay replies to failed logins by up to this many milliseconds
+    auth_failure_delay: u64,
+
+    #[structopt(long = "dscp")]
+    /// DSCP value (0-63) to mark outbound traffic with
+    dscp: Option<u8>,
+
+    #[structopt(long = "no-dns")]
+    /// Reject requests for domain names, only allowing IP addresses
+    no_dns: bool,
+
+    #[structopt(long = "addr-type")]
+    /// Only allow requests naming this address type: ipv4, ipv6 or domain. Repeat to allow several.
+    addr_type: Vec<AddrType>,
+
+    #[structopt(long = "no-ipv6")]
+    /// Never connect to IPv6 targets, for networks without working IPv6
+    no_ipv6: bool,
+
+    #[structopt(long = "no-ipv4")]
+    /// Never connect to IPv4 targets, for IPv6-only networks
+    no_ipv4: bool,
+
+    #[structopt(long = "prefer-family", default_value = "system")]
+    /// Address family to try first for targets with both: system, ipv4 or ipv6
+    prefer_family: FamilyPreference,
+
+    #[structopt(long = "expect-proxy-protocol")]
+    /// Expect a PROXY protocol header from a load balancer on every connection
+    expect_proxy_protocol: bool,
+
+    #[structopt(long = "reuse-port")]
+    /// Set SO_REUSEPORT so several instances can share the port (unix only)
+    reuse_port: bool,
+
+    #[structopt(long = "health-check")]
+    /// Answer "MERINO-HEALTH\n" with "OK\n", for TCP health checkers
+    health_check: bool,
+
+    #[structopt(long = "access-log", parse(from_os_str))]
+    /// Append a line per request to this file, see `merino::AccessLog` for the format. Reopened on SIGUSR1 with the signals feature.
+    access_log: Option<PathBuf>,
+
+    #[structopt(long = "connect-retries", default_value = "0")]
+    /// Retry failed outbound lookups and connections this many times
+    connect_retries: u32,
+
+    #[structopt(long = "warn-port")]
+    /// Log a warning for requests to this port, without blocking them
+    warn_port: Vec<u16>,
+
+    #[structopt(long = "warn-common-ports")]
+    /// Log a warning for requests to ports commonly abused through proxies (SMTP, telnet, SMB, RDP)
+    warn_common_ports: bool,
+
+    #[structopt(long = "allow")]
+    /// Allow CONNECT to a CIDR, IP address, domain (and its subdomains) or regex:PATTERN (regex feature)
+    allow: Vec<RuleTarget>,
+
+    #[structopt(long = "deny")]
+    /// Deny CONNECT to a CIDR, IP address, domain or regex:PATTERN, taking precedence over --allow
+    deny: Vec<RuleTarget>,
+
+    #[structopt(long = "deny-by-default")]
+    /// Deny CONNECT to anything not allowed with --allow
+    deny_by_default: bool,
+
+    #[structopt(long = "outbound-ttl")]
+    /// IP TTL (IPv6 hop limit) for outbound connections
+    outbound_ttl: Option<u32>,
+
+    #[structopt(long = "advertised-addr")]
+    /// Address to report to clients in replies instead of the local one, e.g. the public IP behind NAT
+    advertised_addr: Option<std::net::IpAddr>,
+
+    #[structopt(long = "reap-idle")]
+    /// Kill connections that relay nothing for this many seconds
+    reap_idle: Option<u64>,
+
+    #[structopt(long = "fwmark")]
+    /// Firewall mark (SO_MARK) for outbound connections (Linux only)
+    fwmark: Option<u32>,
+
+    #[structopt(long = "connect-timeout")]
+    /// Seconds to wait for each address of a CONNECT target before trying the next
+    connect_timeout: Option<u64>,
+
+    #[structopt(long = "outbound-port-range", parse(try_from_str = "parse_port_range"))]
+    /// Source port range for outbound connections, e.g. 40000-40999
+    outbound_port_range: Option<(u16, u16)>,
+
+    #[structopt(long = "max-auth-methods", default_value = "16")]
+    /// Close connections from clients offering more auth methods than this
+    max_auth_methods: u8,
+
+    #[structopt(long = "max-handshake-bytes", default_value = "8192")]
+    /// Close connections from clients sending more than this before their request is complete
+    max_handshake_bytes: u64,
+
+    #[structopt(long = "relay-read-timeout")]
+    /// Close a tunnel direction after this many seconds without data from its source
+    relay_read_timeout: Option<u64>,
+
+    #[structopt(long = "relay-write-timeout")]
+    /// Close a tunnel direction after a write is blocked for this many seconds
+    relay_write_timeout: Option<u64>,
+
+    #[structopt(long = "tcp-fast-open")]
+    /// Use TCP Fast Open for outbound connections (Linux, client-first protocols only)
+    tcp_fast_open: bool,
+
+    #[structopt(long = "netns", parse(from_os_str))]
+    /// Connect to targets from this network namespace, a name from `ip netns` or a path (Linux)
+    netns: Option<PathBuf>,
+
+    #[structopt(long = "upstream")]
+    /// Send CONNECT requests through this SOCKS5 proxy, e.g. another merino
+    upstream: Option<SocketAddr>,
+
+    #[structopt(long = "upstream-compression")]
+    /// Compress tunnels to the upstream if it's a merino accepting them
+    upstream_compression: bool,
+
+    #[structopt(long = "upstream-pool-size", default_value = "0")]
+    /// Connections to the upstream to keep open ahead of time
+    upstream_pool_size: usize,
+
+    #[structopt(long = "upstream-pool-idle", default_value = "10")]
+    /// How long a spare upstream connection is kept, in seconds
+    upstream_pool_idle: u64,
+
+    #[structopt(long = "accept-compressed")]
+    /// Accept compressed tunnels from other merino instances, without authentication
+    accept_compressed: bool,
+
+    #[structopt(long = "chaos-reply-delay")]
+    /// Testing only: wait this many milliseconds before every reply (chaos feature)
+    chaos_reply_delay: Option<u64>,
+
+    #[structopt(long = "chaos-jitter", default_value = "0")]
+    /// Testing only: add up to this many random milliseconds to replies and relayed chunks (chaos feature)
+    chaos_jitter: u64,
+
+    #[structopt(long = "chaos-relay-rate")]
+    /// Testing only: relay at most this many bytes per second each way (chaos feature)
+    chaos_relay_rate: Option<u64>,
+
+    #[structopt(long = "admin")]
+    /// Serve admin commands on this loopback address or Unix socket path, see the README (admin feature)
+    admin: Option<String>,
+
+}
+
+/// Parse a `LO-HI` port range
+fn parse_port_range(range: &str) -> Result<(u16, u16), String> {
+    let invalid = || format!("invalid port range '{}', expected LO-HI", range);
+    let (lo, hi) = range.split_once('-').ok_or_else(invalid)?;
+    let (lo, hi) = (lo.parse().map_err(|_| invalid())?, hi.parse().map_err(|_| invalid())?);
+    if lo > hi {
+        return Err(invalid());
+    }
+    Ok((lo, hi))
+}
+
+/// Read users from a CSV file with `username` and `password` columns
+fn read_users(path: &Path) -> Result<Vec<User>, Box<dyn Error>> {
+    let file = std::fs::File::open(path)?;
+
+    let mut users: Vec<User> = Vec::new();
+
+    let mut rdr = csv::Reader::from_reader(file);
+    for result in rdr.deserialize() {
+        let record: User = result?;
+
+        trace!("Loaded user: {}", record.username);
+        users.push(record);
+    }
+
+    Ok(users)
+}
+
+/// Users the admin `reload` command can replace while merino runs
+#[cfg(feature = "admin")]
+#[derive(Clone, Default)]
+struct ReloadableUsers(std::sync::Arc<std::sync::RwLock<UserStore>>);
+
+#[cfg(feature = "admin")]
+impl CredentialStore for ReloadableUsers {
+    fn verify(&self, username: &str, password: &str) -> bool {
+        self.0.read().unwrap().verify(username, password)
+    }
+}
+
+/// Serve admin commands on `admin`, a loopback address or a Unix socket path
+///
+/// `reload` rereads `users_file` and the environment users, keeping the
+/// current users if any of them are invalid.
+#[cfg(feature = "admin")]
+fn spawn_admin(admin: &str, merino: &mut Merino, access_log: Option<AccessLog>, users_file: Option<PathBuf>, env_users: bool) -> Result<(), Box<dyn Error>> {
+    let server = match admin.parse::<SocketAddr>() {
+        Ok(addr) => AdminServer::bind_tcp(addr, merino.handle())?,
+        #[cfg(unix)]
+        Err(_) => AdminServer::bind_unix(admin, merino.handle())?,
+        #[cfg(not(unix))]
+        Err(_) => return Err(format!("invalid admin address {}", admin).into()),
+    };
+    let server = match access_log {
+        Some(access_log) => server.with_access_log(access_log),
+        None => server,
+    };
+
+    let users = ReloadableUsers::default();
+    merino.set_credential_store(users.clone());
+    let reload_users = move || {
+        let mut reloaded = match &users_file {
+            Some(users_file) => read_users(users_file).map_err(|e| e.to_string())?,
+            None => Vec::new(),
+        };
+        if env_users {
+            reloaded.extend(users_from_env().map_err(|e| e.to_string())?);
+        }
+        if let Err(issues) = validate_users(&reloaded, true) {
+            if let Some(issue) = issues.iter().find(|issue| !matches!(issue, ValidationIssue::WeakPassword { .. })) {
+                return Err(issue.to_string());
+            }
+        }
+        info!("Loaded {} users", reloaded.len());
+        *users.0.write().unwrap() = UserStore::new(reloaded);
+        Ok(())
+    };
+    // Load the users the server starts with the same way
+    reload_users()?;
+    server.with_reload(std::sync::Arc::new(reload_users)).spawn();
+    Ok(())
+}
+
+/// Reopen `access_log` whenever merino gets SIGUSR1, for logrotate
+///
+/// Has to run before any other thread is started, so they all inherit the
+/// blocked signal and it's only delivered to the waiting thread.
+#[cfg(all(target_os = "linux", feature = "signals"))]
+fn reopen_on_sigusr1(access_log: AccessLog) -> Result<(), Box<dyn Error>> {
+    use nix::sys::signal::{SigSet, Signal};
+
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGUSR1);
+    signals.thread_block()?;
+    std::thread::spawn(move || {
+        while signals.wait().is_ok() {
+            if let Err(e) = access_log.reopen() {
+                error!("Failed to reopen access log: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(all(target_os = "linux", feature = "signals")))]
+fn reopen_on_sigusr1(_access_log: AccessLog) -> Result<(), Box<dyn Error>> {
+    Ok(())
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
@@ -61,37 +368,146 @@ fn main() -> Result<(), Box<dyn Error>> {
     // Allow unauthenticated connections
     if opt.no_auth { auth_methods.push(merino::AuthMethods::NoAuth as u8); }
 
+    // Allow merino-to-merino compressed tunnels
+    if opt.accept_compressed { auth_methods.push(AuthMethods::CompressedTunnel as u8); }
+    if (opt.accept_compressed || opt.upstream_compression) && !cfg!(feature = "compression") {
+        warn!("merino was built without the compression feature, tunnels won't be compressed");
+    }
+
     // Enable username/password auth
-    let authed_users: Result<Vec<User>, Box<dyn Error>> = match opt.users {
+    let authed_users: Result<Vec<User>, Box<dyn Error>> = match &opt.users {
         Some(users_file) => {
             auth_methods.push(AuthMethods::UserPass as u8);
-            let file = std::fs::File::open(users_file)?;
-
-            let mut users: Vec<User> = Vec::new();
-
-
-            let mut rdr = csv::Reader::from_reader(file);
-            for result in rdr.deserialize() {
-                let record: User = result?;
-
-                trace!("Loaded user: {}", record.username);
-                users.push(record);
-            }
-
-            Ok(users)
+            read_users(users_file)
         },
         _ => { Ok(Vec::new()) }
     };
 
-    let authed_users = authed_users?;
+    let mut authed_users = authed_users?;
+
+    if opt.env_users {
+        let users = users_from_env()?;
+        if users.is_empty() {
+            warn!("--env-users is set but no users were found in the environment");
+        }
+        else if !auth_methods.contains(&(AuthMethods::UserPass as u8)) {
+            auth_methods.push(AuthMethods::UserPass as u8);
+        }
+        authed_users.extend(users);
+    }
+
+    // Weak passwords are only warned about, other problems stop startup
+    if let Err(issues) = validate_users(&authed_users, true) {
+        let mut fatal = false;
+        for issue in issues {
+            match issue {
+                ValidationIssue::WeakPassword { .. } => warn!("{}", issue),
+                _ => {
+                    error!("{}", issue);
+                    fatal = true;
+                }
+            }
+        }
+        if fatal {
+            return Err("invalid user list".into());
+        }
+    }
 
     if auth_methods.is_empty() {
         warn!("No Authentication methods enabled. Clients will not be able to connect!");
     }
 
 
+    let access_log = opt.access_log.map(AccessLog::open).transpose()?;
+    if let Some(access_log) = &access_log {
+        reopen_on_sigusr1(access_log.clone())?;
+    }
+    #[cfg(feature = "admin")]
+    let admin_access_log = access_log.clone();
+
+    let chaos_requested = opt.chaos_reply_delay.is_some() || opt.chaos_jitter > 0 || opt.chaos_relay_rate.is_some();
+    if chaos_requested && !cfg!(feature = "chaos") {
+        warn!("merino was built without the chaos feature, no delays will be injected");
+    }
+    #[cfg(feature = "chaos")]
+    let chaos = if chaos_requested {
+        warn!("Chaos mode is on: replies are delayed and tunnels throttled on purpose");
+        Some(Chaos {
+            reply_delay: Duration::from_millis(opt.chaos_reply_delay.unwrap_or(0)),
+            jitter: Duration::from_millis(opt.chaos_jitter),
+            relay_rate: opt.chaos_relay_rate,
+        })
+    } else {
+        None
+    };
+
+    let mut sensitive_ports = opt.warn_port;
+    if opt.warn_common_ports {
+        sensitive_ports.extend_from_slice(COMMON_SENSITIVE_PORTS);
+    }
+
+    let rules = opt.deny.into_iter().map(|target| Rule { policy: Policy::Deny, target })
+        .chain(opt.allow.into_iter().map(|target| Rule { policy: Policy::Allow, target }))
+        .collect();
+    let default_policy = if opt.deny_by_default { Policy::Deny } else { Policy::Allow };
+
+    let options = Options {
+        listen_backlog: opt.listen_backlog,
+        idle_shutdown: opt.idle_shutdown.map(Duration::from_secs),
+        log_format: if opt.json_logs { LogFormat::Json } else { LogFormat::Text },
+        max_connections: opt.max_connections,
+        max_open_fds: opt.max_open_fds,
+        overload_reply: opt.overload_reply,
+        overload_queue_timeout: opt.overload_queue_timeout.map(Duration::from_millis),
+        max_connections_per_user: opt.max_connections_per_user,
+        auth_failure_threshold: opt.auth_failure_threshold,
+        auth_lockout_duration: Duration::from_secs(opt.auth_lockout_duration),
+        auth_failure_delay: Duration::from_millis(opt.auth_failure_delay),
+        sensitive_ports,
+        dscp: opt.dscp,
+        literal_only: opt.no_dns,
+        allowed_addr_types: if opt.addr_type.is_empty() { Options::default().allowed_addr_types } else { opt.addr_type },
+        disable_ipv6: opt.no_ipv6,
+        disable_ipv4: opt.no_ipv4,
+        address_family_preference: opt.prefer_family,
+        #[cfg(feature = "chaos")]
+        chaos,
+        expect_proxy_protocol: opt.expect_proxy_protocol,
+        reuse_port: opt.reuse_port,
+        health_check: opt.health_check,
+        access_log,
+        connect_retries: opt.connect_retries,
+        rules: RuleSet { rules, default_policy },
+        outbound_ttl: opt.outbound_ttl,
+        advertised_addr: opt.advertised_addr,
+        reap_idle: opt.reap_idle.map(Duration::from_secs),
+        fwmark: opt.fwmark,
+        connect_timeout: opt.connect_timeout.map(Duration::from_secs),
+        outbound_port_range: opt.outbound_port_range,
+        max_auth_methods: opt.max_auth_methods,
+        max_handshake_bytes: opt.max_handshake_bytes,
+        relay_read_timeout: opt.relay_read_timeout.map(Duration::from_secs),
+        relay_write_timeout: opt.relay_write_timeout.map(Duration::from_secs),
+        tcp_fast_open: opt.tcp_fast_open,
+        upstream: opt.upstream,
+        upstream_compression: opt.upstream_compression,
+        upstream_pool_size: opt.upstream_pool_size,
+        upstream_pool_idle: Duration::from_secs(opt.upstream_pool_idle),
+        // Names are looked up where `ip netns` keeps them
+        netns: opt.netns.map(|netns| if netns.components().count() == 1 { Path::new("/var/run/netns").join(netns) } else { netns }),
+        ..Options::default()
+    };
+
     // Create proxy server
-    let mut merino = Merino::new(opt.port, opt.ip, auth_methods, authed_users)?;
+    let mut merino = Merino::with_options(opt.port, opt.ip, auth_methods, authed_users, options)?;
+
+    match opt.admin {
+        #[cfg(feature = "admin")]
+        Some(admin) => spawn_admin(&admin, &mut merino, admin_access_log, opt.users, opt.env_users)?,
+        #[cfg(not(feature = "admin"))]
+        Some(_) => warn!("merino was built without the admin feature, --admin is ignored"),
+        None => {},
+    }
 
     // Start Proxies
     merino.serve()?;
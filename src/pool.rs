@@ -0,0 +1,170 @@
+//! Spare connections to the upstream proxy
+//!
+//! A SOCKS5 connection carries a single request: once the upstream accepts a
+//! CONNECT, the connection is the tunnel and can never be handed back. So
+//! rather than reusing connections, the pool opens a few ahead of time and a
+//! request takes one instead of waiting for a TCP handshake with the
+//! upstream. Every connection taken is replaced in the background.
+//!
+//! Spare connections sit idle before the greeting, so the upstream may close
+//! them at any time, e.g. when its handshake timeout runs out. They're only
+//! kept for `max_idle` and checked for EOF before being handed out.
+use std::io;
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Idle connections to `Options::upstream`
+pub(crate) struct UpstreamPool {
+    size: usize,
+    max_idle: Duration,
+    idle: Mutex<Vec<(TcpStream, Instant)>>,
+    /// Set while `fill` runs, so concurrent fills don't overshoot `size`
+    filling: AtomicBool,
+}
+
+impl UpstreamPool {
+    pub(crate) fn new(size: usize, max_idle: Duration) -> Self {
+        UpstreamPool {
+            size,
+            max_idle,
+            idle: Mutex::new(Vec::new()),
+            filling: AtomicBool::new(false),
+        }
+    }
+
+    /// Connections the pool keeps open when full
+    pub(crate) fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Take the newest idle connection that's still open, if any
+    pub(crate) fn take(&self) -> Option<TcpStream> {
+        let mut idle = self.idle.lock().unwrap();
+        while let Some((stream, since)) = idle.pop() {
+            if since.elapsed() < self.max_idle && is_open(&stream) {
+                return Some(stream);
+            }
+        }
+        None
+    }
+
+    /// Open connections with `connect` on a new thread until `size` are
+    /// idle, giving up at the first failure. Returns `None` without spawning
+    /// if a fill is running already, so there's never more than one per pool.
+    pub(crate) fn spawn_fill<F>(self: &Arc<Self>, connect: F) -> Option<JoinHandle<()>>
+    where
+        F: Fn() -> io::Result<TcpStream> + Send + 'static,
+    {
+        if self.filling.swap(true, Ordering::SeqCst) {
+            return None;
+        }
+        let pool = self.clone();
+        Some(thread::spawn(move || pool.fill(connect)))
+    }
+
+    /// The body of `spawn_fill`, clearing `filling` when done
+    fn fill<F: Fn() -> io::Result<TcpStream>>(&self, connect: F) {
+        loop {
+            {
+                let mut idle = self.idle.lock().unwrap();
+                let max_idle = self.max_idle;
+                idle.retain(|(_, since)| since.elapsed() < max_idle);
+                if idle.len() >= self.size {
+                    break;
+                }
+            }
+            // Connect without holding the lock, so requests can still take
+            match connect() {
+                Ok(stream) => self.idle.lock().unwrap().push((stream, Instant::now())),
+                Err(e) => {
+                    debug!("Failed to open a spare upstream connection: {}", e);
+                    break;
+                }
+            }
+        }
+
+        self.filling.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Whether the upstream still has `stream` open
+///
+/// Nothing is sent before the greeting, so anything to read, EOF included,
+/// means the upstream gave up on it.
+fn is_open(stream: &TcpStream) -> bool {
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let open = matches!(stream.peek(&mut [0u8; 1]), Err(ref e) if e.kind() == io::ErrorKind::WouldBlock);
+    stream.set_nonblocking(false).is_ok() && open
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn fill_and_take() {
+        let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = upstream.local_addr().unwrap();
+        let pool = Arc::new(UpstreamPool::new(2, Duration::from_secs(60)));
+
+        pool.spawn_fill(move || TcpStream::connect(addr)).unwrap().join().unwrap();
+        let accepted: Vec<_> = (0..2).map(|_| upstream.accept().unwrap().0).collect();
+        assert!(pool.take().is_some());
+        assert!(pool.take().is_some());
+        assert!(pool.take().is_none());
+        drop(accepted);
+    }
+
+    #[test]
+    fn skips_closed() {
+        let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = upstream.local_addr().unwrap();
+        let pool = Arc::new(UpstreamPool::new(1, Duration::from_secs(60)));
+
+        pool.spawn_fill(move || TcpStream::connect(addr)).unwrap().join().unwrap();
+        drop(upstream.accept().unwrap());
+        // Give the FIN time to arrive
+        thread::sleep(Duration::from_millis(50));
+        assert!(pool.take().is_none());
+    }
+
+    #[test]
+    fn one_fill_at_a_time() {
+        use std::sync::atomic::AtomicUsize;
+
+        let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = upstream.local_addr().unwrap();
+        let pool = Arc::new(UpstreamPool::new(1, Duration::from_secs(60)));
+        let fills = Arc::new(AtomicUsize::new(0));
+
+        let spawned: Vec<_> = (0..5).filter_map(|_| {
+            let fills = fills.clone();
+            pool.spawn_fill(move || {
+                fills.fetch_add(1, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(100));
+                TcpStream::connect(addr)
+            })
+        }).collect();
+        assert_eq!(spawned.len(), 1);
+        spawned.into_iter().for_each(|fill| fill.join().unwrap());
+        assert_eq!(fills.load(Ordering::SeqCst), 1);
+        assert!(pool.take().is_some());
+    }
+
+    #[test]
+    fn expires() {
+        let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = upstream.local_addr().unwrap();
+        let pool = Arc::new(UpstreamPool::new(1, Duration::from_millis(50)));
+
+        pool.spawn_fill(move || TcpStream::connect(addr)).unwrap().join().unwrap();
+        thread::sleep(Duration::from_millis(60));
+        assert!(pool.take().is_none());
+    }
+}
@@ -0,0 +1,672 @@
+//! SOCKS5 wire format
+//!
+//! Everything here only reads or builds bytes, there is no socket handling.
+//! Deciding what to do about a malformed message (replying, shutting the
+//! connection down) is up to the caller, which makes these usable from any
+//! event loop.
+//!
+//! The `read_*` functions consume exactly the bytes of the message they parse.
+//! To parse from a byte slice, pass a `&mut &[u8]`; the bytes consumed are
+//! what's missing from the slice afterwards:
+//!
+//! ```
+//! use merino::protocol::{read_request, SockCommand};
+//!
+//! let bytes = [5, 1, 0, 1, 127, 0, 0, 1, 0, 80, 0xAA];
+//! let mut rest = &bytes[..];
+//! let request = read_request(&mut rest).unwrap();
+//!
+//! assert_eq!(request.command, SockCommand::Connect);
+//! assert_eq!(bytes.len() - rest.len(), 10);
+//! ```
+//!
+//! `parse_request` does the same for requests and tells a request that's
+//! still incomplete apart from a malformed one.
+use crate::ResponseCode;
+use snafu::Snafu;
+
+use std::convert::TryFrom;
+use std::io::{self, BufReader, Read};
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6, Ipv4Addr, Ipv6Addr, ToSocketAddrs};
+
+/// Version of socks
+pub const SOCKS_VERSION: u8 = 0x05;
+
+/// Version of the username/password subnegotiation (RFC 1929)
+pub const USERPASS_VERSION: u8 = 0x01;
+
+/// Longest possible request: header, domain length, a 255 byte domain and
+/// the port
+const MAX_REQUEST_LEN: usize = 4 + 1 + 255 + 2;
+
+pub(crate) const RESERVED: u8 = 0x00;
+
+/// Errors from parsing a SOCKS5 message
+#[derive(Debug, Snafu)]
+pub enum ProtocolError {
+    #[snafu(display("{}", source))]
+    Io { source: io::Error },
+    #[snafu(display("Unsupported version: SOCKS{}", version))]
+    UnsupportedVersion { version: u8 },
+    #[snafu(display("Command not supported: {}", command))]
+    UnsupportedCommand { command: u8 },
+    #[snafu(display("Addr Type not supported: {}", addr_type))]
+    UnsupportedAddrType { addr_type: u8 },
+    #[snafu(display("Incomplete message"))]
+    Incomplete,
+}
+
+impl ProtocolError {
+    /// The SOCKS5 reply code matching this error
+    pub fn response_code(&self) -> ResponseCode {
+        match self {
+            ProtocolError::UnsupportedCommand { .. } => ResponseCode::CommandNotSupported,
+            ProtocolError::UnsupportedAddrType { .. } => ResponseCode::AddrTypeNotSupported,
+            _ => ResponseCode::Failure,
+        }
+    }
+}
+
+impl From<io::Error> for ProtocolError {
+    fn from(source: io::Error) -> Self {
+        ProtocolError::Io { source }
+    }
+}
+
+/// Destination address of a SOCKS5 request
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Address {
+    /// IPv4 address and port
+    Ipv4(Ipv4Addr, u16),
+    /// IPv6 address and port
+    Ipv6(Ipv6Addr, u16),
+    /// Domain name (as sent by the client) and port
+    Domain(Vec<u8>, u16),
+}
+
+impl Address {
+    /// Resolve the address to a list of `SocketAddr`s to connect to
+    pub fn to_socket_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        match self {
+            Address::Ipv4(addr, port) => Ok(vec![SocketAddr::from(SocketAddrV4::new(*addr, *port))]),
+            Address::Ipv6(addr, port) => Ok(vec![SocketAddr::from(SocketAddrV6::new(*addr, *port, 0, 0))]),
+            Address::Domain(domain, port) => {
+                let domain = std::str::from_utf8(domain).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                Ok((domain, *port).to_socket_addrs()?.collect())
+            }
+        }
+    }
+}
+
+impl Address {
+    /// Host part of the address: the domain name or IP address without the port
+    ///
+    /// IPv6 addresses are compressed and unbracketed. Domain names that aren't
+    /// valid UTF-8 have the offending bytes escaped as `\xNN`.
+    pub fn host_only(&self) -> String {
+        match self {
+            Address::Ipv4(addr, _) => addr.to_string(),
+            Address::Ipv6(addr, _) => addr.to_string(),
+            Address::Domain(domain, _) => match std::str::from_utf8(domain) {
+                Ok(domain) => domain.to_string(),
+                Err(_) => domain.escape_ascii().to_string(),
+            }
+        }
+    }
+
+    /// Port of the address
+    pub fn port(&self) -> u16 {
+        match self {
+            Address::Ipv4(_, port) | Address::Ipv6(_, port) | Address::Domain(_, port) => *port,
+        }
+    }
+
+    /// ATYP of the address
+    pub fn addr_type(&self) -> AddrType {
+        match self {
+            Address::Ipv4(..) => AddrType::V4,
+            Address::Ipv6(..) => AddrType::V6,
+            Address::Domain(..) => AddrType::Domain,
+        }
+    }
+
+    /// Name of the address type, for logging
+    pub(crate) fn type_name(&self) -> &'static str {
+        match self {
+            Address::Ipv4(..) => "ipv4",
+            Address::Ipv6(..) => "ipv6",
+            Address::Domain(..) => "domain",
+        }
+    }
+}
+
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Address::Ipv4(addr, port) => write!(f, "{}", SocketAddrV4::new(*addr, *port)),
+            Address::Ipv6(addr, port) => write!(f, "{}", SocketAddrV6::new(*addr, *port, 0, 0)),
+            Address::Domain(_, port) => write!(f, "{}:{}", self.host_only(), port),
+        }
+    }
+}
+
+/// Build a SOCKS5 reply carrying `code` and the server bound address `bind_addr`
+///
+/// The address type (ATYP) follows the family of `bind_addr`.
+pub fn build_reply(code: ResponseCode, bind_addr: SocketAddr) -> Vec<u8> {
+    let mut reply = vec![SOCKS_VERSION, code as u8, RESERVED];
+    write_socket_addr(&mut reply, bind_addr);
+    reply
+}
+
+/// STATUS of a username/password subnegotiation reply
+///
+/// RFC 1929 only defines success as 0x00 and failure as anything else. These
+/// are not `ResponseCode`s, which belong to request replies.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuthStatus {
+    Success = 0x00,
+    Failure = 0x01,
+}
+
+/// Build a username/password subnegotiation reply
+pub fn build_auth_reply(status: AuthStatus) -> [u8; 2] {
+    [USERPASS_VERSION, status as u8]
+}
+
+/// Append the ATYP, ADDR and PORT fields for `addr`
+pub(crate) fn write_socket_addr(buf: &mut Vec<u8>, addr: SocketAddr) {
+    match addr {
+        SocketAddr::V4(addr) => {
+            buf.push(AddrType::V4 as u8);
+            buf.extend_from_slice(&addr.ip().octets());
+        },
+        SocketAddr::V6(addr) => {
+            buf.push(AddrType::V6 as u8);
+            buf.extend_from_slice(&addr.ip().octets());
+        }
+    }
+
+    write_u16_be(buf, addr.port());
+}
+
+/// Build a request for `command` to `destination`, for the client side of
+/// the protocol
+///
+/// Fails with `InvalidInput` if `destination` is a domain longer than 255
+/// bytes, which SOCKS5 can't carry.
+pub fn encode_request(command: SockCommand, destination: &Address) -> io::Result<Vec<u8>> {
+    let mut request = vec![SOCKS_VERSION, command as u8, RESERVED];
+    write_address(&mut request, destination)?;
+    Ok(request)
+}
+
+/// Append the ATYP, ADDR and PORT fields for `address`
+fn write_address(buf: &mut Vec<u8>, address: &Address) -> io::Result<()> {
+    match address {
+        Address::Ipv4(ip, port) => write_socket_addr(buf, SocketAddr::from((*ip, *port))),
+        Address::Ipv6(ip, port) => write_socket_addr(buf, SocketAddr::from((*ip, *port))),
+        Address::Domain(domain, port) => {
+            let len = u8::try_from(domain.len()).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "domain too long for SOCKS5"))?;
+            buf.push(AddrType::Domain as u8);
+            buf.push(len);
+            buf.extend_from_slice(domain);
+            write_u16_be(buf, *port);
+        }
+    }
+    Ok(())
+}
+
+/// DST.addr variant types
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddrType {
+    /// IPv4 address
+    V4 = 0x01,
+    /// Domain name
+    Domain = 0x03,
+    /// IPv6 address
+    V6 = 0x04,
+}
+
+impl AddrType {
+    /// Parse Byte to Command
+    pub(crate) fn from(n: usize) -> Option<AddrType> {
+        match n {
+            1 => Some(AddrType::V4),
+            3 => Some(AddrType::Domain),
+            4 => Some(AddrType::V6),
+            _ => None
+        }
+    }
+
+    // /// Return the size of the AddrType
+    // fn size(&self) -> u8 {
+    //     match self {
+    //         AddrType::V4 => 4,
+    //         AddrType::Domain => 1,
+    //         AddrType::V6 => 16
+    //     }
+    // }
+}
+
+impl std::str::FromStr for AddrType {
+    type Err = String;
+
+    /// Parse the names `Address` types are logged under: `ipv4`, `ipv6` or
+    /// `domain`
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "ipv4" => Ok(AddrType::V4),
+            "ipv6" => Ok(AddrType::V6),
+            "domain" => Ok(AddrType::Domain),
+            _ => Err(format!("invalid address type '{}', expected ipv4, ipv6 or domain", name)),
+        }
+    }
+}
+
+/// SOCK5 CMD Type
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SockCommand {
+    Connect = 0x01,
+    Bind = 0x02,
+    UdpAssosiate = 0x3
+}
+
+impl SockCommand {
+    /// Parse Byte to Command
+    pub fn from(n: usize) -> Option<SockCommand> {
+        match n {
+            1 => Some(SockCommand::Connect),
+            2 => Some(SockCommand::Bind),
+            3 => Some(SockCommand::UdpAssosiate),
+            _ => None
+        }
+    }
+}
+
+/// Client greeting: the version and the authentication methods it offers
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Greeting {
+    pub version: u8,
+    pub methods: Vec<u8>,
+}
+
+/// Username/password subnegotiation request (RFC 1929)
+///
+/// Neither field is required to be UTF-8.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Credentials {
+    pub version: u8,
+    pub username: Vec<u8>,
+    pub password: Vec<u8>,
+}
+
+/// Proxy User Request
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Request {
+    pub version: u8,
+    pub command: SockCommand,
+    /// RSV byte, which must be 0. Only checked with `Options::strict_reserved`.
+    pub reserved: u8,
+    pub address: Address,
+}
+
+/// Read a client greeting
+///
+/// Only SOCKS5 greetings are read in full. For any other version nothing past
+/// the version byte is consumed and `UnsupportedVersion` is returned.
+pub fn read_greeting<R: Read>(stream: &mut R) -> Result<Greeting, ProtocolError> {
+    let mut version = [0u8; 1];
+    stream.read_exact(&mut version)?;
+
+    if version[0] != SOCKS_VERSION {
+        return Err(ProtocolError::UnsupportedVersion { version: version[0] });
+    }
+
+    let mut nmethods = [0u8; 1];
+    stream.read_exact(&mut nmethods)?;
+
+    Ok(Greeting {
+        version: version[0],
+        methods: read_methods(stream, nmethods[0])?,
+    })
+}
+
+/// Read the `nmethods` method bytes following the greeting header
+pub fn read_methods<R: Read>(stream: &mut R, nmethods: u8) -> io::Result<Vec<u8>> {
+    let mut methods = vec![0u8; nmethods as usize];
+    stream.read_exact(&mut methods)?;
+    Ok(methods)
+}
+
+/// Read a username/password subnegotiation request
+pub fn read_credentials<R: Read + ?Sized>(stream: &mut R) -> io::Result<Credentials> {
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header)?;
+
+    // Username parsing
+    let mut username = vec![0; header[1] as usize];
+    stream.read_exact(&mut username)?;
+
+    // Password Parsing
+    let mut plen = [0u8; 1];
+    stream.read_exact(&mut plen)?;
+
+    let mut password = vec![0; plen[0] as usize];
+    stream.read_exact(&mut password)?;
+
+    Ok(Credentials {
+        version: header[0],
+        username,
+        password,
+    })
+}
+
+/// Read a request
+///
+/// Parsing stops at the first invalid field, leaving the rest of the request
+/// unread.
+pub fn read_request<R: Read>(stream: &mut R) -> Result<Request, ProtocolError> {
+    let mut packet = [0u8; 4];
+    stream.read_exact(&mut packet)?;
+
+    if packet[0] != SOCKS_VERSION {
+        return Err(ProtocolError::UnsupportedVersion { version: packet[0] });
+    }
+
+    let command = SockCommand::from(packet[1] as usize)
+        .ok_or(ProtocolError::UnsupportedCommand { command: packet[1] })?;
+
+    // DST.address
+    let addr_type = AddrType::from(packet[3] as usize)
+        .ok_or(ProtocolError::UnsupportedAddrType { addr_type: packet[3] })?;
+
+    trace!("Getting Addr");
+    // Get Addr from addr_type and stream
+    let address = read_address(stream, addr_type)?;
+
+    Ok(Request {
+        version: packet[0],
+        command,
+        reserved: packet[2],
+        address,
+    })
+}
+
+/// Parse a request from the start of `bytes`, returning it along with the
+/// number of bytes it took up
+///
+/// Returns `Incomplete` if `bytes` ends before the request does, in which case
+/// it can be parsed again once more bytes arrived. Other errors mean the
+/// request is malformed.
+pub fn parse_request(bytes: &[u8]) -> Result<(Request, usize), ProtocolError> {
+    let mut rest = bytes;
+    match read_request(&mut rest) {
+        Ok(request) => Ok((request, bytes.len() - rest.len())),
+        Err(ProtocolError::Io { source }) if source.kind() == io::ErrorKind::UnexpectedEof => Err(ProtocolError::Incomplete),
+        Err(e) => Err(e),
+    }
+}
+
+/// Read a request through a buffer, returning it along with any bytes read
+/// past its end
+///
+/// `read_request` reads each field on its own, which is 3 reads for an IP
+/// address and 4 for a domain, each a syscall on a socket. Buffered, a request
+/// that arrived in one segment takes a single read. The flip side is that
+/// anything the client sent right behind the request, like the first data of
+/// a CONNECT, may be read too; it's returned so it can be passed on.
+pub fn read_request_buffered<R: Read>(stream: &mut R) -> Result<(Request, Vec<u8>), ProtocolError> {
+    let mut reader = BufReader::with_capacity(MAX_REQUEST_LEN, stream);
+    let request = read_request(&mut reader)?;
+    Ok((request, reader.buffer().to_vec()))
+}
+
+/// Read DST.addr and DST.port of type `addr_type` from the stream
+pub(crate) fn read_address<T: Read>(stream: &mut T, addr_type: AddrType) -> io::Result<Address> {
+    match addr_type {
+        AddrType::Domain => {
+            let mut dlen = [0u8; 1];
+            stream.read_exact(&mut dlen)?;
+
+            let mut domain = vec![0u8; dlen[0] as usize];
+            stream.read_exact(&mut domain)?;
+
+            Ok(Address::Domain(domain, read_u16_be(stream)?))
+        },
+        AddrType::V4 => {
+            let mut addr = [0u8; 4];
+            stream.read_exact(&mut addr)?;
+            Ok(Address::Ipv4(Ipv4Addr::from(addr), read_u16_be(stream)?))
+        },
+        AddrType::V6 => {
+            let mut addr = [0u8; 16];
+            stream.read_exact(&mut addr)?;
+            Ok(Address::Ipv6(Ipv6Addr::from(addr), read_u16_be(stream)?))
+        }
+    }
+}
+
+/// Read a `u16` in network (big-endian) order, like DST.PORT
+pub(crate) fn read_u16_be<T: Read>(stream: &mut T) -> io::Result<u16> {
+    let mut bytes = [0u8; 2];
+    stream.read_exact(&mut bytes)?;
+    Ok(u16::from_be_bytes(bytes))
+}
+
+/// Append a `u16` in network (big-endian) order, like BND.PORT
+pub(crate) fn write_u16_be(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Counts the reads made on the bytes it wraps
+    struct CountingReader<'a> {
+        bytes: &'a [u8],
+        reads: usize,
+    }
+
+    impl Read for CountingReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.reads += 1;
+            self.bytes.read(buf)
+        }
+    }
+
+    #[test]
+    fn request_buffered() {
+        let mut bytes = vec![5, 1, 0, 3, 11];
+        bytes.extend_from_slice(b"example.com");
+        bytes.extend_from_slice(&[0, 80]);
+        bytes.extend_from_slice(b"GET /");
+
+        let mut reader = CountingReader { bytes: &bytes, reads: 0 };
+        read_request(&mut reader).unwrap();
+        assert_eq!(reader.reads, 4);
+
+        let mut reader = CountingReader { bytes: &bytes, reads: 0 };
+        let (request, rest) = read_request_buffered(&mut reader).unwrap();
+        assert_eq!(reader.reads, 1);
+        assert_eq!(request.address, Address::Domain(b"example.com".to_vec(), 80));
+        assert_eq!(rest, b"GET /");
+    }
+
+    #[test]
+    fn addr_type_names() {
+        for address in &[Address::Ipv4(Ipv4Addr::LOCALHOST, 80), Address::Ipv6(Ipv6Addr::LOCALHOST, 80), Address::Domain(b"example.com".to_vec(), 80)] {
+            assert_eq!(address.type_name().parse::<AddrType>(), Ok(address.addr_type()));
+        }
+        assert!("ip".parse::<AddrType>().is_err());
+    }
+
+    #[test]
+    fn u16_be() {
+        let mut buf = Vec::new();
+        write_u16_be(&mut buf, 8080);
+        assert_eq!(buf, vec![0x1F, 0x90]);
+        assert_eq!(read_u16_be(&mut &buf[..]).unwrap(), 8080);
+
+        // Port 0x0100 is 256, not 1
+        assert_eq!(read_u16_be(&mut &[1, 0][..]).unwrap(), 256);
+        assert!(read_u16_be(&mut &[1][..]).is_err());
+    }
+
+    #[test]
+    fn greeting() {
+        let mut bytes = &[5, 2, 0, 2, 0xAA][..];
+        let greeting = read_greeting(&mut bytes).unwrap();
+
+        assert_eq!(greeting, Greeting { version: 5, methods: vec![0, 2] });
+        assert_eq!(bytes, &[0xAA]);
+    }
+
+    #[test]
+    fn auth_reply() {
+        assert_eq!(build_auth_reply(AuthStatus::Success), [1, 0]);
+        assert_eq!(build_auth_reply(AuthStatus::Failure), [1, 1]);
+    }
+
+    #[test]
+    fn greeting_unsupported_version() {
+        let mut bytes = &[4, 1, 0][..];
+        match read_greeting(&mut bytes) {
+            Err(ProtocolError::UnsupportedVersion { version: 4 }) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(bytes, &[1, 0]);
+    }
+
+    #[test]
+    fn credentials() {
+        let mut bytes = &[1, 3, b'b', b'o', b'b', 2, 0xFF, b'x'][..];
+        let credentials = read_credentials(&mut bytes).unwrap();
+
+        assert_eq!(credentials.version, 1);
+        assert_eq!(credentials.username, b"bob");
+        assert_eq!(credentials.password, vec![0xFF, b'x']);
+        assert!(bytes.is_empty());
+    }
+
+    #[test]
+    fn request_domain() {
+        let mut bytes = &[5, 3, 0, 3, 4, b'h', b'o', b's', b't', 0x1F, 0x90][..];
+        let request = read_request(&mut bytes).unwrap();
+
+        assert_eq!(request.command, SockCommand::UdpAssosiate);
+        assert_eq!(request.address, Address::Domain(b"host".to_vec(), 8080));
+        assert!(bytes.is_empty());
+    }
+
+    #[test]
+    fn request_v6() {
+        let mut bytes = vec![5, 2, 0, 4];
+        bytes.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        bytes.extend_from_slice(&[0, 21]);
+
+        let request = read_request(&mut &bytes[..]).unwrap();
+        assert_eq!(request.command, SockCommand::Bind);
+        assert_eq!(request.address, Address::Ipv6(Ipv6Addr::LOCALHOST, 21));
+    }
+
+    #[test]
+    fn request_invalid() {
+        let code = |bytes: &[u8]| read_request(&mut &bytes[..]).unwrap_err().response_code();
+
+        assert_eq!(code(&[5, 9, 0, 1, 127, 0, 0, 1, 0, 80]), ResponseCode::CommandNotSupported);
+        assert_eq!(code(&[5, 1, 0, 2, 127, 0, 0, 1, 0, 80]), ResponseCode::AddrTypeNotSupported);
+        assert_eq!(code(&[4, 1, 0, 1, 127, 0, 0, 1, 0, 80]), ResponseCode::Failure);
+        assert_eq!(code(&[5, 1, 0, 1, 127, 0]), ResponseCode::Failure);
+    }
+
+    #[test]
+    fn parse_each_addr_type() {
+        let mut v4 = vec![5, 1, 0, 1, 192, 0, 2, 1, 0, 80];
+        let mut domain = vec![5, 1, 0, 3, 11];
+        domain.extend_from_slice(b"example.com");
+        domain.extend_from_slice(&[1, 187]);
+        let mut v6 = vec![5, 1, 0, 4];
+        v6.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        v6.extend_from_slice(&[0, 22]);
+
+        let cases = [
+            (&mut v4, Address::Ipv4(Ipv4Addr::new(192, 0, 2, 1), 80)),
+            (&mut domain, Address::Domain(b"example.com".to_vec(), 443)),
+            (&mut v6, Address::Ipv6(Ipv6Addr::LOCALHOST, 22)),
+        ];
+        for (bytes, address) in cases {
+            let len = bytes.len();
+            // Anything after the request is left alone
+            bytes.extend_from_slice(b"data");
+            let (request, consumed) = parse_request(bytes).unwrap();
+            assert_eq!(request.address, address);
+            assert_eq!(consumed, len);
+        }
+    }
+
+    #[test]
+    fn encode_round_trip() {
+        let destinations = [
+            Address::Ipv4(Ipv4Addr::new(192, 0, 2, 1), 80),
+            Address::Domain(b"example.com".to_vec(), 443),
+            Address::Ipv6(Ipv6Addr::LOCALHOST, 8080),
+        ];
+        for destination in destinations {
+            for command in [SockCommand::Connect, SockCommand::Bind, SockCommand::UdpAssosiate] {
+                let bytes = encode_request(command, &destination).unwrap();
+                let (request, consumed) = parse_request(&bytes).unwrap();
+                assert_eq!(request, Request { version: SOCKS_VERSION, command, reserved: RESERVED, address: destination.clone() });
+                assert_eq!(consumed, bytes.len());
+            }
+        }
+
+        // Ports are big-endian
+        assert_eq!(encode_request(SockCommand::Connect, &Address::Ipv4(Ipv4Addr::LOCALHOST, 0x1F90)).unwrap(), vec![5, 1, 0, 1, 127, 0, 0, 1, 0x1F, 0x90]);
+    }
+
+    #[test]
+    fn encode_long_domain() {
+        let err = encode_request(SockCommand::Connect, &Address::Domain(vec![b'a'; 256], 80)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(encode_request(SockCommand::Connect, &Address::Domain(vec![b'a'; 255], 80)).is_ok());
+    }
+
+    #[test]
+    fn parse_incomplete() {
+        let mut bytes = vec![5, 1, 0, 3, 11];
+        bytes.extend_from_slice(b"example.com");
+        bytes.extend_from_slice(&[0, 80]);
+
+        // Every prefix needs more bytes, including an empty buffer
+        for len in 0..bytes.len() {
+            match parse_request(&bytes[..len]) {
+                Err(ProtocolError::Incomplete) => {},
+                other => panic!("unexpected result for {} bytes: {:?}", len, other),
+            }
+        }
+        assert_eq!(parse_request(&bytes).unwrap().1, bytes.len());
+
+        // A malformed request is reported as soon as it's seen
+        match parse_request(&[5, 1, 0, 2]) {
+            Err(ProtocolError::UnsupportedAddrType { addr_type: 2 }) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn address_parts() {
+        let v6 = Address::Ipv6("2001:db8:0:0:0:0:0:1".parse().unwrap(), 443);
+        assert_eq!((v6.host_only(), v6.port()), ("2001:db8::1".to_string(), 443));
+        assert_eq!(v6.to_string(), "[2001:db8::1]:443");
+
+        let domain = Address::Domain(b"example.com".to_vec(), 80);
+        assert_eq!((domain.host_only(), domain.port()), ("example.com".to_string(), 80));
+        assert_eq!(domain.to_string(), "example.com:80");
+
+        let invalid = Address::Domain(b"bad\xff.example".to_vec(), 80);
+        assert_eq!(invalid.host_only(), "bad\\xff.example");
+        assert_eq!(invalid.to_string(), "bad\\xff.example:80");
+    }
+}
@@ -0,0 +1,233 @@
+//! PROXY protocol headers (https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt)
+use std::io::{self, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use crate::protocol::{read_u16_be, write_u16_be};
+
+/// Signature starting every version 2 header
+const V2_SIGNATURE: [u8; 12] = [0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A];
+
+/// Longest possible version 1 header, including the CRLF
+const V1_MAX_LEN: usize = 107;
+
+/// PROXY protocol version
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProxyProtocolVersion {
+    /// Human readable text header
+    V1,
+    /// Binary header
+    V2,
+}
+
+/// Send a PROXY protocol header to CONNECT targets, carrying the client's
+/// address
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProxyProtocol {
+    pub version: ProxyProtocolVersion,
+    /// Targets to send the header to. Anything else gets the client's bytes
+    /// untouched, as a server that doesn't expect the header would choke on it.
+    pub destinations: Vec<SocketAddr>,
+}
+
+/// Build a header for a connection from `source` to `dest`
+///
+/// If the families differ, IPv4 addresses are sent as IPv4-mapped IPv6.
+pub(crate) fn encode(version: ProxyProtocolVersion, source: SocketAddr, dest: SocketAddr) -> Vec<u8> {
+    let (source, dest) = match (source, dest) {
+        (SocketAddr::V4(_), SocketAddr::V4(_)) | (SocketAddr::V6(_), SocketAddr::V6(_)) => (source, dest),
+        _ => (to_v6(source), to_v6(dest)),
+    };
+
+    match version {
+        ProxyProtocolVersion::V1 => {
+            let family = if source.is_ipv4() { "TCP4" } else { "TCP6" };
+            format!("PROXY {} {} {} {} {}\r\n", family, source.ip(), dest.ip(), source.port(), dest.port()).into_bytes()
+        },
+        ProxyProtocolVersion::V2 => {
+            let mut header = V2_SIGNATURE.to_vec();
+            // Version 2, PROXY command
+            header.push(0x21);
+
+            match (source, dest) {
+                (SocketAddr::V4(source), SocketAddr::V4(dest)) => {
+                    // TCP over IPv4
+                    header.push(0x11);
+                    write_u16_be(&mut header, 12);
+                    header.extend_from_slice(&source.ip().octets());
+                    header.extend_from_slice(&dest.ip().octets());
+                },
+                (SocketAddr::V6(source), SocketAddr::V6(dest)) => {
+                    // TCP over IPv6
+                    header.push(0x21);
+                    write_u16_be(&mut header, 36);
+                    header.extend_from_slice(&source.ip().octets());
+                    header.extend_from_slice(&dest.ip().octets());
+                },
+                _ => unreachable!("families were matched above"),
+            }
+
+            write_u16_be(&mut header, source.port());
+            write_u16_be(&mut header, dest.port());
+            header
+        }
+    }
+}
+
+/// Read a version 1 or 2 header, returning the source address it carries
+///
+/// Exactly the header is consumed, so the stream can be handed on. Headers
+/// without an address (`UNKNOWN`, `LOCAL` or a non-IP family) return `None`.
+pub(crate) fn read_header<R: Read>(stream: &mut R) -> io::Result<Option<SocketAddr>> {
+    // Both versions are at least this long
+    let mut header = vec![0u8; V2_SIGNATURE.len()];
+    stream.read_exact(&mut header)?;
+
+    if header == V2_SIGNATURE {
+        return read_v2(stream);
+    }
+
+    if !header.starts_with(b"PROXY ") {
+        return Err(invalid("missing PROXY protocol header"));
+    }
+
+    // Read up to the CRLF a byte at a time, so nothing past it is consumed
+    while !header.ends_with(b"\r\n") {
+        if header.len() >= V1_MAX_LEN {
+            return Err(invalid("PROXY protocol header too long"));
+        }
+        let mut byte = [0u8; 1];
+        stream.read_exact(&mut byte)?;
+        header.push(byte[0]);
+    }
+
+    let line = std::str::from_utf8(&header[..header.len() - 2]).map_err(|_| invalid("PROXY protocol header is not ASCII"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), source, _, port, _] => {
+            let ip: IpAddr = source.parse().map_err(|_| invalid("invalid PROXY protocol source address"))?;
+            if ip.is_ipv4() != (family == "TCP4") {
+                return Err(invalid("PROXY protocol source address doesn't match its family"));
+            }
+            let port = port.parse().map_err(|_| invalid("invalid PROXY protocol source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        },
+        _ => Err(invalid("malformed PROXY protocol header")),
+    }
+}
+
+/// Read the rest of a version 2 header, after the signature
+fn read_v2<R: Read>(stream: &mut R) -> io::Result<Option<SocketAddr>> {
+    let mut fixed = [0u8; 4];
+    stream.read_exact(&mut fixed)?;
+
+    if fixed[0] >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+
+    let mut addresses = vec![0u8; read_u16_be(&mut &fixed[2..])? as usize];
+    stream.read_exact(&mut addresses)?;
+
+    match fixed[0] & 0x0F {
+        // LOCAL connections (health checks etc.) carry no client
+        0 => return Ok(None),
+        // PROXY
+        1 => {},
+        _ => return Err(invalid("unsupported PROXY protocol command")),
+    }
+
+    match fixed[1] >> 4 {
+        // AF_INET
+        1 if addresses.len() >= 12 => {
+            let mut ip = [0u8; 4];
+            ip.copy_from_slice(&addresses[..4]);
+            Ok(Some(SocketAddr::new(Ipv4Addr::from(ip).into(), read_u16_be(&mut &addresses[8..])?)))
+        },
+        // AF_INET6
+        2 if addresses.len() >= 36 => {
+            let mut ip = [0u8; 16];
+            ip.copy_from_slice(&addresses[..16]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), read_u16_be(&mut &addresses[32..])?)))
+        },
+        1 | 2 => Err(invalid("PROXY protocol address block too short")),
+        _ => Ok(None),
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn to_v6(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(v4) => SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port()),
+        SocketAddr::V6(_) => addr,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_v1() {
+        let header = encode(ProxyProtocolVersion::V1, "192.0.2.1:50000".parse().unwrap(), "127.0.0.1:1080".parse().unwrap());
+        assert_eq!(header, b"PROXY TCP4 192.0.2.1 127.0.0.1 50000 1080\r\n");
+
+        let header = encode(ProxyProtocolVersion::V1, "[2001:db8::1]:50000".parse().unwrap(), "127.0.0.1:1080".parse().unwrap());
+        assert_eq!(header, b"PROXY TCP6 2001:db8::1 ::ffff:127.0.0.1 50000 1080\r\n");
+    }
+
+    #[test]
+    fn encode_v2() {
+        let header = encode(ProxyProtocolVersion::V2, "192.0.2.1:50000".parse().unwrap(), "127.0.0.1:1080".parse().unwrap());
+
+        let mut expected = V2_SIGNATURE.to_vec();
+        expected.extend_from_slice(&[0x21, 0x11, 0, 12, 192, 0, 2, 1, 127, 0, 0, 1, 0xC3, 0x50, 0x04, 0x38]);
+        assert_eq!(header, expected);
+
+        let header = encode(ProxyProtocolVersion::V2, "[::1]:1".parse().unwrap(), "[::2]:2".parse().unwrap());
+        assert_eq!(header.len(), 16 + 36);
+        assert_eq!(header[13..16], [0x21, 0, 36]);
+    }
+
+    #[test]
+    fn read_round_trip() {
+        let source: SocketAddr = "192.0.2.1:50000".parse().unwrap();
+        let dest: SocketAddr = "[2001:db8::1]:1080".parse().unwrap();
+
+        for version in [ProxyProtocolVersion::V1, ProxyProtocolVersion::V2] {
+            let mut bytes = encode(version, source, dest);
+            bytes.extend_from_slice(&[5, 1, 0]);
+
+            let mut rest = &bytes[..];
+            assert_eq!(read_header(&mut rest).unwrap(), Some(SocketAddr::new(Ipv4Addr::new(192, 0, 2, 1).to_ipv6_mapped().into(), 50000)));
+            assert_eq!(rest, &[5, 1, 0]);
+        }
+    }
+
+    #[test]
+    fn read_without_address() {
+        assert_eq!(read_header(&mut &b"PROXY UNKNOWN\r\n"[..]).unwrap(), None);
+
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        assert_eq!(read_header(&mut &local[..]).unwrap(), None);
+    }
+
+    #[test]
+    fn read_invalid() {
+        assert!(read_header(&mut &[5, 1, 0, 5, 1, 0, 1, 127, 0, 0, 1, 0, 80][..]).is_err());
+        assert!(read_header(&mut &b"PROXY TCP4 nonsense\r\n"[..]).is_err());
+        assert!(read_header(&mut &[b"PROXY TCP4 ".to_vec(), vec![b'1'; 200]].concat()[..]).is_err());
+
+        // Addresses of the other family than the header says
+        assert!(read_header(&mut &b"PROXY TCP4 2001:db8::1 ::1 50000 1080\r\n"[..]).is_err());
+        assert!(read_header(&mut &b"PROXY TCP6 192.0.2.1 127.0.0.1 50000 1080\r\n"[..]).is_err());
+
+        // Commands other than LOCAL and PROXY
+        let mut header = encode(ProxyProtocolVersion::V2, "192.0.2.1:50000".parse().unwrap(), "127.0.0.1:1080".parse().unwrap());
+        header[12] = 0x22;
+        assert!(read_header(&mut &header[..]).is_err());
+    }
+}
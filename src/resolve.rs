@@ -0,0 +1,162 @@
+//! Name resolution for domain targets
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Resolves the domain names clients ask to connect to
+///
+/// Install one with `Options::resolver`.
+pub trait Resolver: Send + Sync {
+    /// Resolve `host` to the addresses to try for `port`
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>>;
+}
+
+/// Resolves names through the OS, like `ToSocketAddrs`
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        Ok((host, port).to_socket_addrs()?.collect())
+    }
+}
+
+/// Caches the results of another `Resolver`
+///
+/// SOCKS carries no DNS TTLs, so every entry lives for the same fixed `ttl`.
+/// Once `capacity` names are cached the least recently used one is evicted.
+/// Failed lookups aren't cached.
+pub struct CachingResolver<R: Resolver> {
+    inner: R,
+    ttl: Duration,
+    capacity: usize,
+    cache: Mutex<Cache>,
+}
+
+#[derive(Default)]
+struct Cache {
+    entries: HashMap<String, Entry>,
+    /// Incremented on every lookup, to order entries by last use
+    clock: u64,
+}
+
+struct Entry {
+    ips: Vec<IpAddr>,
+    expires: Instant,
+    last_used: u64,
+}
+
+impl<R: Resolver> CachingResolver<R> {
+    /// Cache up to `capacity` names resolved by `inner` for `ttl` each
+    pub fn new(inner: R, ttl: Duration, capacity: usize) -> Self {
+        CachingResolver {
+            inner,
+            ttl,
+            capacity,
+            cache: Mutex::new(Cache::default()),
+        }
+    }
+}
+
+impl<R: Resolver> Resolver for CachingResolver<R> {
+    fn resolve(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        let now = Instant::now();
+
+        {
+            let mut cache = self.cache.lock().unwrap();
+            cache.clock += 1;
+            let clock = cache.clock;
+
+            if let Some(entry) = cache.entries.get_mut(host) {
+                if entry.expires > now {
+                    entry.last_used = clock;
+                    return Ok(entry.ips.iter().map(|ip| SocketAddr::new(*ip, port)).collect());
+                }
+            }
+        }
+
+        // Resolve without holding the lock, so other names aren't held up
+        let addrs = self.inner.resolve(host, port)?;
+        if self.capacity == 0 {
+            return Ok(addrs);
+        }
+
+        let mut cache = self.cache.lock().unwrap();
+        cache.entries.retain(|_, entry| entry.expires > now);
+        if cache.entries.len() >= self.capacity && !cache.entries.contains_key(host) {
+            let lru = cache.entries.iter().min_by_key(|(_, entry)| entry.last_used).map(|(host, _)| host.clone());
+            if let Some(lru) = lru {
+                cache.entries.remove(&lru);
+            }
+        }
+
+        let last_used = cache.clock;
+        cache.entries.insert(host.to_string(), Entry {
+            ips: addrs.iter().map(SocketAddr::ip).collect(),
+            expires: now + self.ttl,
+            last_used,
+        });
+
+        Ok(addrs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Resolves every name to 192.0.2.1, counting lookups
+    #[derive(Default)]
+    struct CountingResolver {
+        lookups: AtomicUsize,
+    }
+
+    impl Resolver for &CountingResolver {
+        fn resolve(&self, _host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            Ok(vec![SocketAddr::new(Ipv4Addr::new(192, 0, 2, 1).into(), port)])
+        }
+    }
+
+    #[test]
+    fn caches_lookups() {
+        let inner = CountingResolver::default();
+        let resolver = CachingResolver::new(&inner, Duration::from_secs(60), 16);
+
+        assert_eq!(resolver.resolve("example.com", 80).unwrap(), vec!["192.0.2.1:80".parse().unwrap()]);
+        assert_eq!(resolver.resolve("example.com", 443).unwrap(), vec!["192.0.2.1:443".parse().unwrap()]);
+        assert_eq!(inner.lookups.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn expires_entries() {
+        let inner = CountingResolver::default();
+        let resolver = CachingResolver::new(&inner, Duration::from_millis(0), 16);
+
+        resolver.resolve("example.com", 80).unwrap();
+        resolver.resolve("example.com", 80).unwrap();
+        assert_eq!(inner.lookups.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let inner = CountingResolver::default();
+        let resolver = CachingResolver::new(&inner, Duration::from_secs(60), 2);
+
+        resolver.resolve("a.example", 80).unwrap();
+        resolver.resolve("b.example", 80).unwrap();
+        resolver.resolve("a.example", 80).unwrap();
+        // Evicts b, which was used longest ago
+        resolver.resolve("c.example", 80).unwrap();
+        assert_eq!(inner.lookups.load(Ordering::SeqCst), 3);
+
+        resolver.resolve("a.example", 80).unwrap();
+        assert_eq!(inner.lookups.load(Ordering::SeqCst), 3);
+        resolver.resolve("b.example", 80).unwrap();
+        assert_eq!(inner.lookups.load(Ordering::SeqCst), 4);
+    }
+}
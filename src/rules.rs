@@ -0,0 +1,281 @@
+//! Destination rules for CONNECT requests
+use snafu::Snafu;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// What to do with a destination
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Policy {
+    Allow,
+    Deny,
+}
+
+/// Destinations a `Rule` applies to
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RuleTarget {
+    /// IP addresses in a network, given as an address and prefix length
+    Cidr(IpAddr, u8),
+    /// A domain name and all of its subdomains, matched case-insensitively
+    Domain(String),
+    /// Domain names matching a regular expression, see `DomainPattern`
+    #[cfg(feature = "regex")]
+    Pattern(DomainPattern),
+}
+
+/// Regular expression matched against the whole of a requested domain name,
+/// case-insensitively
+///
+/// Pattern rules are a category of their own: they're checked before every
+/// other rule, by name alone, so a denied name isn't even looked up. Every
+/// pattern rule is tried on every domain request, which is much slower than
+/// the other rules, so prefer `RuleTarget::Domain` where a suffix will do.
+#[cfg(feature = "regex")]
+#[derive(Clone, Debug)]
+pub struct DomainPattern(regex::Regex);
+
+#[cfg(feature = "regex")]
+impl DomainPattern {
+    /// Compile `pattern`, which has to match the whole domain name
+    pub fn new(pattern: &str) -> Result<Self, regex::Error> {
+        regex::Regex::new(&format!("(?i)^(?:{})$", pattern)).map(DomainPattern)
+    }
+
+    fn is_match(&self, host: &str) -> bool {
+        self.0.is_match(host.trim_end_matches('.'))
+    }
+}
+
+#[cfg(feature = "regex")]
+impl PartialEq for DomainPattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+#[cfg(feature = "regex")]
+impl Eq for DomainPattern {}
+
+/// Prefix marking a `RuleTarget` as a `DomainPattern`
+const PATTERN_PREFIX: &str = "regex:";
+
+/// Error parsing a `RuleTarget`
+#[derive(Debug, Snafu)]
+pub enum RuleError {
+    #[snafu(display("invalid prefix length in {}", target))]
+    InvalidPrefix { target: String },
+    #[snafu(display("invalid network address in {}", target))]
+    InvalidNetwork { target: String },
+    #[snafu(display("invalid regular expression in {}: {}", target, message))]
+    InvalidPattern { target: String, message: String },
+    #[snafu(display("{} needs merino built with the regex feature", target))]
+    PatternUnsupported { target: String },
+}
+
+impl FromStr for RuleTarget {
+    type Err = RuleError;
+
+    /// Parse a CIDR (`10.0.0.0/8`), a single IP address, a domain name or a
+    /// `regex:` prefixed domain pattern (`regex:ads-.*`)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(pattern) = s.strip_prefix(PATTERN_PREFIX) {
+            #[cfg(feature = "regex")]
+            return DomainPattern::new(pattern)
+                .map(RuleTarget::Pattern)
+                .map_err(|e| RuleError::InvalidPattern { target: s.to_string(), message: e.to_string() });
+
+            #[cfg(not(feature = "regex"))]
+            return Err(RuleError::PatternUnsupported { target: pattern.to_string() });
+        }
+
+        if let Some((network, prefix)) = s.split_once('/') {
+            let network: IpAddr = network.parse().map_err(|_| RuleError::InvalidNetwork { target: s.to_string() })?;
+            let max = if network.is_ipv4() { 32 } else { 128 };
+            match prefix.parse() {
+                Ok(prefix) if prefix <= max => Ok(RuleTarget::Cidr(network, prefix)),
+                _ => Err(RuleError::InvalidPrefix { target: s.to_string() }),
+            }
+        }
+        else if let Ok(ip) = s.parse::<IpAddr>() {
+            Ok(RuleTarget::Cidr(ip, if ip.is_ipv4() { 32 } else { 128 }))
+        }
+        else {
+            Ok(RuleTarget::Domain(s.trim_end_matches('.').to_ascii_lowercase()))
+        }
+    }
+}
+
+impl RuleTarget {
+    /// Whether a connection to `ip`, requested as `host` if it was a domain
+    /// name, falls under the target
+    fn matches(&self, host: Option<&str>, ip: IpAddr) -> bool {
+        match self {
+            RuleTarget::Cidr(network, prefix) => in_network(ip.to_canonical(), *network, *prefix),
+            RuleTarget::Domain(domain) => host.is_some_and(|host| {
+                let host = host.trim_end_matches('.').to_ascii_lowercase();
+                host == *domain || host.strip_suffix(domain.as_str()).is_some_and(|sub| sub.ends_with('.'))
+            }),
+            #[cfg(feature = "regex")]
+            RuleTarget::Pattern(pattern) => host.is_some_and(|host| pattern.is_match(host)),
+        }
+    }
+
+    /// Whether the target is a `DomainPattern`, checked by name alone
+    fn is_pattern(&self) -> bool {
+        match self {
+            #[cfg(feature = "regex")]
+            RuleTarget::Pattern(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Whether the target is a `DomainPattern` matching `host`
+    fn matches_name(&self, #[allow(unused_variables)] host: &str) -> bool {
+        match self {
+            #[cfg(feature = "regex")]
+            RuleTarget::Pattern(pattern) => pattern.is_match(host),
+            _ => false,
+        }
+    }
+}
+
+/// A policy for the destinations matching a target
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rule {
+    pub policy: Policy,
+    pub target: RuleTarget,
+}
+
+/// Ordered rules deciding which destinations CONNECT may reach
+///
+/// The first rule matching a destination decides, `default_policy` applies if
+/// none do. A domain name is matched against domain rules by name and against
+/// CIDR rules by each address it resolves to. Only the allowed addresses are
+/// connected to, so a domain can't be used to reach a denied network. Pattern
+/// rules (`RuleTarget::Pattern`) are tried before all the others, on the name
+/// alone, so they decide before it's resolved.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RuleSet {
+    pub rules: Vec<Rule>,
+    pub default_policy: Policy,
+}
+
+impl Default for RuleSet {
+    /// Allow everything
+    fn default() -> Self {
+        RuleSet {
+            rules: Vec::new(),
+            default_policy: Policy::Allow,
+        }
+    }
+}
+
+impl RuleSet {
+    /// Whether a connection to `ip`, requested as `host` if it was a domain
+    /// name, is allowed
+    pub fn allows(&self, host: Option<&str>, ip: IpAddr) -> bool {
+        let policy = host.and_then(|host| self.policy_by_name(host))
+            .or_else(|| self.rules.iter()
+                .filter(|rule| !rule.target.is_pattern())
+                .find(|rule| rule.target.matches(host, ip))
+                .map(|rule| rule.policy))
+            .unwrap_or(self.default_policy);
+        policy == Policy::Allow
+    }
+
+    /// Policy of the first pattern rule matching `host`, which decides
+    /// before the name is resolved. `None` if no pattern rule matches.
+    pub fn policy_by_name(&self, host: &str) -> Option<Policy> {
+        self.rules.iter()
+            .find(|rule| rule.target.matches_name(host))
+            .map(|rule| rule.policy)
+    }
+}
+
+fn in_network(ip: IpAddr, network: IpAddr, prefix: u8) -> bool {
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(network) & mask
+        },
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(network) & mask
+        },
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(policy: Policy, target: &str) -> Rule {
+        Rule { policy, target: target.parse().unwrap() }
+    }
+
+    #[test]
+    fn parse_targets() {
+        assert_eq!("10.0.0.0/8".parse::<RuleTarget>().unwrap(), RuleTarget::Cidr("10.0.0.0".parse().unwrap(), 8));
+        assert_eq!("::1".parse::<RuleTarget>().unwrap(), RuleTarget::Cidr("::1".parse().unwrap(), 128));
+        assert_eq!("Example.COM.".parse::<RuleTarget>().unwrap(), RuleTarget::Domain("example.com".to_string()));
+        assert!("10.0.0.0/33".parse::<RuleTarget>().is_err());
+        assert!("example.com/8".parse::<RuleTarget>().is_err());
+    }
+
+    #[test]
+    fn first_match_wins() {
+        let rules = RuleSet {
+            rules: vec![rule(Policy::Deny, "10.1.0.0/16"), rule(Policy::Allow, "10.0.0.0/8"), rule(Policy::Allow, "example.com")],
+            default_policy: Policy::Deny,
+        };
+
+        assert!(rules.allows(None, "10.2.3.4".parse().unwrap()));
+        assert!(!rules.allows(None, "10.1.3.4".parse().unwrap()));
+        assert!(rules.allows(None, "::ffff:10.2.3.4".parse().unwrap()));
+        assert!(rules.allows(Some("www.Example.com"), "192.0.2.1".parse().unwrap()));
+        assert!(!rules.allows(Some("badexample.com"), "192.0.2.1".parse().unwrap()));
+        // The network rule applies to the addresses a domain resolves to
+        assert!(!rules.allows(Some("example.com"), "10.1.0.1".parse().unwrap()));
+    }
+
+    #[cfg(feature = "regex")]
+    #[test]
+    fn patterns() {
+        assert_eq!("regex:ads-.*".parse::<RuleTarget>().unwrap(), RuleTarget::Pattern(DomainPattern::new("ads-.*").unwrap()));
+        assert!("regex:ads-(".parse::<RuleTarget>().is_err());
+
+        let rules = RuleSet {
+            rules: vec![rule(Policy::Allow, "ads-ok.example.com"), rule(Policy::Deny, "regex:ads-.*"), rule(Policy::Allow, "regex:ads-ok\\..*")],
+            default_policy: Policy::Allow,
+        };
+        let ip = "192.0.2.1".parse().unwrap();
+
+        // Matched against the whole name, case-insensitively
+        assert!(!rules.allows(Some("ads-1.example.com"), ip));
+        assert!(!rules.allows(Some("ADS-1.example.com."), ip));
+        assert!(rules.allows(Some("www.ads-1.example.com"), ip));
+        assert_eq!(rules.policy_by_name("ads-1.example.com"), Some(Policy::Deny));
+        assert_eq!(rules.policy_by_name("example.com"), None);
+
+        // Patterns come before the other rules, then the first one matching wins
+        assert!(!rules.allows(Some("ads-ok.example.com"), ip));
+        // IP address requests have no name to match
+        assert!(rules.allows(None, ip));
+    }
+
+    #[cfg(not(feature = "regex"))]
+    #[test]
+    fn patterns_unsupported() {
+        assert!("regex:ads-.*".parse::<RuleTarget>().is_err());
+    }
+
+    #[test]
+    fn deny_by_default() {
+        let rules = RuleSet { rules: Vec::new(), default_policy: Policy::Deny };
+        assert!(!rules.allows(None, "192.0.2.1".parse().unwrap()));
+        assert!(!rules.allows(None, "::1".parse().unwrap()));
+        assert!(!rules.allows(Some("example.com"), "192.0.2.1".parse().unwrap()));
+
+        assert!(RuleSet::default().allows(None, "192.0.2.1".parse().unwrap()));
+    }
+}
@@ -0,0 +1,203 @@
+//! In-memory test harness for the protocol layer
+//!
+//! [`duplex`] returns a connected pair of [`MemoryStream`]s. One end is handed to
+//! a `SOCKClient` (see [`spawn_client`]) while the test writes raw SOCKS5 bytes
+//! into the other end and reads back merino's responses, no sockets required:
+//!
+//! Outside the crate it's available as `merino::testutils` with the
+//! `testutils` feature.
+//!
+//! ```ignore
+//! let (mut client, server) = duplex();
+//! let handle = spawn_client(server, Vec::new(), vec![AuthMethods::NoAuth as u8]);
+//!
+//! client.write_all(&[5, 1, 0]).unwrap();
+//! assert_eq!(read_n(&mut client, 2), vec![5, 0]);
+//! ```
+use crate::{ClientStream, ConnContext, CredentialStore, Options, ServerState, SOCKClient, User, UserStore};
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Address reported as the peer of every `MemoryStream`
+pub const PEER_ADDR: &str = "127.0.0.1:50000";
+
+/// Address reported as the local end of a `MemoryStream` by default
+pub const LOCAL_ADDR: &str = "127.0.0.1:1080";
+
+#[derive(Default)]
+struct PipeState {
+    data: VecDeque<u8>,
+    closed: bool,
+}
+
+/// One direction of a duplex stream
+#[derive(Default)]
+struct Pipe {
+    state: Mutex<PipeState>,
+    ready: Condvar,
+}
+
+impl Pipe {
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.ready.notify_all();
+    }
+}
+
+/// One end of an in-memory duplex byte stream
+///
+/// Reads block until the other end writes or shuts down, in which case they
+/// return EOF, just like a `TcpStream`.
+#[derive(Clone)]
+pub struct MemoryStream {
+    rx: Arc<Pipe>,
+    tx: Arc<Pipe>,
+    local: SocketAddr,
+    /// Shared between clones, like a socket option
+    read_timeout: Arc<Mutex<Option<Duration>>>,
+}
+
+impl MemoryStream {
+    /// Shut down either direction, just like `TcpStream::shutdown`
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match how {
+            Shutdown::Read => self.rx.close(),
+            Shutdown::Write => self.tx.close(),
+            Shutdown::Both => {
+                self.rx.close();
+                self.tx.close();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Create a connected pair of in-memory streams
+pub fn duplex() -> (MemoryStream, MemoryStream) {
+    duplex_with_local(LOCAL_ADDR.parse().unwrap())
+}
+
+/// Create a connected pair of in-memory streams reporting `local` as their
+/// local address, which is where merino binds BIND and UDP relays
+pub fn duplex_with_local(local: SocketAddr) -> (MemoryStream, MemoryStream) {
+    let a = Arc::new(Pipe::default());
+    let b = Arc::new(Pipe::default());
+
+    (
+        MemoryStream { rx: a.clone(), tx: b.clone(), local, read_timeout: Default::default() },
+        MemoryStream { rx: b, tx: a, local, read_timeout: Default::default() },
+    )
+}
+
+impl Read for MemoryStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let timeout = *self.read_timeout.lock().unwrap();
+        let mut state = self.rx.state.lock().unwrap();
+        while state.data.is_empty() && !state.closed {
+            state = match timeout {
+                Some(timeout) => {
+                    let (state, result) = self.rx.ready.wait_timeout(state, timeout).unwrap();
+                    if result.timed_out() && state.data.is_empty() && !state.closed {
+                        return Err(io::ErrorKind::WouldBlock.into());
+                    }
+                    state
+                },
+                None => self.rx.ready.wait(state).unwrap(),
+            };
+        }
+
+        let n = buf.len().min(state.data.len());
+        for (dst, src) in buf.iter_mut().zip(state.data.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for MemoryStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.tx.state.lock().unwrap();
+        if state.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        state.data.extend(buf);
+        self.tx.ready.notify_all();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl ClientStream for MemoryStream {
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        MemoryStream::shutdown(self, how)
+    }
+
+    fn try_clone(&self) -> io::Result<Self> {
+        Ok(self.clone())
+    }
+
+    fn set_buffer_size(&self, _size: usize) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_dscp(&self, _dscp: u8) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        *self.read_timeout.lock().unwrap() = timeout;
+        Ok(())
+    }
+
+    /// Writes never block, so there's nothing to time out
+    fn set_write_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Writes are delivered immediately, Nagle or not
+    fn set_nodelay(&self, _nodelay: bool) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Serve a `SOCKClient` over `stream` on a new thread
+///
+/// The handle resolves to the client's `init` result, with the error
+/// stringified so it can cross the thread boundary.
+pub fn spawn_client(stream: MemoryStream, users: Vec<User>, auth_methods: Vec<u8>) -> JoinHandle<Result<(), String>> {
+    spawn_client_with(stream, UserStore::new(users), auth_methods, Options::default())
+}
+
+/// Serve a `SOCKClient` with custom `Options` over `stream` on a new thread
+pub fn spawn_client_with<C: CredentialStore + 'static>(stream: MemoryStream, credentials: C, auth_methods: Vec<u8>, options: Options) -> JoinHandle<Result<(), String>> {
+    thread::spawn(move || {
+        let mut client = SOCKClient::new(stream, ConnContext::new(0, PEER_ADDR.parse().unwrap()), Arc::new(credentials), auth_methods, Arc::new(options), Arc::new(ServerState::new(Vec::new())));
+        client.init().map_err(|e| e.to_string())
+    })
+}
+
+/// Read exactly `n` bytes from `stream`
+pub fn read_n<R: Read>(stream: &mut R, n: usize) -> Vec<u8> {
+    let mut buf = vec![0; n];
+    stream.read_exact(&mut buf).unwrap();
+    buf
+}
+
+/// Read until the other end shuts down
+pub fn read_to_end<R: Read>(stream: &mut R) -> Vec<u8> {
+    let mut buf = Vec::new();
+    stream.read_to_end(&mut buf).unwrap();
+    buf
+}
@@ -0,0 +1,1557 @@
+//! Protocol tests driven over the in-memory harness
+use crate::testing::*;
+use crate::{build_reply, copy_counted, is_disconnect, users_from_vars, validate_users, UsersEnvError, ValidationIssue, AuthHandler, AuthMethods, AuthOutcome, AuthStream, ClientStream, ConnContext, CredentialStore, Options, Policy, ResponseCode, RuleSet, User, UserStore};
+
+use std::io::Write;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+fn user(username: &str, password: &str) -> User {
+    User { username: username.to_string(), password: password.to_string() }
+}
+
+/// Build a CONNECT request for an IPv4 `SocketAddr`
+fn connect_request(addr: SocketAddr) -> Vec<u8> {
+    let ip = match addr {
+        SocketAddr::V4(addr) => addr.ip().octets(),
+        SocketAddr::V6(_) => panic!("expected a V4 address"),
+    };
+    let port = addr.port().to_be_bytes();
+
+    vec![5, 1, 0, 1, ip[0], ip[1], ip[2], ip[3], port[0], port[1]]
+}
+
+/// Send a CONNECT for `target`, check the reply and relay a message both ways
+///
+/// The target's end of the tunnel is closed afterwards.
+fn connect_and_relay(client: &mut MemoryStream, target: &TcpListener) {
+    client.write_all(&connect_request(target.local_addr().unwrap())).unwrap();
+    let reply = read_n(client, 10);
+
+    // BND.ADDR is merino's end of the connection to the target
+    let (mut remote, remote_addr) = target.accept().unwrap();
+    assert_eq!(reply, build_reply(ResponseCode::Success, remote_addr));
+
+    client.write_all(b"ping").unwrap();
+    assert_eq!(read_n(&mut remote, 4), b"ping");
+    remote.write_all(b"pong").unwrap();
+    assert_eq!(read_n(client, 4), b"pong");
+}
+
+#[test]
+fn noauth_handshake() {
+    let target = TcpListener::bind("127.0.0.1:0").unwrap();
+    let (mut client, server) = duplex();
+    let handle = spawn_client(server, Vec::new(), vec![AuthMethods::NoAuth as u8]);
+
+    client.write_all(&[5, 1, AuthMethods::NoAuth as u8]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, AuthMethods::NoAuth as u8]);
+
+    connect_and_relay(&mut client, &target);
+    client.shutdown(Shutdown::Both).unwrap();
+    assert_eq!(handle.join().unwrap(), Ok(()));
+}
+
+#[test]
+fn userpass_handshake() {
+    let target = TcpListener::bind("127.0.0.1:0").unwrap();
+    let (mut client, server) = duplex();
+    let handle = spawn_client(server, vec![user("admin", "hunter2")], vec![AuthMethods::UserPass as u8]);
+
+    client.write_all(&[5, 1, AuthMethods::UserPass as u8]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, AuthMethods::UserPass as u8]);
+
+    client.write_all(&[1, 5]).unwrap();
+    client.write_all(b"admin").unwrap();
+    client.write_all(&[7]).unwrap();
+    client.write_all(b"hunter2").unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![1, 0]);
+
+    connect_and_relay(&mut client, &target);
+    client.shutdown(Shutdown::Both).unwrap();
+    assert_eq!(handle.join().unwrap(), Ok(()));
+}
+
+#[test]
+fn userpass_wrong_password() {
+    let (mut client, server) = duplex();
+    let handle = spawn_client(server, vec![user("admin", "hunter2")], vec![AuthMethods::UserPass as u8]);
+
+    client.write_all(&[5, 1, AuthMethods::UserPass as u8]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, AuthMethods::UserPass as u8]);
+
+    client.write_all(&[1, 5]).unwrap();
+    client.write_all(b"admin").unwrap();
+    client.write_all(&[6]).unwrap();
+    client.write_all(b"wrong!").unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![1, 1]);
+
+    // Connection is closed after a failed authentication
+    assert_eq!(read_to_end(&mut client), Vec::<u8>::new());
+    assert!(handle.join().unwrap().is_err());
+}
+
+#[test]
+fn userpass_failure_delay() {
+    let (mut client, server) = duplex();
+    let options = Options { auth_failure_delay: Duration::from_millis(200), ..Options::default() };
+    let handle = spawn_client_with(server, UserStore::new(vec![user("admin", "hunter2")]), vec![AuthMethods::UserPass as u8], options);
+
+    client.write_all(&[5, 1, AuthMethods::UserPass as u8]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, AuthMethods::UserPass as u8]);
+
+    let start = Instant::now();
+    client.write_all(&[1, 5]).unwrap();
+    client.write_all(b"admin").unwrap();
+    client.write_all(&[6]).unwrap();
+    client.write_all(b"wrong!").unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![1, 1]);
+    assert!(start.elapsed() >= Duration::from_millis(100));
+
+    assert_eq!(read_to_end(&mut client), Vec::<u8>::new());
+    assert!(handle.join().unwrap().is_err());
+}
+
+#[test]
+fn no_acceptable_methods() {
+    let (mut client, server) = duplex();
+    let handle = spawn_client(server, Vec::new(), vec![AuthMethods::NoAuth as u8]);
+
+    client.write_all(&[5, 1, AuthMethods::UserPass as u8]).unwrap();
+    assert_eq!(read_to_end(&mut client), vec![5, AuthMethods::NoMethods as u8]);
+    assert!(handle.join().unwrap().is_err());
+}
+
+#[test]
+fn too_many_methods() {
+    let (mut client, server) = duplex();
+    let options = Options { max_auth_methods: 2, ..Options::default() };
+    let handle = spawn_client_with(server, Vec::new(), vec![AuthMethods::NoAuth as u8], options);
+
+    // Closed without reading the methods or replying
+    client.write_all(&[5, 3]).unwrap();
+    assert_eq!(read_to_end(&mut client), Vec::<u8>::new());
+    assert!(handle.join().unwrap().is_err());
+
+    let (mut client, server) = duplex();
+    let options = Options { max_auth_methods: 2, ..Options::default() };
+    let handle = spawn_client_with(server, Vec::new(), vec![AuthMethods::NoAuth as u8], options);
+
+    client.write_all(&[5, 2, AuthMethods::UserPass as u8, AuthMethods::NoAuth as u8]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, AuthMethods::NoAuth as u8]);
+    client.shutdown(Shutdown::Both).unwrap();
+    handle.join().unwrap().unwrap_or(());
+}
+
+#[test]
+fn max_handshake_bytes() {
+    let greeting = [5, 1, AuthMethods::UserPass as u8];
+    let credentials = b"\x01\x05admin\x07hunter2";
+    let options = || Options { max_handshake_bytes: (greeting.len() + credentials.len()) as u64, ..Options::default() };
+
+    // The limit is reached by the credentials, so the request is never read
+    let (mut client, server) = duplex();
+    let handle = spawn_client_with(server, vec![user("admin", "hunter2")], vec![AuthMethods::UserPass as u8], options());
+    client.write_all(&greeting).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, AuthMethods::UserPass as u8]);
+    client.write_all(credentials).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![1, 0]);
+    // The connection may be closed before the request is even sent
+    client.write_all(&[5, 1, 0, 1, 127, 0, 0, 1, 0, 80]).unwrap_or(());
+    assert_eq!(read_to_end(&mut client), Vec::<u8>::new());
+    assert!(handle.join().unwrap().is_err());
+
+    // A longer username goes over it before the credentials are complete
+    let (mut client, server) = duplex();
+    let handle = spawn_client_with(server, vec![user("admin", "hunter2")], vec![AuthMethods::UserPass as u8], options());
+    client.write_all(&greeting).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, AuthMethods::UserPass as u8]);
+    client.write_all(b"\x01\x0dadministrator\x07hunter2").unwrap();
+    assert_eq!(read_to_end(&mut client), Vec::<u8>::new());
+    assert!(handle.join().unwrap().is_err());
+}
+
+#[test]
+fn unsupported_methods_rejected() {
+    let (mut client, server) = duplex();
+    let handle = spawn_client(server, Vec::new(), vec![AuthMethods::NoAuth as u8]);
+
+    // GSSAPI, an unassigned and a private method
+    client.write_all(&[5, 3, 0x01, 0x03, 0x80]).unwrap();
+    assert_eq!(read_to_end(&mut client), vec![5, AuthMethods::NoMethods as u8]);
+    assert!(handle.join().unwrap().is_err());
+}
+
+#[test]
+fn unsupported_methods_consumed() {
+    let target = TcpListener::bind("127.0.0.1:0").unwrap();
+    let (mut client, server) = duplex();
+    let handle = spawn_client(server, Vec::new(), vec![AuthMethods::NoAuth as u8]);
+
+    // Unsupported methods on either side of the supported one must not desync the request
+    client.write_all(&[5, 3, 0x01, AuthMethods::NoAuth as u8, 0x80]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, AuthMethods::NoAuth as u8]);
+
+    connect_and_relay(&mut client, &target);
+    client.shutdown(Shutdown::Both).unwrap();
+    assert_eq!(handle.join().unwrap(), Ok(()));
+}
+
+#[test]
+fn client_gone_before_connect_reply() {
+    let target = TcpListener::bind("127.0.0.1:0").unwrap();
+    let (mut client, server) = duplex();
+    let handle = spawn_client(server, Vec::new(), vec![AuthMethods::NoAuth as u8]);
+
+    client.write_all(&[5, 1, AuthMethods::NoAuth as u8]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, AuthMethods::NoAuth as u8]);
+
+    // Hang up right after the request, so the reply can't be written
+    client.write_all(&connect_request(target.local_addr().unwrap())).unwrap();
+    client.shutdown(Shutdown::Both).unwrap();
+    assert_eq!(handle.join().unwrap(), Ok(()));
+
+    // The connection to the target was closed rather than leaked
+    let (mut remote, _) = target.accept().unwrap();
+    assert_eq!(read_to_end(&mut remote), Vec::<u8>::new());
+}
+
+#[test]
+fn data_sent_with_request() {
+    let target = TcpListener::bind("127.0.0.1:0").unwrap();
+    let (mut client, server) = duplex();
+    let handle = spawn_client(server, Vec::new(), vec![AuthMethods::NoAuth as u8]);
+
+    client.write_all(&[5, 1, AuthMethods::NoAuth as u8]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, AuthMethods::NoAuth as u8]);
+
+    // Data pipelined behind the request reaches the target
+    let mut request = connect_request(target.local_addr().unwrap());
+    request.extend_from_slice(b"early");
+    client.write_all(&request).unwrap();
+    read_n(&mut client, 10);
+
+    let (mut remote, _) = target.accept().unwrap();
+    assert_eq!(read_n(&mut remote, 5), b"early");
+    client.write_all(b"late").unwrap();
+    assert_eq!(read_n(&mut remote, 4), b"late");
+
+    drop(remote);
+    client.shutdown(Shutdown::Both).unwrap();
+    assert_eq!(handle.join().unwrap(), Ok(()));
+}
+
+#[test]
+fn reply_v4() {
+    let addr: SocketAddr = "192.0.2.1:1080".parse().unwrap();
+    assert_eq!(build_reply(ResponseCode::Success, addr), vec![5, 0, 0, 1, 192, 0, 2, 1, 0x04, 0x38]);
+}
+
+#[test]
+fn reply_v6() {
+    let addr: SocketAddr = "[2001:db8::1]:443".parse().unwrap();
+    let mut expected = vec![5, ResponseCode::HostUnreachable as u8, 0, 4];
+    expected.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+    expected.extend_from_slice(&[0x01, 0xbb]);
+    assert_eq!(build_reply(ResponseCode::HostUnreachable, addr), expected);
+}
+
+#[test]
+fn connect_reply_hook() {
+    let target = TcpListener::bind("127.0.0.1:0").unwrap();
+    let (mut client, server) = duplex();
+
+    // Report an unspecified bind address instead of the real one
+    let options = Options {
+        connect_reply_hook: Some(Arc::new(|_ctx: &ConnContext, reply: &mut Vec<u8>| {
+            *reply = build_reply(ResponseCode::Success, "0.0.0.0:0".parse().unwrap());
+        })),
+        ..Options::default()
+    };
+    let handle = spawn_client_with(server, Vec::new(), vec![AuthMethods::NoAuth as u8], options);
+
+    client.write_all(&[5, 1, AuthMethods::NoAuth as u8]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, AuthMethods::NoAuth as u8]);
+
+    client.write_all(&connect_request(target.local_addr().unwrap())).unwrap();
+    assert_eq!(read_n(&mut client, 10), vec![5, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+
+    client.shutdown(Shutdown::Both).unwrap();
+    drop(target.accept().unwrap());
+    assert_eq!(handle.join().unwrap(), Ok(()));
+}
+
+#[test]
+fn reply_hook_context() {
+    let target = TcpListener::bind("127.0.0.1:0").unwrap();
+    let (mut client, server) = duplex();
+
+    // Tag the reply with what the hook saw of the connection
+    let options = Options {
+        connect_reply_hook: Some(Arc::new(|ctx: &ConnContext, reply: &mut Vec<u8>| {
+            assert_eq!(ctx.peer_addr, PEER_ADDR.parse().unwrap());
+            assert_eq!(ctx.auth_method, Some(AuthMethods::UserPass as u8));
+            reply[2] = ctx.username.as_ref().map_or(0, |username| username.len() as u8);
+        })),
+        ..Options::default()
+    };
+    let handle = spawn_client_with(server, vec![user("admin", "hunter2")], vec![AuthMethods::UserPass as u8], options);
+
+    client.write_all(&[5, 1, AuthMethods::UserPass as u8]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, AuthMethods::UserPass as u8]);
+
+    client.write_all(&[1, 5]).unwrap();
+    client.write_all(b"admin").unwrap();
+    client.write_all(&[7]).unwrap();
+    client.write_all(b"hunter2").unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![1, 0]);
+
+    client.write_all(&connect_request(target.local_addr().unwrap())).unwrap();
+    assert_eq!(read_n(&mut client, 10)[2], 5);
+
+    client.shutdown(Shutdown::Both).unwrap();
+    drop(target.accept().unwrap());
+    assert_eq!(handle.join().unwrap(), Ok(()));
+}
+
+#[test]
+fn proxy_protocol_header() {
+    use crate::{ProxyProtocol, ProxyProtocolVersion};
+
+    let listed = TcpListener::bind("127.0.0.1:0").unwrap();
+    let unlisted = TcpListener::bind("127.0.0.1:0").unwrap();
+    let options = Options {
+        proxy_protocol: Some(ProxyProtocol { version: ProxyProtocolVersion::V1, destinations: vec![listed.local_addr().unwrap()] }),
+        ..Options::default()
+    };
+
+    for (target, header) in [(&listed, &b"PROXY TCP4 127.0.0.1 127.0.0.1 50000 1080\r\n"[..]), (&unlisted, &b""[..])] {
+        let (mut client, server) = duplex();
+        let handle = spawn_client_with(server, Vec::new(), vec![AuthMethods::NoAuth as u8], options.clone());
+
+        client.write_all(&[5, 1, AuthMethods::NoAuth as u8]).unwrap();
+        assert_eq!(read_n(&mut client, 2), vec![5, AuthMethods::NoAuth as u8]);
+
+        client.write_all(&connect_request(target.local_addr().unwrap())).unwrap();
+        assert_eq!(read_n(&mut client, 2), vec![5, ResponseCode::Success as u8]);
+
+        client.write_all(b"ping").unwrap();
+        client.shutdown(Shutdown::Both).unwrap();
+
+        let (mut remote, _) = target.accept().unwrap();
+        let mut expected = header.to_vec();
+        expected.extend_from_slice(b"ping");
+        assert_eq!(read_to_end(&mut remote), expected);
+
+        drop(remote);
+        assert_eq!(handle.join().unwrap(), Ok(()));
+    }
+}
+
+#[test]
+fn expect_proxy_protocol() {
+    let target = TcpListener::bind("127.0.0.1:0").unwrap();
+    let (mut client, server) = duplex();
+
+    // The hook sees the address from the header, not the load balancer's
+    let options = Options {
+        expect_proxy_protocol: true,
+        connect_reply_hook: Some(Arc::new(|ctx: &ConnContext, _reply: &mut Vec<u8>| {
+            assert_eq!(ctx.peer_addr, "192.0.2.1:40000".parse().unwrap());
+        })),
+        ..Options::default()
+    };
+    let handle = spawn_client_with(server, Vec::new(), vec![AuthMethods::NoAuth as u8], options);
+
+    client.write_all(b"PROXY TCP4 192.0.2.1 127.0.0.1 40000 1080\r\n").unwrap();
+    client.write_all(&[5, 1, AuthMethods::NoAuth as u8]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, AuthMethods::NoAuth as u8]);
+
+    connect_and_relay(&mut client, &target);
+    client.shutdown(Shutdown::Both).unwrap();
+    assert_eq!(handle.join().unwrap(), Ok(()));
+}
+
+#[test]
+fn expect_proxy_protocol_udp_source() {
+    let target = UdpSocket::bind("127.0.0.1:0").unwrap();
+    target.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+    let local = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+    let options = Options { expect_proxy_protocol: true, ..Options::default() };
+    let (mut client, server) = duplex();
+    let handle = spawn_client_with(server, Vec::new(), vec![AuthMethods::NoAuth as u8], options);
+
+    client.write_all(b"PROXY TCP4 192.0.2.1 127.0.0.1 40000 1080\r\n").unwrap();
+    let relay = udp_associate(&mut client, "0.0.0.0:0".parse().unwrap());
+
+    // Datagrams are expected from the client in the header, not the load balancer
+    local.send_to(&udp_datagram(target.local_addr().unwrap(), b"ping"), relay).unwrap();
+    let mut buf = [0u8; 64];
+    assert!(target.recv_from(&mut buf).is_err());
+
+    client.shutdown(Shutdown::Both).unwrap();
+    assert_eq!(handle.join().unwrap(), Ok(()));
+}
+
+#[test]
+fn max_session_bytes() {
+    let target = TcpListener::bind("127.0.0.1:0").unwrap();
+    let (mut client, server) = duplex();
+    let options = Options { max_session_bytes: Some(10), ..Options::default() };
+    let handle = spawn_client_with(server, Vec::new(), vec![AuthMethods::NoAuth as u8], options);
+
+    client.write_all(&[5, 1, AuthMethods::NoAuth as u8]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, AuthMethods::NoAuth as u8]);
+
+    client.write_all(&connect_request(target.local_addr().unwrap())).unwrap();
+    read_n(&mut client, 10);
+    let (mut remote, _) = target.accept().unwrap();
+
+    // Both ends are cut once the cap is crossed, without the target closing
+    client.write_all(b"0123456789abcdef").unwrap();
+    assert_eq!(read_to_end(&mut remote), b"0123456789");
+    assert_eq!(read_to_end(&mut client), b"");
+    assert_eq!(handle.join().unwrap(), Ok(()));
+}
+
+#[test]
+fn health_check() {
+    use crate::{HEALTH_CHECK_REQUEST, HEALTH_CHECK_RESPONSE};
+
+    let (mut client, server) = duplex();
+    let options = Options { health_check: true, ..Options::default() };
+    let handle = spawn_client_with(server, Vec::new(), vec![AuthMethods::NoAuth as u8], options);
+
+    client.write_all(HEALTH_CHECK_REQUEST).unwrap();
+    assert_eq!(read_to_end(&mut client), HEALTH_CHECK_RESPONSE);
+    assert_eq!(handle.join().unwrap(), Ok(()));
+
+    // Off by default
+    let (mut client, server) = duplex();
+    let handle = spawn_client(server, Vec::new(), vec![AuthMethods::NoAuth as u8]);
+
+    client.write_all(HEALTH_CHECK_REQUEST).unwrap();
+    assert_eq!(read_to_end(&mut client), b"");
+    assert_eq!(handle.join().unwrap(), Ok(()));
+}
+
+#[test]
+fn request_log_json() {
+    let entry = crate::RequestLog {
+        timestamp: 1_560_000_000_000,
+        conn_id: 7,
+        source_ip: "127.0.0.1".parse().unwrap(),
+        source_port: 50123,
+        username: Some("admin".to_string()),
+        command: "Connect".to_string(),
+        dest_host: "example.com".to_string(),
+        dest_port: 443,
+        addr_type: "domain",
+    };
+
+    assert_eq!(
+        serde_json::to_string(&entry).unwrap(),
+        r#"{"timestamp":1560000000000,"conn_id":7,"source_ip":"127.0.0.1","source_port":50123,"username":"admin","command":"Connect","dest_host":"example.com","dest_port":443,"addr_type":"domain"}"#
+    );
+}
+
+#[test]
+fn literal_only_rejects_domains() {
+    let (mut client, server) = duplex();
+    let options = Options { literal_only: true, ..Options::default() };
+    let handle = spawn_client_with(server, Vec::new(), vec![AuthMethods::NoAuth as u8], options);
+
+    client.write_all(&[5, 1, AuthMethods::NoAuth as u8]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, AuthMethods::NoAuth as u8]);
+
+    client.write_all(&[5, 1, 0, 3, 9]).unwrap();
+    client.write_all(b"localhost").unwrap();
+    client.write_all(&[0, 80]).unwrap();
+
+    assert_eq!(read_to_end(&mut client), build_reply(ResponseCode::AddrTypeNotSupported, "0.0.0.0:0".parse().unwrap()));
+    assert_eq!(handle.join().unwrap(), Ok(()));
+}
+
+#[test]
+fn connect_port_zero() {
+    for address in &[&[1, 127, 0, 0, 1][..], &[3, 9, b'l', b'o', b'c', b'a', b'l', b'h', b'o', b's', b't']] {
+        let (mut client, server) = duplex();
+        let handle = spawn_client(server, Vec::new(), vec![AuthMethods::NoAuth as u8]);
+
+        client.write_all(&[5, 1, AuthMethods::NoAuth as u8]).unwrap();
+        assert_eq!(read_n(&mut client, 2), vec![5, AuthMethods::NoAuth as u8]);
+
+        client.write_all(&[5, 1, 0]).unwrap();
+        client.write_all(address).unwrap();
+        client.write_all(&[0, 0]).unwrap();
+
+        assert_eq!(read_to_end(&mut client), build_reply(ResponseCode::Failure, "0.0.0.0:0".parse().unwrap()));
+        assert_eq!(handle.join().unwrap(), Ok(()));
+    }
+}
+
+#[test]
+fn allowed_addr_types() {
+    let (mut client, server) = duplex();
+    let options = Options { allowed_addr_types: vec![crate::AddrType::V4, crate::AddrType::Domain], ..Options::default() };
+    let handle = spawn_client_with(server, Vec::new(), vec![AuthMethods::NoAuth as u8], options);
+
+    client.write_all(&[5, 1, AuthMethods::NoAuth as u8]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, AuthMethods::NoAuth as u8]);
+
+    client.write_all(&[5, 1, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 80]).unwrap();
+    assert_eq!(read_to_end(&mut client), build_reply(ResponseCode::AddrTypeNotSupported, "0.0.0.0:0".parse().unwrap()));
+    assert_eq!(handle.join().unwrap(), Ok(()));
+
+    // Allowed types are served as usual
+    let target = TcpListener::bind("127.0.0.1:0").unwrap();
+    let (mut client, server) = duplex();
+    let options = Options { allowed_addr_types: vec![crate::AddrType::V4, crate::AddrType::Domain], ..Options::default() };
+    let handle = spawn_client_with(server, Vec::new(), vec![AuthMethods::NoAuth as u8], options);
+    client.write_all(&[5, 1, AuthMethods::NoAuth as u8]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, AuthMethods::NoAuth as u8]);
+    connect_and_relay(&mut client, &target);
+    client.shutdown(Shutdown::Both).unwrap();
+    assert_eq!(handle.join().unwrap(), Ok(()));
+}
+
+#[test]
+fn strict_reserved() {
+    let target = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut request = connect_request(target.local_addr().unwrap());
+    request[2] = 0x01;
+
+    let (mut client, server) = duplex();
+    let options = Options { strict_reserved: true, ..Options::default() };
+    let handle = spawn_client_with(server, Vec::new(), vec![AuthMethods::NoAuth as u8], options);
+
+    client.write_all(&[5, 1, AuthMethods::NoAuth as u8]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, AuthMethods::NoAuth as u8]);
+
+    client.write_all(&request).unwrap();
+    assert_eq!(read_to_end(&mut client), build_reply(ResponseCode::Failure, "0.0.0.0:0".parse().unwrap()));
+    assert_eq!(handle.join().unwrap(), Ok(()));
+
+    // Lenient by default
+    let (mut client, server) = duplex();
+    let handle = spawn_client(server, Vec::new(), vec![AuthMethods::NoAuth as u8]);
+
+    client.write_all(&[5, 1, AuthMethods::NoAuth as u8]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, AuthMethods::NoAuth as u8]);
+
+    client.write_all(&request).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, ResponseCode::Success as u8]);
+
+    client.shutdown(Shutdown::Both).unwrap();
+    drop(target.accept().unwrap());
+    assert_eq!(handle.join().unwrap(), Ok(()));
+}
+
+/// Negotiate NoAuth and send a UDP ASSOCIATE for `source`, returning the relay address
+fn udp_associate(client: &mut MemoryStream, source: SocketAddr) -> SocketAddr {
+    send_request(client, 3, source);
+    parse_reply(&read_n(client, 10), ResponseCode::Success)
+}
+
+/// Wrap `data` in a SOCKS5 UDP header addressed to `dest`
+fn udp_datagram(dest: SocketAddr, data: &[u8]) -> Vec<u8> {
+    let mut datagram = connect_request(dest);
+    datagram[..3].copy_from_slice(&[0, 0, 0]);
+    datagram.extend_from_slice(data);
+    datagram
+}
+
+#[test]
+fn udp_associate_relay() {
+    let target = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let local = UdpSocket::bind("127.0.0.1:0").unwrap();
+    local.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+    let (mut client, server) = duplex();
+    let handle = spawn_client(server, Vec::new(), vec![AuthMethods::NoAuth as u8]);
+    let relay = udp_associate(&mut client, local.local_addr().unwrap());
+
+    local.send_to(&udp_datagram(target.local_addr().unwrap(), b"ping"), relay).unwrap();
+
+    let mut buf = [0u8; 64];
+    let (len, from) = target.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..len], b"ping");
+    assert_eq!(from, relay);
+
+    target.send_to(b"pong", relay).unwrap();
+    let (len, _) = local.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..len], &udp_datagram(target.local_addr().unwrap(), b"pong")[..]);
+
+    // Closing the control connection ends the association
+    client.shutdown(Shutdown::Both).unwrap();
+    assert_eq!(handle.join().unwrap(), Ok(()));
+}
+
+#[test]
+fn udp_associate_port_zero() {
+    // Unlike CONNECT, port 0 means the client doesn't know its source yet
+    let (mut client, server) = duplex();
+    let handle = spawn_client(server, Vec::new(), vec![AuthMethods::NoAuth as u8]);
+    udp_associate(&mut client, "0.0.0.0:0".parse().unwrap());
+
+    client.shutdown(Shutdown::Both).unwrap();
+    assert_eq!(handle.join().unwrap(), Ok(()));
+}
+
+#[test]
+fn udp_associate_rules() {
+    let target = UdpSocket::bind("127.0.0.1:0").unwrap();
+    target.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+    let local = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+    // Datagrams are held to the same rules as CONNECT targets
+    let options = Options {
+        rules: RuleSet { rules: Vec::new(), default_policy: Policy::Deny },
+        ..Options::default()
+    };
+    let (mut client, server) = duplex();
+    let handle = spawn_client_with(server, Vec::new(), vec![AuthMethods::NoAuth as u8], options);
+    let relay = udp_associate(&mut client, local.local_addr().unwrap());
+
+    local.send_to(&udp_datagram(target.local_addr().unwrap(), b"ping"), relay).unwrap();
+
+    let mut buf = [0u8; 64];
+    assert!(target.recv_from(&mut buf).is_err());
+
+    client.shutdown(Shutdown::Both).unwrap();
+    assert_eq!(handle.join().unwrap(), Ok(()));
+}
+
+#[test]
+fn udp_associate_domain() {
+    use crate::Resolver;
+    use std::io;
+
+    /// Resolves every name to one address
+    struct Fixed(SocketAddr);
+
+    impl Resolver for Fixed {
+        fn resolve(&self, _host: &str, _port: u16) -> io::Result<Vec<SocketAddr>> {
+            Ok(vec![self.0])
+        }
+    }
+
+    let target = UdpSocket::bind("127.0.0.1:0").unwrap();
+    target.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+    let mut datagram = vec![0, 0, 0, 3, 8];
+    datagram.extend_from_slice(b"udp.test");
+    datagram.extend_from_slice(&[0, 53]);
+    datagram.extend_from_slice(b"ping");
+
+    // Names are resolved with `Options::resolver`, unless lookups are off
+    for literal_only in [false, true] {
+        let local = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let options = Options {
+            resolver: Arc::new(Fixed(target.local_addr().unwrap())),
+            literal_only,
+            ..Options::default()
+        };
+        let (mut client, server) = duplex();
+        let handle = spawn_client_with(server, Vec::new(), vec![AuthMethods::NoAuth as u8], options);
+        let relay = udp_associate(&mut client, local.local_addr().unwrap());
+
+        local.send_to(&datagram, relay).unwrap();
+
+        let mut buf = [0u8; 64];
+        match target.recv_from(&mut buf) {
+            Ok((len, _)) => assert!(!literal_only && &buf[..len] == b"ping"),
+            Err(_) => assert!(literal_only),
+        }
+
+        client.shutdown(Shutdown::Both).unwrap();
+        assert_eq!(handle.join().unwrap(), Ok(()));
+    }
+}
+
+#[test]
+fn udp_associate_resolve_hook() {
+    use crate::Resolver;
+    use std::io;
+
+    /// Resolves every name to one address
+    struct Fixed(SocketAddr);
+
+    impl Resolver for Fixed {
+        fn resolve(&self, _host: &str, _port: u16) -> io::Result<Vec<SocketAddr>> {
+            Ok(vec![self.0])
+        }
+    }
+
+    let target = UdpSocket::bind("127.0.0.1:0").unwrap();
+    target.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+    let local = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let datagram = |host: &[u8], data: &[u8]| {
+        let mut datagram = vec![0, 0, 0, 3, host.len() as u8];
+        datagram.extend_from_slice(host);
+        datagram.extend_from_slice(&[0, 53]);
+        datagram.extend_from_slice(data);
+        datagram
+    };
+
+    // The hook vetoes datagrams as it does CONNECT requests
+    let options = Options {
+        resolver: Arc::new(Fixed(target.local_addr().unwrap())),
+        resolve_hook: Some(Arc::new(|_ctx: &ConnContext, host: &str, _addrs: &[SocketAddr]| host != "rebound.test")),
+        ..Options::default()
+    };
+    let (mut client, server) = duplex();
+    let handle = spawn_client_with(server, Vec::new(), vec![AuthMethods::NoAuth as u8], options);
+    let relay = udp_associate(&mut client, local.local_addr().unwrap());
+
+    local.send_to(&datagram(b"rebound.test", b"ping"), relay).unwrap();
+    local.send_to(&datagram(b"udp.test", b"pong"), relay).unwrap();
+
+    let mut buf = [0u8; 64];
+    let (len, _) = target.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..len], b"pong");
+
+    client.shutdown(Shutdown::Both).unwrap();
+    assert_eq!(handle.join().unwrap(), Ok(()));
+}
+
+#[test]
+fn udp_associate_drops_other_sources() {
+    let target = UdpSocket::bind("127.0.0.1:0").unwrap();
+    target.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+    let local = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let stranger = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+    let (mut client, server) = duplex();
+    let handle = spawn_client(server, Vec::new(), vec![AuthMethods::NoAuth as u8]);
+    let relay = udp_associate(&mut client, local.local_addr().unwrap());
+
+    stranger.send_to(&udp_datagram(target.local_addr().unwrap(), b"ping"), relay).unwrap();
+
+    let mut buf = [0u8; 64];
+    assert!(target.recv_from(&mut buf).is_err());
+
+    client.shutdown(Shutdown::Both).unwrap();
+    assert_eq!(handle.join().unwrap(), Ok(()));
+}
+
+#[test]
+fn userpass_invalid_utf8() {
+    let (mut client, server) = duplex();
+    let handle = spawn_client(server, vec![user("admin", "hunter2")], vec![AuthMethods::UserPass as u8]);
+
+    client.write_all(&[5, 1, AuthMethods::UserPass as u8]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, AuthMethods::UserPass as u8]);
+
+    client.write_all(&[1, 2, 0xc3, 0x28, 7]).unwrap();
+    client.write_all(b"hunter2").unwrap();
+
+    // Rejected like any other bad credentials
+    assert_eq!(read_to_end(&mut client), vec![1, 1]);
+    assert!(handle.join().unwrap().is_err());
+}
+
+#[test]
+fn userpass_multibyte_utf8() {
+    let target = TcpListener::bind("127.0.0.1:0").unwrap();
+    let (mut client, server) = duplex();
+    let handle = spawn_client(server, vec![user("josé", "contraseña€")], vec![AuthMethods::UserPass as u8]);
+
+    client.write_all(&[5, 1, AuthMethods::UserPass as u8]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, AuthMethods::UserPass as u8]);
+
+    // Lengths are in bytes, not characters
+    let (username, password) = ("josé".as_bytes(), "contraseña€".as_bytes());
+    client.write_all(&[1, username.len() as u8]).unwrap();
+    client.write_all(username).unwrap();
+    client.write_all(&[password.len() as u8]).unwrap();
+    client.write_all(password).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![1, 0]);
+
+    connect_and_relay(&mut client, &target);
+    client.shutdown(Shutdown::Both).unwrap();
+    assert_eq!(handle.join().unwrap(), Ok(()));
+}
+
+#[test]
+fn username_normalizer() {
+    let target = TcpListener::bind("127.0.0.1:0").unwrap();
+    let (mut client, server) = duplex();
+    let options = Options {
+        username_normalizer: Some(Arc::new(|username: &str| {
+            username.split('@').next().unwrap_or_default().to_lowercase()
+        })),
+        connect_reply_hook: Some(Arc::new(|ctx: &ConnContext, _reply: &mut Vec<u8>| {
+            assert_eq!(ctx.username.as_deref(), Some("admin"));
+        })),
+        ..Options::default()
+    };
+    let handle = spawn_client_with(server, vec![user("admin", "hunter2")], vec![AuthMethods::UserPass as u8], options);
+
+    client.write_all(&[5, 1, AuthMethods::UserPass as u8]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, AuthMethods::UserPass as u8]);
+
+    client.write_all(&[1, 17]).unwrap();
+    client.write_all(b"Admin@EXAMPLE.COM").unwrap();
+    client.write_all(&[7]).unwrap();
+    client.write_all(b"hunter2").unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![1, 0]);
+
+    connect_and_relay(&mut client, &target);
+    client.shutdown(Shutdown::Both).unwrap();
+    assert_eq!(handle.join().unwrap(), Ok(()));
+}
+
+#[test]
+fn userpass_password_invalid_utf8() {
+    let (mut client, server) = duplex();
+    let handle = spawn_client(server, vec![user("admin", "hunter2")], vec![AuthMethods::UserPass as u8]);
+
+    client.write_all(&[5, 1, AuthMethods::UserPass as u8]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, AuthMethods::UserPass as u8]);
+
+    client.write_all(&[1, 5]).unwrap();
+    client.write_all(b"admin").unwrap();
+    client.write_all(&[3, 0xe2, 0x82, 0x28]).unwrap();
+
+    assert_eq!(read_to_end(&mut client), vec![1, 1]);
+    assert!(handle.join().unwrap().is_err());
+}
+
+/// Accepts any user whose password is their username reversed
+struct ReversedStore;
+
+impl CredentialStore for ReversedStore {
+    fn verify(&self, username: &str, password: &str) -> bool {
+        username.chars().rev().eq(password.chars())
+    }
+}
+
+#[test]
+fn custom_credential_store() {
+    let target = TcpListener::bind("127.0.0.1:0").unwrap();
+    let (mut client, server) = duplex();
+    let handle = spawn_client_with(server, ReversedStore, vec![AuthMethods::UserPass as u8], Options::default());
+
+    client.write_all(&[5, 1, AuthMethods::UserPass as u8]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, AuthMethods::UserPass as u8]);
+
+    client.write_all(&[1, 5]).unwrap();
+    client.write_all(b"merin").unwrap();
+    client.write_all(&[5]).unwrap();
+    client.write_all(b"nirem").unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![1, 0]);
+
+    connect_and_relay(&mut client, &target);
+    client.shutdown(Shutdown::Both).unwrap();
+    assert_eq!(handle.join().unwrap(), Ok(()));
+}
+
+/// Parse the ATYP, ADDR and PORT of a reply, checking its reply code
+fn parse_reply(reply: &[u8], code: ResponseCode) -> SocketAddr {
+    assert_eq!(&reply[..3], &[5, code as u8, 0]);
+    match reply[3] {
+        1 => {
+            assert_eq!(reply.len(), 10);
+            SocketAddr::from(([reply[4], reply[5], reply[6], reply[7]], u16::from_be_bytes([reply[8], reply[9]])))
+        },
+        4 => {
+            assert_eq!(reply.len(), 22);
+            let mut ip = [0u8; 16];
+            ip.copy_from_slice(&reply[4..20]);
+            SocketAddr::from((ip, u16::from_be_bytes([reply[20], reply[21]])))
+        },
+        atyp => panic!("unexpected ATYP {}", atyp),
+    }
+}
+
+/// Negotiate NoAuth and send `command` for `addr`
+fn send_request(client: &mut MemoryStream, command: u8, addr: SocketAddr) {
+    client.write_all(&[5, 1, AuthMethods::NoAuth as u8]).unwrap();
+    assert_eq!(read_n(client, 2), vec![5, AuthMethods::NoAuth as u8]);
+
+    let mut request = vec![5, command, 0];
+    match addr {
+        SocketAddr::V4(addr) => {
+            request.push(1);
+            request.extend_from_slice(&addr.ip().octets());
+        },
+        SocketAddr::V6(addr) => {
+            request.push(4);
+            request.extend_from_slice(&addr.ip().octets());
+        },
+    }
+    request.extend_from_slice(&addr.port().to_be_bytes());
+    client.write_all(&request).unwrap();
+}
+
+/// BIND through a client whose control connection reached merino on `local`
+fn bind_round_trip(local: SocketAddr, remote_ip: &str) {
+    let (mut client, server) = duplex_with_local(local);
+    let handle = spawn_client(server, Vec::new(), vec![AuthMethods::NoAuth as u8]);
+    send_request(&mut client, 2, SocketAddr::new(remote_ip.parse().unwrap(), 0));
+
+    // First reply: where the listener is
+    let reply_len = if local.is_ipv4() { 10 } else { 22 };
+    let listening = parse_reply(&read_n(&mut client, reply_len), ResponseCode::Success);
+    assert_eq!(listening.ip(), local.ip());
+    assert_ne!(listening.port(), 0);
+
+    // Second reply: who connected
+    let mut remote = TcpStream::connect(listening).unwrap();
+    let connected = parse_reply(&read_n(&mut client, reply_len), ResponseCode::Success);
+    assert_eq!(connected, remote.local_addr().unwrap());
+
+    remote.write_all(b"ping").unwrap();
+    assert_eq!(read_n(&mut client, 4), b"ping");
+    client.write_all(b"pong").unwrap();
+    assert_eq!(read_n(&mut remote, 4), b"pong");
+
+    drop(remote);
+    client.shutdown(Shutdown::Both).unwrap();
+    assert_eq!(handle.join().unwrap(), Ok(()));
+}
+
+#[test]
+fn bind_v4() {
+    bind_round_trip("127.0.0.1:1080".parse().unwrap(), "127.0.0.1");
+}
+
+#[test]
+fn bind_v6() {
+    bind_round_trip("[::1]:1080".parse().unwrap(), "::1");
+}
+
+#[test]
+fn bind_rejects_unexpected_peer() {
+    let (mut client, server) = duplex();
+    let handle = spawn_client(server, Vec::new(), vec![AuthMethods::NoAuth as u8]);
+    send_request(&mut client, 2, "192.0.2.1:0".parse().unwrap());
+
+    let listening = parse_reply(&read_n(&mut client, 10), ResponseCode::Success);
+    let _remote = TcpStream::connect(listening).unwrap();
+    parse_reply(&read_to_end(&mut client), ResponseCode::RuleFailure);
+    assert_eq!(handle.join().unwrap(), Ok(()));
+}
+
+#[test]
+fn udp_associate_reply_v6() {
+    let (mut client, server) = duplex_with_local("[::1]:1080".parse().unwrap());
+    let handle = spawn_client(server, Vec::new(), vec![AuthMethods::NoAuth as u8]);
+    send_request(&mut client, 3, "[::]:0".parse().unwrap());
+
+    // The relay must be reachable at the reported address
+    let relay = parse_reply(&read_n(&mut client, 22), ResponseCode::Success);
+    assert_eq!(relay.ip(), "::1".parse::<std::net::IpAddr>().unwrap());
+    assert_ne!(relay.port(), 0);
+
+    client.shutdown(Shutdown::Both).unwrap();
+    assert_eq!(handle.join().unwrap(), Ok(()));
+}
+
+#[test]
+fn rules_deny_by_default() {
+    let target = TcpListener::bind("127.0.0.1:0").unwrap();
+
+    let (mut client, server) = duplex();
+    let options = Options {
+        rules: RuleSet { rules: Vec::new(), default_policy: Policy::Deny },
+        ..Options::default()
+    };
+    let handle = spawn_client_with(server, Vec::new(), vec![AuthMethods::NoAuth as u8], options);
+
+    client.write_all(&[5, 1, AuthMethods::NoAuth as u8]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, AuthMethods::NoAuth as u8]);
+
+    client.write_all(&connect_request(target.local_addr().unwrap())).unwrap();
+    assert_eq!(read_to_end(&mut client), build_reply(ResponseCode::RuleFailure, "0.0.0.0:0".parse().unwrap()));
+    assert_eq!(handle.join().unwrap(), Ok(()));
+
+    // Nothing was connected to
+    target.set_nonblocking(true).unwrap();
+    assert!(target.accept().is_err());
+}
+
+#[test]
+fn resolve_hook() {
+    use crate::Resolver;
+    use std::io;
+
+    /// Resolves every name to `addr`
+    struct Fixed(SocketAddr);
+
+    impl Resolver for Fixed {
+        fn resolve(&self, _host: &str, _port: u16) -> io::Result<Vec<SocketAddr>> {
+            Ok(vec![self.0])
+        }
+    }
+
+    let target = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = target.local_addr().unwrap();
+    let mut request = vec![5, 1, 0, 3, 12];
+    request.extend_from_slice(b"rebound.test");
+    request.extend_from_slice(&addr.port().to_be_bytes());
+
+    // Veto names that resolve to loopback
+    let options = Options {
+        resolver: Arc::new(Fixed(addr)),
+        resolve_hook: Some(Arc::new(|_ctx: &ConnContext, host: &str, addrs: &[SocketAddr]| {
+            assert_eq!(host, "rebound.test");
+            !addrs.iter().any(|addr| addr.ip().is_loopback())
+        })),
+        ..Options::default()
+    };
+    let (mut client, server) = duplex();
+    let handle = spawn_client_with(server, Vec::new(), vec![AuthMethods::NoAuth as u8], options);
+
+    client.write_all(&[5, 1, AuthMethods::NoAuth as u8]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, AuthMethods::NoAuth as u8]);
+
+    client.write_all(&request).unwrap();
+    assert_eq!(read_to_end(&mut client), build_reply(ResponseCode::RuleFailure, "0.0.0.0:0".parse().unwrap()));
+    assert_eq!(handle.join().unwrap(), Ok(()));
+
+    // Nothing was connected to
+    target.set_nonblocking(true).unwrap();
+    assert!(target.accept().is_err());
+}
+
+#[test]
+fn connect_probe() {
+    let options = || Options {
+        connect_probe: Some(Arc::new(|_ctx: &ConnContext, _address: &crate::Address, peeked: &[u8]| peeked.starts_with(b"SSH-"))),
+        connect_probe_timeout: Duration::from_millis(200),
+        ..Options::default()
+    };
+
+    // The banner is passed on after the reply
+    let target = TcpListener::bind("127.0.0.1:0").unwrap();
+    let (mut client, server) = duplex();
+    let handle = spawn_client_with(server, Vec::new(), vec![AuthMethods::NoAuth as u8], options());
+    client.write_all(&[5, 1, AuthMethods::NoAuth as u8]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, AuthMethods::NoAuth as u8]);
+
+    client.write_all(&connect_request(target.local_addr().unwrap())).unwrap();
+    let (mut remote, remote_addr) = target.accept().unwrap();
+    remote.write_all(b"SSH-2.0-OpenSSH\r\n").unwrap();
+    assert_eq!(read_n(&mut client, 10), build_reply(ResponseCode::Success, remote_addr));
+    assert_eq!(read_n(&mut client, 17), b"SSH-2.0-OpenSSH\r\n");
+    client.shutdown(Shutdown::Both).unwrap();
+    drop(remote);
+    assert_eq!(handle.join().unwrap(), Ok(()));
+
+    // Anything else, or nothing at all, is refused
+    for banner in &[&b"HTTP/1.1 200 OK\r\n"[..], b""] {
+        let (mut client, server) = duplex();
+        let handle = spawn_client_with(server, Vec::new(), vec![AuthMethods::NoAuth as u8], options());
+        client.write_all(&[5, 1, AuthMethods::NoAuth as u8]).unwrap();
+        assert_eq!(read_n(&mut client, 2), vec![5, AuthMethods::NoAuth as u8]);
+
+        client.write_all(&connect_request(target.local_addr().unwrap())).unwrap();
+        let (mut remote, _) = target.accept().unwrap();
+        remote.write_all(banner).unwrap();
+        assert_eq!(read_to_end(&mut client), build_reply(ResponseCode::RuleFailure, "0.0.0.0:0".parse().unwrap()));
+        assert_eq!(handle.join().unwrap(), Ok(()));
+    }
+}
+
+#[cfg(feature = "regex")]
+#[test]
+fn pattern_rules_skip_lookup() {
+    use crate::{Resolver, Rule};
+    use std::io;
+
+    struct Unreachable;
+
+    impl Resolver for Unreachable {
+        fn resolve(&self, host: &str, _port: u16) -> io::Result<Vec<SocketAddr>> {
+            panic!("{} was looked up", host)
+        }
+    }
+
+    let options = Options {
+        resolver: Arc::new(Unreachable),
+        rules: RuleSet { rules: vec![Rule { policy: Policy::Deny, target: "regex:ads-.*".parse().unwrap() }], default_policy: Policy::Allow },
+        ..Options::default()
+    };
+    let (mut client, server) = duplex();
+    let handle = spawn_client_with(server, Vec::new(), vec![AuthMethods::NoAuth as u8], options);
+
+    client.write_all(&[5, 1, AuthMethods::NoAuth as u8]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, AuthMethods::NoAuth as u8]);
+
+    client.write_all(&[5, 1, 0, 3, 10]).unwrap();
+    client.write_all(b"ads-1.test").unwrap();
+    client.write_all(&[0, 80]).unwrap();
+    assert_eq!(read_to_end(&mut client), build_reply(ResponseCode::RuleFailure, "0.0.0.0:0".parse().unwrap()));
+    assert_eq!(handle.join().unwrap(), Ok(()));
+}
+
+#[cfg(feature = "chaos")]
+#[test]
+fn chaos_reply_delay() {
+    use crate::Chaos;
+
+    let target = TcpListener::bind("127.0.0.1:0").unwrap();
+    let options = Options {
+        chaos: Some(Chaos { reply_delay: Duration::from_millis(200), ..Chaos::default() }),
+        ..Options::default()
+    };
+    let (mut client, server) = duplex();
+    let handle = spawn_client_with(server, Vec::new(), vec![AuthMethods::NoAuth as u8], options);
+
+    client.write_all(&[5, 1, AuthMethods::NoAuth as u8]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, AuthMethods::NoAuth as u8]);
+
+    // The method selection isn't held back, the reply to the request is
+    let start = Instant::now();
+    connect_and_relay(&mut client, &target);
+    assert!(start.elapsed() >= Duration::from_millis(200));
+    client.shutdown(Shutdown::Both).unwrap();
+    assert_eq!(handle.join().unwrap(), Ok(()));
+}
+
+#[test]
+fn address_family_preference() {
+    use crate::{FamilyPreference, Resolver};
+    use std::io;
+
+    /// Resolves every name to these addresses, in order
+    struct Fixed(Vec<SocketAddr>);
+
+    impl Resolver for Fixed {
+        fn resolve(&self, _host: &str, _port: u16) -> io::Result<Vec<SocketAddr>> {
+            Ok(self.0.clone())
+        }
+    }
+
+    let v4 = TcpListener::bind("127.0.0.1:0").unwrap();
+    let v6 = TcpListener::bind("[::1]:0").unwrap();
+    let addrs = vec![v4.local_addr().unwrap(), v6.local_addr().unwrap()];
+
+    // Whichever family the resolver lists first, the preferred one is tried first
+    for (preference, resolved, preferred, other) in [
+        (FamilyPreference::V6, addrs.clone(), &v6, &v4),
+        (FamilyPreference::V4, addrs.iter().rev().copied().collect(), &v4, &v6),
+    ] {
+        let options = Options {
+            resolver: Arc::new(Fixed(resolved)),
+            happy_eyeballs_delay: None,
+            address_family_preference: preference,
+            ..Options::default()
+        };
+        let (mut client, server) = duplex();
+        let handle = spawn_client_with(server, Vec::new(), vec![AuthMethods::NoAuth as u8], options);
+
+        client.write_all(&[5, 1, AuthMethods::NoAuth as u8]).unwrap();
+        assert_eq!(read_n(&mut client, 2), vec![5, AuthMethods::NoAuth as u8]);
+
+        let mut request = vec![5, 1, 0, 3, 14];
+        request.extend_from_slice(b"dualstack.test");
+        request.extend_from_slice(&[0, 80]);
+        client.write_all(&request).unwrap();
+        assert_eq!(read_n(&mut client, 2), vec![5, ResponseCode::Success as u8]);
+
+        drop(preferred.accept().unwrap());
+        other.set_nonblocking(true).unwrap();
+        assert!(other.accept().is_err());
+        client.shutdown(Shutdown::Write).unwrap();
+        read_to_end(&mut client);
+        handle.join().unwrap().unwrap();
+    }
+}
+
+#[test]
+fn disable_ipv6() {
+    use crate::Resolver;
+    use std::io;
+
+    /// Resolves every name to an IPv6 and an IPv4 address
+    struct DualStack(u16);
+
+    impl Resolver for DualStack {
+        fn resolve(&self, _host: &str, _port: u16) -> io::Result<Vec<SocketAddr>> {
+            Ok(vec![SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], self.0)), SocketAddr::from(([127, 0, 0, 1], self.0))])
+        }
+    }
+
+    let target = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = target.local_addr().unwrap().port();
+    let options = || Options {
+        resolver: Arc::new(DualStack(port)),
+        resolve_hook: Some(Arc::new(|_ctx: &ConnContext, _host: &str, addrs: &[SocketAddr]| {
+            addrs.iter().all(SocketAddr::is_ipv4)
+        })),
+        disable_ipv6: true,
+        ..Options::default()
+    };
+
+    // Only the IPv4 result of a dual-stack name is connected to
+    let mut request = vec![5, 1, 0, 3, 14];
+    request.extend_from_slice(b"dualstack.test");
+    request.extend_from_slice(&port.to_be_bytes());
+    let (mut client, server) = duplex();
+    let handle = spawn_client_with(server, Vec::new(), vec![AuthMethods::NoAuth as u8], options());
+
+    client.write_all(&[5, 1, AuthMethods::NoAuth as u8]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, AuthMethods::NoAuth as u8]);
+
+    client.write_all(&request).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, ResponseCode::Success as u8]);
+    let (remote, _) = target.accept().unwrap();
+    drop(remote);
+    client.shutdown(Shutdown::Write).unwrap();
+    read_to_end(&mut client);
+    handle.join().unwrap().unwrap();
+
+    // IPv6 literals are refused outright
+    let mut request = vec![5, 1, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+    request.extend_from_slice(&port.to_be_bytes());
+    let (mut client, server) = duplex();
+    let handle = spawn_client_with(server, Vec::new(), vec![AuthMethods::NoAuth as u8], options());
+
+    client.write_all(&[5, 1, AuthMethods::NoAuth as u8]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, AuthMethods::NoAuth as u8]);
+
+    client.write_all(&request).unwrap();
+    assert_eq!(read_to_end(&mut client), build_reply(ResponseCode::AddrTypeNotSupported, "0.0.0.0:0".parse().unwrap()));
+    assert_eq!(handle.join().unwrap(), Ok(()));
+}
+
+#[test]
+fn disable_ipv4() {
+    let target = TcpListener::bind("127.0.0.1:0").unwrap();
+    let options = Options { disable_ipv4: true, ..Options::default() };
+    let (mut client, server) = duplex();
+    let handle = spawn_client_with(server, Vec::new(), vec![AuthMethods::NoAuth as u8], options);
+
+    client.write_all(&[5, 1, AuthMethods::NoAuth as u8]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, AuthMethods::NoAuth as u8]);
+
+    client.write_all(&connect_request(target.local_addr().unwrap())).unwrap();
+    assert_eq!(read_to_end(&mut client), build_reply(ResponseCode::AddrTypeNotSupported, "0.0.0.0:0".parse().unwrap()));
+    assert_eq!(handle.join().unwrap(), Ok(()));
+}
+
+#[test]
+fn anonymous_rules() {
+    let target = TcpListener::bind("127.0.0.1:0").unwrap();
+    let auth_methods = vec![AuthMethods::NoAuth as u8, AuthMethods::UserPass as u8];
+    let options = || Options {
+        anonymous_rules: Some(RuleSet { rules: Vec::new(), default_policy: Policy::Deny }),
+        ..Options::default()
+    };
+
+    // Anonymous clients are held to the stricter rules
+    let (mut client, server) = duplex();
+    let handle = spawn_client_with(server, vec![user("admin", "hunter2")], auth_methods.clone(), options());
+
+    client.write_all(&[5, 1, AuthMethods::NoAuth as u8]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, AuthMethods::NoAuth as u8]);
+
+    client.write_all(&connect_request(target.local_addr().unwrap())).unwrap();
+    assert_eq!(read_to_end(&mut client), build_reply(ResponseCode::RuleFailure, "0.0.0.0:0".parse().unwrap()));
+    assert_eq!(handle.join().unwrap(), Ok(()));
+
+    // Users that log in get the default rules
+    let (mut client, server) = duplex();
+    let handle = spawn_client_with(server, vec![user("admin", "hunter2")], auth_methods, options());
+
+    client.write_all(&[5, 2, AuthMethods::NoAuth as u8, AuthMethods::UserPass as u8]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, AuthMethods::UserPass as u8]);
+
+    client.write_all(&[1, 5]).unwrap();
+    client.write_all(b"admin").unwrap();
+    client.write_all(&[7]).unwrap();
+    client.write_all(b"hunter2").unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![1, 0]);
+
+    connect_and_relay(&mut client, &target);
+    client.shutdown(Shutdown::Both).unwrap();
+    assert_eq!(handle.join().unwrap(), Ok(()));
+}
+
+#[test]
+fn relay_read_timeout() {
+    let target = TcpListener::bind("127.0.0.1:0").unwrap();
+    let (mut client, server) = duplex();
+    let options = Options { relay_read_timeout: Some(Duration::from_millis(200)), ..Options::default() };
+    let handle = spawn_client_with(server, Vec::new(), vec![AuthMethods::NoAuth as u8], options);
+
+    client.write_all(&[5, 1, AuthMethods::NoAuth as u8]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, AuthMethods::NoAuth as u8]);
+    client.write_all(&connect_request(target.local_addr().unwrap())).unwrap();
+    read_n(&mut client, 10);
+    let (_remote, _) = target.accept().unwrap();
+
+    // Neither side sends anything, so both directions time out
+    let start = std::time::Instant::now();
+    assert_eq!(read_to_end(&mut client), Vec::<u8>::new());
+    assert_eq!(handle.join().unwrap(), Ok(()));
+    assert!(start.elapsed() >= Duration::from_millis(150));
+}
+
+#[test]
+fn copy_counted_cancel() {
+    use crate::SessionStatus;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    // Nothing is ever written to `client`, so only cancelling ends the copy
+    let (client, mut server) = duplex();
+    server.set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+
+    let status = Arc::new(SessionStatus::new(PEER_ADDR.parse().unwrap()));
+    let copy = {
+        let status = status.clone();
+        std::thread::spawn(move || copy_counted(&mut server, &mut std::io::sink(), &AtomicU64::new(0), None, &status, None, &AtomicU64::new(0)).unwrap())
+    };
+
+    std::thread::sleep(Duration::from_millis(50));
+    status.cancelled.store(true, Ordering::SeqCst);
+    assert_eq!(copy.join().unwrap(), 0);
+    assert!(status.idle() >= Duration::from_millis(50));
+    drop(client);
+}
+
+#[test]
+fn relay_half_counts_before_error() {
+    use crate::{relay_half, SessionStatus, TunnelEnd};
+    use std::io;
+    use std::sync::atomic::AtomicU64;
+
+    /// Takes `capacity` bytes, then fails like a reset connection
+    struct Reset {
+        capacity: usize,
+    }
+
+    impl Write for Reset {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.capacity == 0 {
+                return Err(io::Error::new(io::ErrorKind::ConnectionReset, "reset"));
+            }
+            let n = buf.len().min(self.capacity);
+            self.capacity -= n;
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl TunnelEnd for Reset {
+        fn close(&mut self, _how: Shutdown) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let (mut client, server) = duplex();
+    client.write_all(&[0; 20]).unwrap();
+    client.shutdown(Shutdown::Write).unwrap();
+
+    let status = SessionStatus::new(PEER_ADDR.parse().unwrap());
+    let relayed = AtomicU64::new(0);
+    assert_eq!(relay_half(server, Reset { capacity: 10 }, &AtomicU64::new(0), None, &status, None, &relayed), 10);
+    assert_eq!(relayed.into_inner(), 10);
+}
+
+#[test]
+fn user_slot_limits() {
+    use crate::{ServerState, UserSlot};
+
+    let state = Arc::new(ServerState::new(Vec::new()));
+    let slot = UserSlot::acquire(state.clone(), "alice".to_string(), 1);
+    assert!(slot.is_some());
+    assert!(UserSlot::acquire(state.clone(), "alice".to_string(), 1).is_none());
+
+    // Turning a user away doesn't leave an entry behind
+    assert!(UserSlot::acquire(state.clone(), "bob".to_string(), 0).is_none());
+    assert_eq!(state.user_connections.lock().unwrap().keys().collect::<Vec<_>>(), vec!["alice"]);
+    drop(slot);
+    assert!(state.user_connections.lock().unwrap().is_empty());
+}
+
+#[test]
+fn custom_handler_compressed_tunnel_method() {
+    /// Claims the method of compressed tunnels, but lets everyone in as is
+    struct Claims;
+
+    impl AuthHandler for Claims {
+        fn method(&self) -> u8 {
+            AuthMethods::CompressedTunnel as u8
+        }
+
+        fn authenticate(&self, _ctx: &ConnContext, _stream: &mut dyn AuthStream) -> std::io::Result<AuthOutcome> {
+            Ok(AuthOutcome::Granted { username: None })
+        }
+    }
+
+    // Only the built-in handler compresses the tunnel
+    let target = TcpListener::bind("127.0.0.1:0").unwrap();
+    let options = Options { auth_handlers: vec![Arc::new(Claims)], ..Options::default() };
+    let (mut client, server) = duplex();
+    let handle = spawn_client_with(server, Vec::new(), vec![AuthMethods::CompressedTunnel as u8], options);
+
+    client.write_all(&[5, 1, AuthMethods::CompressedTunnel as u8]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, AuthMethods::CompressedTunnel as u8]);
+
+    connect_and_relay(&mut client, &target);
+    client.shutdown(Shutdown::Both).unwrap();
+    assert_eq!(handle.join().unwrap(), Ok(()));
+}
+
+/// Private method 0x80: the client sends a one byte token, 42 lets it in
+struct TokenAuth;
+
+impl AuthHandler for TokenAuth {
+    fn method(&self) -> u8 {
+        0x80
+    }
+
+    fn authenticate(&self, _ctx: &ConnContext, stream: &mut dyn AuthStream) -> std::io::Result<AuthOutcome> {
+        let mut token = [0u8; 1];
+        stream.read_exact(&mut token)?;
+        if token[0] == 42 {
+            stream.write_all(&[1, 0])?;
+            Ok(AuthOutcome::Granted { username: Some("token".to_string()) })
+        }
+        else {
+            stream.write_all(&[1, 1])?;
+            Ok(AuthOutcome::Denied)
+        }
+    }
+}
+
+#[test]
+fn custom_auth_handler() {
+    let target = TcpListener::bind("127.0.0.1:0").unwrap();
+    let options = || Options {
+        auth_handlers: vec![Arc::new(TokenAuth)],
+        connect_reply_hook: Some(Arc::new(|ctx: &ConnContext, _reply: &mut Vec<u8>| {
+            assert_eq!(ctx.auth_method, Some(0x80));
+            assert_eq!(ctx.username.as_deref(), Some("token"));
+        })),
+        ..Options::default()
+    };
+
+    // Preferred over the built-in methods
+    let (mut client, server) = duplex();
+    let handle = spawn_client_with(server, Vec::new(), vec![AuthMethods::NoAuth as u8], options());
+
+    client.write_all(&[5, 2, AuthMethods::NoAuth as u8, 0x80]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, 0x80]);
+    client.write_all(&[42]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![1, 0]);
+
+    client.write_all(&connect_request(target.local_addr().unwrap())).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, ResponseCode::Success as u8]);
+
+    client.shutdown(Shutdown::Both).unwrap();
+    drop(target.accept().unwrap());
+    assert_eq!(handle.join().unwrap(), Ok(()));
+
+    // A bad token is denied
+    let (mut client, server) = duplex();
+    let handle = spawn_client_with(server, Vec::new(), Vec::new(), options());
+
+    client.write_all(&[5, 1, 0x80]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, 0x80]);
+    client.write_all(&[7]).unwrap();
+    assert_eq!(read_to_end(&mut client), vec![1, 1]);
+    handle.join().unwrap().unwrap_err();
+}
+
+#[test]
+fn advertised_addr() {
+    let options = Options { advertised_addr: Some("203.0.113.7".parse().unwrap()), ..Options::default() };
+    let (mut client, server) = duplex();
+    let handle = spawn_client_with(server, Vec::new(), vec![AuthMethods::NoAuth as u8], options);
+
+    let relay = udp_associate(&mut client, "127.0.0.1:0".parse().unwrap());
+    assert_eq!(relay.ip(), "203.0.113.7".parse::<std::net::IpAddr>().unwrap());
+    assert_ne!(relay.port(), 0);
+
+    client.shutdown(Shutdown::Both).unwrap();
+    assert_eq!(handle.join().unwrap(), Ok(()));
+}
+
+#[test]
+fn users_from_env_vars() {
+    let vars = |vars: &[(&str, &str)]| users_from_vars(vars.iter().map(|(var, value)| (var.into(), value.into())));
+
+    let users = vars(&[
+        ("MERINO_USER_2", "carol:pass,with,commas"),
+        ("PATH", "/usr/bin"),
+        ("MERINO_USERS", "alice:secret, bob:hunter2:colon"),
+        ("MERINO_USER_1", "dave:"),
+    ]).unwrap();
+    assert_eq!(users, vec![
+        user("alice", "secret"),
+        user("bob", "hunter2:colon"),
+        user("dave", ""),
+        user("carol", "pass,with,commas"),
+    ]);
+
+    assert_eq!(vars(&[]).unwrap(), Vec::new());
+    assert_eq!(vars(&[("MERINO_USERS", "alice:secret,bob")]), Err(UsersEnvError::MalformedUser { var: "MERINO_USERS".to_string(), entry: 2 }));
+    assert_eq!(vars(&[("MERINO_USER_3", ":secret")]), Err(UsersEnvError::MalformedUser { var: "MERINO_USER_3".to_string(), entry: 1 }));
+}
+
+#[test]
+fn user_store() {
+    let store = UserStore::new(vec![user("alice", "secret"), user("bob", "hunter2"), user("alice", "changed")]);
+    assert_eq!(store.len(), 2);
+
+    assert!(store.verify("bob", "hunter2"));
+    assert!(!store.verify("bob", "hunter"));
+    assert!(!store.verify("bob", "hunter22"));
+    assert!(!store.verify("carol", "hunter2"));
+
+    // The last password listed wins
+    assert!(store.verify("alice", "changed"));
+    assert!(!store.verify("alice", "secret"));
+}
+
+#[test]
+fn validate_user_list() {
+    assert_eq!(validate_users(&[user("alice", "correct horse"), user("bob", "battery staple")], true), Ok(()));
+
+    let users = [
+        user("alice", "correct horse"),
+        user("", "secret"),
+        user("alice", "battery staple"),
+        user("bob", ""),
+        user("carol", "hunter2"),
+        user("dave", "dave"),
+        user("alice", "third"),
+    ];
+    assert_eq!(validate_users(&users, true), Err(vec![
+        ValidationIssue::EmptyUsername { position: 2 },
+        ValidationIssue::DuplicateUsername { username: "alice".to_string() },
+        ValidationIssue::EmptyPassword { username: "bob".to_string() },
+        ValidationIssue::WeakPassword { username: "carol".to_string() },
+        ValidationIssue::WeakPassword { username: "dave".to_string() },
+        ValidationIssue::WeakPassword { username: "alice".to_string() },
+    ]));
+
+    // Weak passwords are only flagged on request
+    assert_eq!(validate_users(&[user("carol", "hunter2")], false), Ok(()));
+}
+
+#[test]
+fn disconnect_errors() {
+    use crate::protocol::ProtocolError;
+    use std::error::Error;
+    use std::io;
+
+    let eof: Box<dyn Error> = Box::new(io::Error::from(io::ErrorKind::UnexpectedEof));
+    assert!(is_disconnect(eof.as_ref()));
+    let reset: Box<dyn Error> = Box::new(ProtocolError::Io { source: io::ErrorKind::ConnectionReset.into() });
+    assert!(is_disconnect(reset.as_ref()));
+
+    let refused: Box<dyn Error> = Box::new(io::Error::from(io::ErrorKind::ConnectionRefused));
+    assert!(!is_disconnect(refused.as_ref()));
+    let code: Box<dyn Error> = Box::new(ResponseCode::Failure);
+    assert!(!is_disconnect(code.as_ref()));
+}
@@ -0,0 +1,184 @@
+//! UDP ASSOCIATE relay
+use crate::protocol::{read_address, write_socket_addr, AddrType, Address, RESERVED};
+use crate::{ConnContext, Options, Policy, RuleSet, ServerState, SessionStatus};
+
+use std::collections::HashSet;
+use std::io;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How often the relay checks whether the association has ended
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Which sources may send datagrams through a UDP association
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UdpSourceFilter {
+    /// Only accept datagrams from the address and port given in the UDP
+    /// ASSOCIATE request. An unspecified address (`0.0.0.0` or `::`) stands
+    /// for the IP of the control connection, and port 0 for the port of the
+    /// first datagram received from that IP.
+    Strict,
+    /// Accept the first datagram from any source and lock the association to
+    /// that source. Useful when the client is behind NAT and can't know the
+    /// address its datagrams will come from.
+    Permissive,
+}
+
+/// The source a UDP association accepts client datagrams from
+pub(crate) struct ClientSource {
+    ip: Option<IpAddr>,
+    port: Option<u16>,
+}
+
+impl ClientSource {
+    /// Work out the expected client source from a UDP ASSOCIATE request
+    ///
+    /// `peer_ip` is the client's IP: the source of the control connection, or
+    /// the address in its PROXY header.
+    pub(crate) fn new(filter: UdpSourceFilter, requested: &Address, peer_ip: IpAddr) -> Self {
+        if filter == UdpSourceFilter::Permissive {
+            return ClientSource { ip: None, port: None };
+        }
+
+        let ip = match requested {
+            Address::Ipv4(ip, _) if !ip.is_unspecified() => IpAddr::V4(*ip),
+            Address::Ipv6(ip, _) if !ip.is_unspecified() => IpAddr::V6(*ip),
+            _ => peer_ip,
+        };
+        let port = Some(requested.port()).filter(|port| *port != 0);
+
+        ClientSource { ip: Some(ip), port }
+    }
+
+    fn matches(&self, addr: SocketAddr) -> bool {
+        self.ip.is_none_or(|ip| ip == addr.ip()) && self.port.is_none_or(|port| port == addr.port())
+    }
+}
+
+/// Where a UDP association may send datagrams, checked like CONNECT targets
+pub(crate) struct Destinations {
+    pub(crate) options: Arc<Options>,
+    /// Rules in effect for the association's client
+    pub(crate) rules: RuleSet,
+    pub(crate) state: Arc<ServerState>,
+    /// Local IP of the control connection, for `Options::reject_self_connect`
+    pub(crate) local_ip: IpAddr,
+    /// The association's client, for `Options::resolve_hook`
+    pub(crate) ctx: ConnContext,
+}
+
+impl Destinations {
+    /// Resolve `address` to the addresses datagrams for it may go to, failing
+    /// with `PermissionDenied` if none may
+    fn resolve(&self, address: &Address) -> io::Result<Vec<SocketAddr>> {
+        let denied = |why: &str| io::Error::new(io::ErrorKind::PermissionDenied, format!("{} {}", why, address));
+
+        if !self.options.allowed_addr_types.contains(&address.addr_type()) {
+            return Err(denied("address type not allowed for"));
+        }
+        let host = match address {
+            Address::Domain(..) if self.options.literal_only => return Err(denied("domain names are disabled, dropping")),
+            Address::Domain(..) => Some(address.host_only()),
+            _ => None,
+        };
+        if host.as_ref().and_then(|host| self.rules.policy_by_name(host)) == Some(Policy::Deny) {
+            return Err(denied("rules deny"));
+        }
+
+        let mut addrs = self.options.resolve(address)?;
+        self.options.retain_families(&mut addrs, address)?;
+        if let (Some(hook), Some(host)) = (&self.options.resolve_hook, &host) {
+            if !hook(&self.ctx, host, &addrs) {
+                return Err(denied("resolve hook vetoed"));
+            }
+        }
+        addrs.retain(|addr| self.rules.allows(host.as_deref(), addr.ip()));
+        if self.options.reject_self_connect {
+            addrs.retain(|addr| !self.state.is_listen_addr(*addr, self.local_ip));
+        }
+        if addrs.is_empty() {
+            return Err(denied("rules deny"));
+        }
+        Ok(addrs)
+    }
+}
+
+/// Relay datagrams between the client and its destinations until `done` is set
+///
+/// Datagrams from the client are unwrapped and forwarded if `destinations`
+/// allows them, datagrams from a destination the client has sent to are
+/// wrapped and sent back to the client. Anything else is dropped, so the
+/// relay can't be used as an open reflector. Relayed datagrams mark `status`
+/// active.
+pub(crate) fn relay(socket: UdpSocket, source: ClientSource, destinations: &Destinations, done: &AtomicBool, status: &SessionStatus) -> io::Result<()> {
+    socket.set_read_timeout(Some(POLL_INTERVAL))?;
+
+    let mut client: Option<SocketAddr> = None;
+    let mut sent_to = HashSet::new();
+    let mut buf = [0u8; 65535];
+
+    while !done.load(Ordering::SeqCst) {
+        let (len, src) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => continue,
+            Err(e) => return Err(e),
+        };
+
+        let from_client = match client {
+            Some(client) => src == client,
+            None => source.matches(src),
+        };
+
+        if from_client {
+            status.touch();
+            client = Some(src);
+            match forward(&socket, &buf[..len], destinations) {
+                Ok(dest) => {
+                    sent_to.insert(dest);
+                },
+                Err(e) => debug!("Dropping datagram from {}: {}", src, e),
+            }
+        }
+        else if let (Some(client), true) = (client, sent_to.contains(&src)) {
+            status.touch();
+            let mut datagram = vec![RESERVED, RESERVED, 0];
+            write_socket_addr(&mut datagram, src);
+            datagram.extend_from_slice(&buf[..len]);
+            socket.send_to(&datagram, client)?;
+        }
+        else {
+            debug!("Dropping datagram from unexpected source {}", src);
+        }
+    }
+
+    Ok(())
+}
+
+/// Unwrap a client datagram and send it on, returning where it was sent
+fn forward(socket: &UdpSocket, datagram: &[u8], destinations: &Destinations) -> io::Result<SocketAddr> {
+    if datagram.len() < 4 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "datagram too short"));
+    }
+
+    // Fragmentation is optional and not supported
+    if datagram[2] != 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "fragmented datagram"));
+    }
+
+    let addr_type = AddrType::from(datagram[3] as usize).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid address type"))?;
+    let mut data = &datagram[4..];
+    let address = read_address(&mut data, addr_type)?;
+
+    // Send from the relay socket, so the destination must be the same family
+    let local = socket.local_addr()?;
+    let dest = destinations
+        .resolve(&address)?
+        .into_iter()
+        .find(|addr| addr.is_ipv4() == local.is_ipv4())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("no address for {} reachable from {}", address, local)))?;
+
+    socket.send_to(data, dest)?;
+    Ok(dest)
+}
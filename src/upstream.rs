@@ -0,0 +1,59 @@
+//! Forwarding requests to another SOCKS5 proxy
+use std::error::Error;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use crate::protocol::{self, AddrType, Address, SockCommand, SOCKS_VERSION};
+use crate::{AuthMethods, ResponseCode};
+
+/// Ask the upstream proxy on `stream` to CONNECT to `address`
+///
+/// Only unauthenticated upstreams are supported. With `compress` the
+/// `CompressedTunnel` method is offered first, and the result tells whether
+/// the upstream picked it, in which case everything relayed over `stream`
+/// from here on has to be compressed. A refused request is returned as the
+/// upstream's `ResponseCode`. An upstream that stalls for `timeout` in
+/// the middle of the handshake fails the request.
+pub(crate) fn connect(stream: &mut TcpStream, address: &Address, compress: bool, timeout: Duration) -> Result<bool, Box<dyn Error>> {
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let compressed = handshake(stream, address, compress)?;
+    stream.set_read_timeout(None)?;
+    stream.set_write_timeout(None)?;
+    Ok(compressed)
+}
+
+/// The greeting and request of `connect`
+fn handshake(stream: &mut TcpStream, address: &Address, compress: bool) -> Result<bool, Box<dyn Error>> {
+    let mut greeting = vec![SOCKS_VERSION, 1, AuthMethods::NoAuth as u8];
+    if compress {
+        greeting = vec![SOCKS_VERSION, 2, AuthMethods::CompressedTunnel as u8, AuthMethods::NoAuth as u8];
+    }
+    stream.write_all(&greeting)?;
+
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice)?;
+    if choice[0] != SOCKS_VERSION || !greeting[2..].contains(&choice[1]) {
+        warn!("Upstream proxy refused our auth methods, picked {:#04x}", choice[1]);
+        return Err(Box::new(ResponseCode::Failure));
+    }
+    let compressed = choice[1] == AuthMethods::CompressedTunnel as u8;
+
+    stream.write_all(&protocol::encode_request(SockCommand::Connect, address)?)?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply)?;
+    let addr_type = AddrType::from(reply[3] as usize).ok_or(ResponseCode::Failure)?;
+    let bound = protocol::read_address(stream, addr_type)?;
+    match ResponseCode::from_code(reply[1]) {
+        Some(ResponseCode::Success) => {
+            debug!("Upstream proxy connected to {} from {} (compressed: {})", address, bound, compressed);
+            Ok(compressed)
+        },
+        code => {
+            debug!("Upstream proxy refused CONNECT to {} with {:#04x}", address, reply[1]);
+            Err(Box::new(code.unwrap_or(ResponseCode::Failure)))
+        }
+    }
+}
@@ -6,3 +6,1198 @@ fn merino_contructor() {
     assert!(Merino::new(1080, "127.0.0.1".to_string(), Vec::new(), Vec::new()).is_ok())
 }
 
+
+#[test]
+/// Do bind failures say which address couldn't be bound
+fn merino_bind_error() {
+    use std::net::TcpListener;
+
+    let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = taken.local_addr().unwrap().port();
+
+    let error = Merino::new(port, "127.0.0.1".to_string(), Vec::new(), Vec::new()).err().unwrap();
+    assert!(error.to_string().starts_with(&format!("failed to bind 127.0.0.1:{}: ", port)), "{}", error);
+    assert!(error.downcast_ref::<ListenError>().is_some());
+
+    // Likewise when the socket is set up by hand
+    let options = Options { listen_backlog: Some(16), ..Options::default() };
+    let error = Merino::with_options(port, "127.0.0.1".to_string(), Vec::new(), Vec::new(), options).err().unwrap();
+    assert!(error.to_string().starts_with(&format!("failed to bind 127.0.0.1:{}: ", port)), "{}", error);
+}
+
+#[test]
+/// Can we create a `Merino` instance with a custom listen backlog
+fn merino_listen_backlog() {
+    let options = Options { listen_backlog: Some(16), ..Options::default() };
+    assert!(Merino::with_options(0, "127.0.0.1".to_string(), Vec::new(), Vec::new(), options).is_ok())
+}
+
+#[test]
+/// Can we serve an already bound listener
+fn merino_from_listener() {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let mut merino = Merino::from_listener(listener, vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap();
+    assert_eq!(merino.local_addr().unwrap(), addr);
+    thread::spawn(move || merino.serve().is_ok());
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.write_all(&[5, 1, AuthMethods::NoAuth as u8]).unwrap();
+
+    let mut reply = [0u8; 2];
+    client.read_exact(&mut reply).unwrap();
+    assert_eq!(reply, [5, AuthMethods::NoAuth as u8]);
+}
+
+#[test]
+/// Does `check` report reachable and unreachable destinations
+fn options_check() {
+    use std::net::{Ipv4Addr, TcpListener};
+
+    let options = Options::default();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    assert_eq!(options.check(&Address::Ipv4(Ipv4Addr::LOCALHOST, port)), ResponseCode::Success);
+    assert_eq!(options.check(&Address::Domain(b"localhost".to_vec(), port)), ResponseCode::Success);
+
+    drop(listener);
+    assert_eq!(options.check(&Address::Ipv4(Ipv4Addr::LOCALHOST, port)), ResponseCode::ConnectionRefused);
+}
+
+#[test]
+/// Does `serve` return once it has been idle for `idle_shutdown`
+fn merino_idle_shutdown() {
+    use std::net::{Shutdown, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+
+    let options = Options { idle_shutdown: Some(Duration::from_millis(300)), ..Options::default() };
+    let mut merino = Merino::with_options(0, "127.0.0.1".to_string(), Vec::new(), Vec::new(), options).unwrap();
+    let addr = merino.local_addr().unwrap();
+
+    // Connect before the idle timeout expires and hold the connection open
+    let client = TcpStream::connect(addr).unwrap();
+    let server = thread::spawn(move || merino.serve().is_ok());
+
+    thread::sleep(Duration::from_millis(600));
+    assert!(!server.is_finished());
+
+    client.shutdown(Shutdown::Both).unwrap();
+    assert!(server.join().unwrap());
+}
+
+#[test]
+/// Are requests turned away in maintenance mode
+fn merino_maintenance() {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread;
+
+    let mut merino = Merino::new(0, "127.0.0.1".to_string(), vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap();
+    let addr = merino.local_addr().unwrap();
+    let handle = merino.handle();
+    thread::spawn(move || merino.serve().is_ok());
+
+    handle.set_maintenance(ResponseCode::HostUnreachable);
+    assert!(handle.in_maintenance());
+
+    // CONNECT to the proxy itself, which must not be attempted
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.write_all(&[5, 1, 0]).unwrap();
+    client.write_all(&[5, 1, 0, 1, 127, 0, 0, 1]).unwrap();
+    client.write_all(&addr.port().to_be_bytes()).unwrap();
+
+    let mut reply = Vec::new();
+    client.read_to_end(&mut reply).unwrap();
+    assert_eq!(reply, vec![5, 0, 5, ResponseCode::HostUnreachable as u8, 0, 1, 0, 0, 0, 0, 0, 0]);
+
+    handle.clear_maintenance();
+    assert!(!handle.in_maintenance());
+}
+
+#[test]
+/// Are connections over `max_connections` turned away
+fn merino_max_connections() {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread;
+
+    let serve = |options: Options| {
+        let mut merino = Merino::with_options(0, "127.0.0.1".to_string(), vec![AuthMethods::NoAuth as u8], Vec::new(), options).unwrap();
+        let addr = merino.local_addr().unwrap();
+        thread::spawn(move || merino.serve().is_ok());
+        addr
+    };
+
+    // Closed straight away
+    let addr = serve(Options { max_connections: Some(1), ..Options::default() });
+    let _active = TcpStream::connect(addr).unwrap();
+    let mut client = TcpStream::connect(addr).unwrap();
+
+    let mut reply = Vec::new();
+    client.read_to_end(&mut reply).unwrap_or(0);
+    assert!(reply.is_empty());
+
+    // Told why
+    let addr = serve(Options { max_connections: Some(1), overload_reply: true, ..Options::default() });
+    let _active = TcpStream::connect(addr).unwrap();
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.write_all(&[5, 1, 0]).unwrap();
+    client.write_all(&[5, 1, 0, 1, 127, 0, 0, 1]).unwrap();
+    client.write_all(&addr.port().to_be_bytes()).unwrap();
+
+    let mut reply = Vec::new();
+    client.read_to_end(&mut reply).unwrap();
+    assert_eq!(reply, vec![5, 0, 5, ResponseCode::Failure as u8, 0, 1, 0, 0, 0, 0, 0, 0]);
+}
+
+#[test]
+/// Are connections that would go over the fd budget closed
+fn merino_max_open_fds() {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread;
+
+    // stdio, the listener and a single connection
+    let options = Options { max_open_fds: Some(4 + FDS_PER_CONNECTION), ..Options::default() };
+    let mut merino = Merino::with_options(0, "127.0.0.1".to_string(), vec![AuthMethods::NoAuth as u8], Vec::new(), options).unwrap();
+    let addr = merino.local_addr().unwrap();
+    let handle = merino.handle();
+    assert_eq!(handle.estimated_open_fds(), 4);
+    thread::spawn(move || merino.serve().is_ok());
+
+    let mut active = TcpStream::connect(addr).unwrap();
+    active.write_all(&[5, 1, AuthMethods::NoAuth as u8]).unwrap();
+    let mut reply = [0u8; 2];
+    active.read_exact(&mut reply).unwrap();
+    assert_eq!(handle.estimated_open_fds(), 4 + FDS_PER_CONNECTION);
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    let mut reply = Vec::new();
+    client.read_to_end(&mut reply).unwrap_or(0);
+    assert!(reply.is_empty());
+}
+
+#[test]
+/// Is the reject hook told why clients were turned away
+fn merino_on_rejected() {
+    use std::io::Read;
+    use std::net::{SocketAddrV4, TcpStream};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    let rejections = Arc::new(Mutex::new(Vec::new()));
+    let on_rejected = {
+        let rejections = rejections.clone();
+        Arc::new(move |peer: std::net::SocketAddr, reason: RejectReason| rejections.lock().unwrap().push((peer, reason)))
+    };
+
+    // Over the connection cap
+    let options = Options { max_connections: Some(1), on_rejected: Some(on_rejected.clone()), ..Options::default() };
+    let mut merino = Merino::with_options(0, "127.0.0.1".to_string(), vec![AuthMethods::NoAuth as u8], Vec::new(), options).unwrap();
+    let addr = merino.local_addr().unwrap();
+    thread::spawn(move || merino.serve().is_ok());
+
+    let _active = TcpStream::connect(addr).unwrap();
+    let mut client = TcpStream::connect(addr).unwrap();
+    let local = client.local_addr().unwrap();
+    client.read_to_end(&mut Vec::new()).unwrap_or(0);
+    assert_eq!(rejections.lock().unwrap().pop(), Some((local, RejectReason::ConnectionCap)));
+
+    // Denied by the rules
+    let options = Options {
+        rules: RuleSet { rules: Vec::new(), default_policy: Policy::Deny },
+        on_rejected: Some(on_rejected),
+        ..Options::default()
+    };
+    let addr = spawn_merino(vec![AuthMethods::NoAuth as u8], options);
+    let reply = request_reply(addr, 1, SocketAddrV4::new([127, 0, 0, 1].into(), 80));
+    assert_eq!(reply[..4], [5, 0, 5, ResponseCode::RuleFailure as u8]);
+    assert_eq!(rejections.lock().unwrap().pop().map(|(_, reason)| reason), Some(RejectReason::Denied));
+}
+
+#[test]
+/// Do connections over `max_connections` wait for a slot when queueing
+fn merino_overload_queue() {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread;
+    use std::time::Duration;
+
+    let serve = |timeout| {
+        let options = Options { max_connections: Some(1), overload_queue_timeout: Some(timeout), ..Options::default() };
+        let mut merino = Merino::with_options(0, "127.0.0.1".to_string(), vec![AuthMethods::NoAuth as u8], Vec::new(), options).unwrap();
+        let addr = merino.local_addr().unwrap();
+        thread::spawn(move || merino.serve().is_ok());
+        addr
+    };
+
+    // Served once the active connection goes away
+    let addr = serve(Duration::from_secs(5));
+    let active = TcpStream::connect(addr).unwrap();
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.write_all(&[5, 1, 0]).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    drop(active);
+
+    let mut reply = [0u8; 2];
+    client.read_exact(&mut reply).unwrap();
+    assert_eq!(reply, [5, 0]);
+
+    // Closed once the queue timeout passes
+    let addr = serve(Duration::from_millis(100));
+    let _active = TcpStream::connect(addr).unwrap();
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.write_all(&[5, 1, 0]).unwrap();
+
+    let mut reply = Vec::new();
+    client.read_to_end(&mut reply).unwrap_or(0);
+    assert!(reply.is_empty());
+}
+
+#[test]
+/// Can we listen on several addresses at once
+fn merino_multiple_addresses() {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread;
+
+    let mut merino = Merino::new(0, "127.0.0.1, 127.0.0.2".to_string(), vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap();
+    let addrs = merino.local_addrs().unwrap();
+    assert_eq!(addrs.len(), 2);
+    thread::spawn(move || merino.serve().is_ok());
+
+    for addr in addrs {
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(&[5, 1, 0]).unwrap();
+
+        let mut reply = [0u8; 2];
+        client.read_exact(&mut reply).unwrap();
+        assert_eq!(reply, [5, 0]);
+    }
+}
+
+#[test]
+/// Is a CONNECT to the proxy itself rejected
+fn merino_reject_self_connect() {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread;
+
+    let options = Options { reject_self_connect: true, ..Options::default() };
+    let mut merino = Merino::with_options(0, "127.0.0.1".to_string(), vec![AuthMethods::NoAuth as u8], Vec::new(), options).unwrap();
+    let addr = merino.local_addr().unwrap();
+    thread::spawn(move || merino.serve().is_ok());
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.write_all(&[5, 1, 0]).unwrap();
+    client.write_all(&[5, 1, 0, 1, 127, 0, 0, 1]).unwrap();
+    client.write_all(&addr.port().to_be_bytes()).unwrap();
+
+    let mut reply = Vec::new();
+    client.read_to_end(&mut reply).unwrap();
+    assert_eq!(reply, vec![5, 0, 5, ResponseCode::RuleFailure as u8, 0, 1, 0, 0, 0, 0, 0, 0]);
+}
+
+/// Start an echo server on an ephemeral port, serving one connection at a time
+fn echo_server() -> std::net::SocketAddr {
+    use std::io::copy;
+    use std::net::TcpListener;
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = stream.try_clone().unwrap();
+            copy(&mut reader, &mut stream).unwrap_or(0);
+        }
+    });
+    addr
+}
+
+/// Send a CONNECT for the IPv4 `target` on an authenticated `client` and
+/// check a message comes back from the echo server
+fn connect_echo(client: &mut std::net::TcpStream, target: std::net::SocketAddr) {
+    use std::io::{Read, Write};
+    use std::net::{Shutdown, SocketAddr};
+
+    let ip = match target {
+        SocketAddr::V4(target) => target.ip().octets(),
+        SocketAddr::V6(_) => panic!("expected a V4 address"),
+    };
+    client.write_all(&[5, 1, 0, 1]).unwrap();
+    client.write_all(&ip).unwrap();
+    client.write_all(&target.port().to_be_bytes()).unwrap();
+
+    let mut reply = [0u8; 10];
+    client.read_exact(&mut reply).unwrap();
+    assert_eq!(reply[..4], [5, ResponseCode::Success as u8, 0, 1]);
+
+    client.write_all(b"hello, merino").unwrap();
+    client.shutdown(Shutdown::Write).unwrap();
+
+    let mut echoed = Vec::new();
+    client.read_to_end(&mut echoed).unwrap();
+    assert_eq!(echoed, b"hello, merino");
+}
+
+#[test]
+/// Does a CONNECT relay data to the target and back
+fn connect_round_trip_noauth() {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread;
+
+    let target = echo_server();
+    let mut merino = Merino::new(0, "127.0.0.1".to_string(), vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap();
+    let addr = merino.local_addr().unwrap();
+    thread::spawn(move || merino.serve().is_ok());
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.write_all(&[5, 1, AuthMethods::NoAuth as u8]).unwrap();
+
+    let mut reply = [0u8; 2];
+    client.read_exact(&mut reply).unwrap();
+    assert_eq!(reply, [5, AuthMethods::NoAuth as u8]);
+
+    connect_echo(&mut client, target);
+}
+
+#[test]
+/// Does a CONNECT relay data after username/password authentication
+fn connect_round_trip_userpass() {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread;
+
+    struct Store;
+
+    impl CredentialStore for Store {
+        fn verify(&self, username: &str, password: &str) -> bool {
+            username == "admin" && password == "hunter2"
+        }
+    }
+
+    let target = echo_server();
+    let mut merino = Merino::new(0, "127.0.0.1".to_string(), vec![AuthMethods::UserPass as u8], Vec::new()).unwrap();
+    merino.set_credential_store(Store);
+    let addr = merino.local_addr().unwrap();
+    thread::spawn(move || merino.serve().is_ok());
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.write_all(&[5, 1, AuthMethods::UserPass as u8]).unwrap();
+
+    let mut reply = [0u8; 2];
+    client.read_exact(&mut reply).unwrap();
+    assert_eq!(reply, [5, AuthMethods::UserPass as u8]);
+
+    client.write_all(&[1, 5]).unwrap();
+    client.write_all(b"admin").unwrap();
+    client.write_all(&[7]).unwrap();
+    client.write_all(b"hunter2").unwrap();
+    client.read_exact(&mut reply).unwrap();
+    assert_eq!(reply, [1, 0]);
+
+    connect_echo(&mut client, target);
+}
+
+/// Send a NoAuth greeting and the request `command` for `target`, returning
+/// everything merino sends back until it closes the connection
+fn request_reply(proxy: std::net::SocketAddr, command: u8, target: std::net::SocketAddrV4) -> Vec<u8> {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    let mut client = TcpStream::connect(proxy).unwrap();
+    client.write_all(&[5, 1, AuthMethods::NoAuth as u8]).unwrap();
+    client.write_all(&[5, command, 0, 1]).unwrap();
+    client.write_all(&target.ip().octets()).unwrap();
+    client.write_all(&target.port().to_be_bytes()).unwrap();
+
+    let mut reply = Vec::new();
+    client.read_to_end(&mut reply).unwrap();
+    reply
+}
+
+#[test]
+/// Do failed requests get the matching reply code
+fn merino_error_replies() {
+    use std::net::{SocketAddrV4, TcpListener};
+    use std::thread;
+
+    let mut merino = Merino::new(0, "127.0.0.1".to_string(), vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap();
+    let addr = merino.local_addr().unwrap();
+    thread::spawn(move || merino.serve().is_ok());
+
+    let closed = match TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap() {
+        std::net::SocketAddr::V4(addr) => addr,
+        _ => unreachable!(),
+    };
+    let expected = |code: ResponseCode| vec![5, 0, 5, code as u8, 0, 1, 0, 0, 0, 0, 0, 0];
+
+    assert_eq!(request_reply(addr, 9, SocketAddrV4::new(*closed.ip(), 80)), expected(ResponseCode::CommandNotSupported));
+    assert_eq!(request_reply(addr, 1, closed), expected(ResponseCode::ConnectionRefused));
+}
+
+#[test]
+#[cfg(unix)]
+/// Can two instances share a port with `reuse_port`
+fn merino_reuse_port() {
+    let options = Options { reuse_port: true, ..Options::default() };
+    let first = Merino::with_options(0, "127.0.0.1".to_string(), Vec::new(), Vec::new(), options.clone()).unwrap();
+    let port = first.local_addr().unwrap().port();
+
+    let second = Merino::with_options(port, "127.0.0.1".to_string(), Vec::new(), Vec::new(), options).unwrap();
+    assert_eq!(second.local_addr().unwrap().port(), port);
+
+    // Without it the port is taken
+    assert!(Merino::new(port, "127.0.0.1".to_string(), Vec::new(), Vec::new()).is_err());
+}
+
+#[test]
+/// Does the handle report active and total connections
+fn merino_connection_counts() {
+    use std::net::{Shutdown, TcpStream};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    let mut merino = Merino::new(0, "127.0.0.1".to_string(), vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap();
+    let addr = merino.local_addr().unwrap();
+    let handle = merino.handle();
+    thread::spawn(move || merino.serve().is_ok());
+
+    // Counts change on the server's threads, so wait for them
+    let wait_for = |active: usize, total: u64| {
+        let start = Instant::now();
+        while (handle.active_connections(), handle.total_connections()) != (active, total) {
+            assert!(start.elapsed() < Duration::from_secs(5), "expected {} active, {} total", active, total);
+            thread::sleep(Duration::from_millis(10));
+        }
+    };
+
+    wait_for(0, 0);
+    let first = TcpStream::connect(addr).unwrap();
+    let second = TcpStream::connect(addr).unwrap();
+    wait_for(2, 2);
+
+    first.shutdown(Shutdown::Both).unwrap();
+    wait_for(1, 2);
+    second.shutdown(Shutdown::Both).unwrap();
+    wait_for(0, 2);
+}
+
+#[test]
+/// Does the access log get a line once a CONNECT session closes
+fn merino_access_log() {
+    use std::io::{self, Read, Write};
+    use std::net::TcpStream;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let log = Shared::default();
+    let options = Options {
+        session_hooks: vec![AccessLog::new(Box::new(log.clone())).hook()],
+        ..Options::default()
+    };
+
+    let target = echo_server();
+    let mut merino = Merino::with_options(0, "127.0.0.1".to_string(), vec![AuthMethods::NoAuth as u8], Vec::new(), options).unwrap();
+    let addr = merino.local_addr().unwrap();
+    thread::spawn(move || merino.serve().is_ok());
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.write_all(&[5, 1, AuthMethods::NoAuth as u8]).unwrap();
+    let mut reply = [0u8; 2];
+    client.read_exact(&mut reply).unwrap();
+    connect_echo(&mut client, target);
+    drop(client);
+
+    // The line is written on the server's thread once the session is over
+    let start = Instant::now();
+    while log.0.lock().unwrap().is_empty() {
+        assert!(start.elapsed() < Duration::from_secs(5), "no access log line");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let line = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+    let fields: Vec<&str> = line.trim_end().split(' ').collect();
+    assert_eq!(fields[3..9], ["-", "Connect", &target.to_string(), "0", "13", "13"]);
+    assert!(line.ends_with('\n'));
+}
+
+#[test]
+/// Are transient connect failures retried, and permanent ones not
+fn options_connect_retries() {
+    use std::io;
+    use std::net::{Ipv4Addr, SocketAddr, TcpListener};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// Fails the first `failures` lookups with `kind`, then resolves to `addr`
+    struct Flaky {
+        addr: SocketAddr,
+        kind: io::ErrorKind,
+        failures: usize,
+        lookups: AtomicUsize,
+    }
+
+    impl Resolver for Flaky {
+        fn resolve(&self, _host: &str, _port: u16) -> io::Result<Vec<SocketAddr>> {
+            if self.lookups.fetch_add(1, Ordering::SeqCst) < self.failures {
+                return Err(io::Error::new(self.kind, "flaky"));
+            }
+            Ok(vec![self.addr])
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let options = |kind, retries| {
+        let resolver = Arc::new(Flaky { addr, kind, failures: 2, lookups: AtomicUsize::new(0) });
+        let options = Options {
+            resolver: resolver.clone(),
+            connect_retries: retries,
+            connect_retry_backoff: Duration::from_millis(1),
+            ..Options::default()
+        };
+        (options, resolver)
+    };
+    let target = Address::Domain(b"flaky.example".to_vec(), addr.port());
+
+    let (no_retries, _) = options(io::ErrorKind::TimedOut, 0);
+    assert_eq!(no_retries.check(&target), ResponseCode::TtlExpired);
+
+    let (retries, resolver) = options(io::ErrorKind::TimedOut, 2);
+    assert_eq!(retries.check(&target), ResponseCode::Success);
+    assert_eq!(resolver.lookups.load(Ordering::SeqCst), 3);
+
+    let (permanent, resolver) = options(io::ErrorKind::InvalidInput, 2);
+    assert_eq!(permanent.check(&target), ResponseCode::Failure);
+    assert_eq!(resolver.lookups.load(Ordering::SeqCst), 1);
+
+    // Retries stop once the next one would overrun connect_timeout
+    let resolver = Arc::new(Flaky { addr, kind: io::ErrorKind::TimedOut, failures: 100, lookups: AtomicUsize::new(0) });
+    let budgeted = Options {
+        resolver: resolver.clone(),
+        connect_retries: 10,
+        connect_retry_backoff: Duration::from_millis(50),
+        connect_timeout: Some(Duration::from_millis(120)),
+        ..Options::default()
+    };
+    assert_eq!(budgeted.check(&target), ResponseCode::TtlExpired);
+    assert_eq!(resolver.lookups.load(Ordering::SeqCst), 2);
+
+    drop(listener);
+    assert_eq!(retries.check(&Address::Ipv4(Ipv4Addr::LOCALHOST, addr.port())), ResponseCode::ConnectionRefused);
+}
+
+#[test]
+/// Are relayed bytes counted by destination port
+fn merino_bytes_by_port() {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    let tracked = echo_server();
+    let untracked = echo_server();
+    let options = Options { tracked_ports: vec![tracked.port()], ..Options::default() };
+    let mut merino = Merino::with_options(0, "127.0.0.1".to_string(), vec![AuthMethods::NoAuth as u8], Vec::new(), options).unwrap();
+    let addr = merino.local_addr().unwrap();
+    let handle = merino.handle();
+    thread::spawn(move || merino.serve().is_ok());
+
+    for target in [tracked, untracked] {
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(&[5, 1, AuthMethods::NoAuth as u8]).unwrap();
+        let mut reply = [0u8; 2];
+        client.read_exact(&mut reply).unwrap();
+        connect_echo(&mut client, target);
+    }
+
+    // Sessions are counted on the server's threads once they close
+    let start = Instant::now();
+    while handle.bytes_by_port().len() < 2 {
+        assert!(start.elapsed() < Duration::from_secs(5), "sessions weren't counted");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let counts = handle.bytes_by_port();
+    assert_eq!(counts[&PortBucket::Port(tracked.port())], ByteCounts { up: 13, down: 13 });
+    assert_eq!(counts[&PortBucket::Other], ByteCounts { up: 13, down: 13 });
+}
+
+#[test]
+/// Does killing a connection close its tunnel
+fn merino_kill_connection() {
+    use std::io::{Read, Write};
+    use std::net::{Shutdown, TcpStream};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    let target = echo_server();
+    let mut merino = Merino::new(0, "127.0.0.1".to_string(), vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap();
+    let addr = merino.local_addr().unwrap();
+    let handle = merino.handle();
+    thread::spawn(move || merino.serve().is_ok());
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.write_all(&[5, 1, AuthMethods::NoAuth as u8]).unwrap();
+    let mut reply = [0u8; 2];
+    client.read_exact(&mut reply).unwrap();
+
+    let ip = match target {
+        std::net::SocketAddr::V4(target) => target.ip().octets(),
+        std::net::SocketAddr::V6(_) => panic!("expected a V4 address"),
+    };
+    client.write_all(&[5, 1, 0, 1]).unwrap();
+    client.write_all(&ip).unwrap();
+    client.write_all(&target.port().to_be_bytes()).unwrap();
+    let mut reply = [0u8; 10];
+    client.read_exact(&mut reply).unwrap();
+    assert_eq!(reply[1], ResponseCode::Success as u8);
+
+    // The first connection gets ID 0
+    assert!(!handle.kill_connection(1));
+    assert!(handle.kill_connection(0));
+    assert!(!handle.kill_connection(0));
+
+    // The tunnel is closed without the client closing its end
+    let mut rest = Vec::new();
+    client.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty());
+
+    let start = Instant::now();
+    while handle.active_connections() > 0 {
+        assert!(start.elapsed() < Duration::from_secs(5), "connection wasn't closed");
+        thread::sleep(Duration::from_millis(10));
+    }
+    client.shutdown(Shutdown::Both).unwrap_or(());
+}
+
+#[test]
+/// Are idle tunnels reaped
+fn merino_reap_idle() {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread;
+    use std::time::Duration;
+
+    let target = echo_server();
+    let options = Options { reap_idle: Some(Duration::from_millis(200)), ..Options::default() };
+    let mut merino = Merino::with_options(0, "127.0.0.1".to_string(), vec![AuthMethods::NoAuth as u8], Vec::new(), options).unwrap();
+    let addr = merino.local_addr().unwrap();
+    thread::spawn(move || merino.serve().is_ok());
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.write_all(&[5, 1, AuthMethods::NoAuth as u8]).unwrap();
+    let mut reply = [0u8; 2];
+    client.read_exact(&mut reply).unwrap();
+
+    let ip = match target {
+        std::net::SocketAddr::V4(target) => target.ip().octets(),
+        std::net::SocketAddr::V6(_) => panic!("expected a V4 address"),
+    };
+    client.write_all(&[5, 1, 0, 1]).unwrap();
+    client.write_all(&ip).unwrap();
+    client.write_all(&target.port().to_be_bytes()).unwrap();
+    let mut reply = [0u8; 10];
+    client.read_exact(&mut reply).unwrap();
+    assert_eq!(reply[1], ResponseCode::Success as u8);
+
+    // Activity keeps the tunnel open
+    let mut echoed = [0u8; 4];
+    for _ in 0..3 {
+        thread::sleep(Duration::from_millis(100));
+        client.write_all(b"ping").unwrap();
+        client.read_exact(&mut echoed).unwrap();
+    }
+
+    // Then the reaper closes it
+    client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let mut rest = Vec::new();
+    client.read_to_end(&mut rest).unwrap();
+}
+
+#[test]
+/// Does CONNECT to a domain move on from an address that fails to one that works
+fn options_connect_tries_all_addresses() {
+    use std::io;
+    use std::net::{SocketAddr, TcpListener};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// Resolves every name to `addrs`
+    struct Fixed {
+        addrs: Vec<SocketAddr>,
+    }
+
+    impl Resolver for Fixed {
+        fn resolve(&self, _host: &str, _port: u16) -> io::Result<Vec<SocketAddr>> {
+            Ok(self.addrs.clone())
+        }
+    }
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let good = listener.local_addr().unwrap();
+    let bad = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let target = Address::Domain(b"multi.example".to_vec(), good.port());
+
+    for &delay in &[None, Some(Duration::from_millis(50))] {
+        let options = Options {
+            resolver: Arc::new(Fixed { addrs: vec![bad, good] }),
+            happy_eyeballs_delay: delay,
+            connect_timeout: Some(Duration::from_secs(5)),
+            ..Options::default()
+        };
+        assert_eq!(options.check(&target), ResponseCode::Success);
+
+        let options = Options {
+            resolver: Arc::new(Fixed { addrs: vec![bad, bad] }),
+            happy_eyeballs_delay: delay,
+            ..Options::default()
+        };
+        assert_eq!(options.check(&target), ResponseCode::ConnectionRefused);
+    }
+}
+
+#[test]
+/// Are clients offering no auth methods counted apart from unsupported ones
+fn merino_empty_method_offers() {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread;
+
+    let mut merino = Merino::new(0, "127.0.0.1".to_string(), vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap();
+    let addr = merino.local_addr().unwrap();
+    let handle = merino.handle();
+    thread::spawn(move || merino.serve().is_ok());
+
+    let greet = |greeting: &[u8]| {
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(greeting).unwrap();
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).unwrap();
+        assert_eq!(reply, vec![5, AuthMethods::NoMethods as u8]);
+    };
+
+    greet(&[5, 1, AuthMethods::UserPass as u8]);
+    assert_eq!(handle.empty_method_offers(), 0);
+
+    greet(&[5, 0]);
+    assert_eq!(handle.empty_method_offers(), 1);
+}
+
+#[test]
+/// Does shutting down stop accepting, and kill tunnels that outlast the drain timeout
+fn merino_shutdown_drain() {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    let target = echo_server();
+    let options = Options {
+        shutdown_drain_timeout: Duration::from_millis(200),
+        ..Options::default()
+    };
+    let mut merino = Merino::with_options(0, "127.0.0.1".to_string(), vec![AuthMethods::NoAuth as u8], Vec::new(), options).unwrap();
+    let addr = merino.local_addr().unwrap();
+    let handle = merino.handle();
+    let server = thread::spawn(move || merino.serve().is_ok());
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.write_all(&[5, 1, AuthMethods::NoAuth as u8]).unwrap();
+    let mut reply = [0u8; 2];
+    client.read_exact(&mut reply).unwrap();
+
+    let ip = match target {
+        std::net::SocketAddr::V4(target) => target.ip().octets(),
+        std::net::SocketAddr::V6(_) => panic!("expected a V4 address"),
+    };
+    client.write_all(&[5, 1, 0, 1]).unwrap();
+    client.write_all(&ip).unwrap();
+    client.write_all(&target.port().to_be_bytes()).unwrap();
+    let mut reply = [0u8; 10];
+    client.read_exact(&mut reply).unwrap();
+    assert_eq!(reply[1], ResponseCode::Success as u8);
+
+    client.write_all(b"ping").unwrap();
+    let mut pong = [0u8; 4];
+    client.read_exact(&mut pong).unwrap();
+
+    let start = Instant::now();
+    handle.shutdown();
+    assert!(server.join().unwrap());
+    assert!(start.elapsed() >= Duration::from_millis(200));
+
+    // The long-lived tunnel was terminated
+    let mut rest = Vec::new();
+    client.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty());
+
+    // And nothing is listening anymore
+    assert!(TcpStream::connect(addr).is_err());
+}
+
+#[test]
+/// Are open connections listed with live byte counts
+fn merino_list_sessions() {
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    let target = echo_server();
+    let mut merino = Merino::new(0, "127.0.0.1".to_string(), vec![AuthMethods::NoAuth as u8], Vec::new()).unwrap();
+    let addr = merino.local_addr().unwrap();
+    let handle = merino.handle();
+    thread::spawn(move || merino.serve().is_ok());
+    assert!(handle.list_sessions().is_empty());
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.write_all(&[5, 1, AuthMethods::NoAuth as u8]).unwrap();
+    let mut reply = [0u8; 2];
+    client.read_exact(&mut reply).unwrap();
+
+    let ip = match target {
+        SocketAddr::V4(target) => *target.ip(),
+        SocketAddr::V6(_) => panic!("expected a V4 address"),
+    };
+    client.write_all(&[5, 1, 0, 1]).unwrap();
+    client.write_all(&ip.octets()).unwrap();
+    client.write_all(&target.port().to_be_bytes()).unwrap();
+    let mut reply = [0u8; 10];
+    client.read_exact(&mut reply).unwrap();
+
+    client.write_all(b"ping").unwrap();
+    let mut pong = [0u8; 4];
+    client.read_exact(&mut pong).unwrap();
+
+    // The relay counts a write once it has returned, which can be after the
+    // client has read it
+    let start = Instant::now();
+    while handle.list_sessions()[0].bytes.down < 4 {
+        assert!(start.elapsed() < Duration::from_secs(5), "bytes weren't counted");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let sessions = handle.list_sessions();
+    assert_eq!(sessions.len(), 1);
+    assert_eq!(sessions[0].conn_id, 0);
+    assert_eq!(sessions[0].peer_addr, client.local_addr().unwrap());
+    assert_eq!(sessions[0].username, None);
+    assert_eq!(sessions[0].destination, Some(Address::Ipv4(ip, target.port())));
+    assert_eq!(sessions[0].bytes, ByteCounts { up: 4, down: 4 });
+}
+
+#[test]
+/// Are users held to their connection limits, without affecting other users
+fn merino_per_user_limits() {
+    use std::collections::HashMap;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use std::thread;
+
+    /// Every user's password is their name's first letter
+    struct Store;
+
+    impl CredentialStore for Store {
+        fn verify(&self, username: &str, password: &str) -> bool {
+            username.get(..1) == Some(password)
+        }
+    }
+
+    let target = echo_server();
+    let options = Options {
+        max_connections_per_user: Some(1),
+        user_connection_limits: vec![("carol".to_string(), 2)].into_iter().collect::<HashMap<_, _>>(),
+        ..Options::default()
+    };
+    let mut merino = Merino::with_options(0, "127.0.0.1".to_string(), vec![AuthMethods::UserPass as u8], Vec::new(), options).unwrap();
+    merino.set_credential_store(Store);
+    let addr = merino.local_addr().unwrap();
+    thread::spawn(move || merino.serve().is_ok());
+
+    // Open a tunnel as `username`, returning the reply code
+    let open = |username: &str, password: &str| {
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(&[5, 1, AuthMethods::UserPass as u8]).unwrap();
+        let mut reply = [0u8; 2];
+        client.read_exact(&mut reply).unwrap();
+
+        client.write_all(&[1, username.len() as u8]).unwrap();
+        client.write_all(username.as_bytes()).unwrap();
+        client.write_all(&[password.len() as u8]).unwrap();
+        client.write_all(password.as_bytes()).unwrap();
+        client.read_exact(&mut reply).unwrap();
+        assert_eq!(reply, [1, 0]);
+
+        let ip = match target {
+            SocketAddr::V4(target) => target.ip().octets(),
+            SocketAddr::V6(_) => panic!("expected a V4 address"),
+        };
+        client.write_all(&[5, 1, 0, 1]).unwrap();
+        client.write_all(&ip).unwrap();
+        client.write_all(&target.port().to_be_bytes()).unwrap();
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).unwrap();
+        (reply[1], client)
+    };
+
+    let (code, _alice) = open("alice", "a");
+    assert_eq!(code, ResponseCode::Success as u8);
+    assert_eq!(open("alice", "a").0, ResponseCode::RuleFailure as u8);
+    let (code, _bob) = open("bob", "b");
+    assert_eq!(code, ResponseCode::Success as u8);
+
+    let (code, _carol) = open("carol", "c");
+    assert_eq!(code, ResponseCode::Success as u8);
+    let (code, _carol2) = open("carol", "c");
+    assert_eq!(code, ResponseCode::Success as u8);
+    assert_eq!(open("carol", "c").0, ResponseCode::RuleFailure as u8);
+}
+
+#[test]
+/// Does each listener offer its own authentication methods
+fn merino_listener_policies() {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    let trusted = ListenerPolicy { auth_methods: vec![AuthMethods::NoAuth as u8], rules: None };
+    let public = ListenerPolicy { auth_methods: vec![AuthMethods::UserPass as u8], rules: None };
+    let listeners = vec![
+        (TcpListener::bind("127.0.0.1:0").unwrap(), trusted),
+        (TcpListener::bind("127.0.0.1:0").unwrap(), public),
+    ];
+    let mut merino = Merino::with_listeners(listeners, Vec::new(), Options::default()).unwrap();
+    let addrs = merino.local_addrs().unwrap();
+    thread::spawn(move || merino.serve().is_ok());
+
+    let greet = |addr| {
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(&[5, 1, AuthMethods::NoAuth as u8]).unwrap();
+        let mut reply = [0; 2];
+        client.read_exact(&mut reply).unwrap();
+        reply
+    };
+
+    assert_eq!(greet(addrs[0]), [5, AuthMethods::NoAuth as u8]);
+    assert_eq!(greet(addrs[1]), [5, AuthMethods::NoMethods as u8]);
+}
+
+#[test]
+/// Is a `Merino` without listeners rejected
+fn merino_no_listeners() {
+    assert!(Merino::with_listeners(Vec::new(), Vec::new(), Options::default()).is_err());
+}
+
+#[test]
+/// Are clients refused after repeated failed logins, even with the right password
+fn merino_auth_lockout() {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::thread;
+    use std::time::Duration;
+
+    /// Only `hunter2` is accepted
+    struct Store;
+
+    impl CredentialStore for Store {
+        fn verify(&self, _username: &str, password: &str) -> bool {
+            password == "hunter2"
+        }
+    }
+
+    let options = Options {
+        auth_failure_threshold: Some(3),
+        auth_lockout_duration: Duration::from_secs(60),
+        ..Options::default()
+    };
+    let mut merino = Merino::with_options(0, "127.0.0.1".to_string(), vec![AuthMethods::UserPass as u8], Vec::new(), options).unwrap();
+    merino.set_credential_store(Store);
+    let addr = merino.local_addr().unwrap();
+    thread::spawn(move || merino.serve().is_ok());
+
+    // Log in, returning the auth status
+    let login = |password: &str| {
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(&[5, 1, AuthMethods::UserPass as u8]).unwrap();
+        let mut reply = [0u8; 2];
+        client.read_exact(&mut reply).unwrap();
+
+        client.write_all(&[1, 5]).unwrap();
+        client.write_all(b"admin").unwrap();
+        client.write_all(&[password.len() as u8]).unwrap();
+        client.write_all(password.as_bytes()).unwrap();
+        client.read_exact(&mut reply).unwrap();
+        reply[1]
+    };
+
+    assert_eq!(login("hunter2"), 0);
+    for _ in 0..3 {
+        assert_eq!(login("guess"), 1);
+    }
+    assert_eq!(login("hunter2"), 1);
+}
+
+/// Serve a merino with `auth_methods` and `options` in the background
+fn spawn_merino(auth_methods: Vec<u8>, options: Options) -> std::net::SocketAddr {
+    use std::thread;
+
+    let mut merino = Merino::with_options(0, "127.0.0.1".to_string(), auth_methods, Vec::new(), options).unwrap();
+    let addr = merino.local_addr().unwrap();
+    thread::spawn(move || merino.serve().is_ok());
+    addr
+}
+
+#[test]
+/// Are requests forwarded to the upstream proxy, and its replies passed back
+fn merino_upstream() {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    let target = echo_server();
+    let upstream = spawn_merino(vec![AuthMethods::NoAuth as u8], Options::default());
+    // Compression is offered, but without it the tunnel is relayed as is
+    let options = Options {
+        upstream: Some(upstream),
+        upstream_compression: true,
+        ..Options::default()
+    };
+    let addr = spawn_merino(vec![AuthMethods::NoAuth as u8], options);
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.write_all(&[5, 1, AuthMethods::NoAuth as u8]).unwrap();
+    let mut reply = [0u8; 2];
+    client.read_exact(&mut reply).unwrap();
+    connect_echo(&mut client, target);
+
+    let closed = match TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap() {
+        std::net::SocketAddr::V4(addr) => addr,
+        _ => unreachable!(),
+    };
+    let reply = request_reply(addr, 1, closed);
+    assert_eq!(reply[..4], [5, 0, 5, ResponseCode::ConnectionRefused as u8]);
+}
+
+#[test]
+/// Does an upstream proxy that never answers fail the request instead of hanging it
+fn merino_upstream_stalled() {
+    use std::net::{Ipv4Addr, SocketAddrV4, TcpListener};
+    use std::time::{Duration, Instant};
+
+    let upstream = TcpListener::bind("127.0.0.1:0").unwrap();
+    let options = Options {
+        upstream: Some(upstream.local_addr().unwrap()),
+        connect_timeout: Some(Duration::from_millis(200)),
+        ..Options::default()
+    };
+    let addr = spawn_merino(vec![AuthMethods::NoAuth as u8], options);
+
+    let start = Instant::now();
+    let reply = request_reply(addr, 1, SocketAddrV4::new(Ipv4Addr::LOCALHOST, 80));
+    assert_ne!(reply[3], ResponseCode::Success as u8);
+    assert!(start.elapsed() < Duration::from_secs(5));
+    drop(upstream);
+}
+
+#[test]
+/// Are requests relayed over spare upstream connections
+fn merino_upstream_pool() {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    let target = echo_server();
+    let upstream = spawn_merino(vec![AuthMethods::NoAuth as u8], Options::default());
+    let options = Options {
+        upstream: Some(upstream),
+        upstream_pool_size: 2,
+        ..Options::default()
+    };
+    let addr = spawn_merino(vec![AuthMethods::NoAuth as u8], options);
+
+    // More requests than spares, so some wait for the pool to be topped up
+    for _ in 0..4 {
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(&[5, 1, AuthMethods::NoAuth as u8]).unwrap();
+        let mut reply = [0u8; 2];
+        client.read_exact(&mut reply).unwrap();
+        connect_echo(&mut client, target);
+    }
+}
+
+#[cfg(feature = "compression")]
+#[test]
+/// Can two merinos relay through a compressed tunnel
+fn merino_compressed_tunnel() {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    let target = echo_server();
+    // Only compressed tunnels are accepted
+    let upstream = spawn_merino(vec![AuthMethods::CompressedTunnel as u8], Options::default());
+    let options = Options {
+        upstream: Some(upstream),
+        upstream_compression: true,
+        ..Options::default()
+    };
+    let addr = spawn_merino(vec![AuthMethods::NoAuth as u8], options);
+
+    let mut client = TcpStream::connect(addr).unwrap();
+    client.write_all(&[5, 1, AuthMethods::NoAuth as u8]).unwrap();
+    let mut reply = [0u8; 2];
+    client.read_exact(&mut reply).unwrap();
+    connect_echo(&mut client, target);
+}
+
+#[cfg(feature = "testutils")]
+#[test]
+/// Can integration tests script a client over the in-memory harness
+fn testutils_harness() {
+    use std::io::Write;
+    use std::net::Shutdown;
+    use merino::testutils::{duplex, encode_request, parse_request, read_n, read_to_end, spawn_client};
+
+    let (mut client, server) = duplex();
+    let handle = spawn_client(server, Vec::new(), vec![AuthMethods::NoAuth as u8]);
+
+    // Only methods merino was told to accept are picked
+    client.write_all(&[5, 1, AuthMethods::UserPass as u8]).unwrap();
+    assert_eq!(read_to_end(&mut client), vec![5, AuthMethods::NoMethods as u8]);
+    assert!(handle.join().unwrap().is_err());
+
+    let (mut client, server) = duplex();
+    let handle = spawn_client(server, Vec::new(), vec![AuthMethods::NoAuth as u8]);
+    client.write_all(&[5, 1, AuthMethods::NoAuth as u8]).unwrap();
+    assert_eq!(read_n(&mut client, 2), vec![5, AuthMethods::NoAuth as u8]);
+    client.shutdown(Shutdown::Both).unwrap();
+    handle.join().unwrap().unwrap_or(());
+
+    let address = Address::Domain(b"example.com".to_vec(), 443);
+    let bytes = encode_request(SockCommand::Connect, &address).unwrap();
+    let (request, len) = parse_request(&bytes).unwrap();
+    assert_eq!((request.command, request.address, len), (SockCommand::Connect, address, bytes.len()));
+}